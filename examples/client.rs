@@ -2,85 +2,219 @@ extern crate env_logger;
 extern crate mio;
 extern crate tick;
 
-use std::io::{self, Read, Write, stdout};
-type Tcp = mio::tcp::TcpStream;
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::rc::Rc;
 
-struct Client {
-    msg: &'static [u8],
-    pos: usize,
-    eof: bool,
+use mio::{EventSet, PollOpt, Selector, Token, TryAccept};
+
+// a netcat-style client, pumping between a socket and the terminal
+
+const TCP: usize = 0;
+const STDIO: usize = 1;
+const MAX_BUF: usize = 64 * 1024;
+
+enum Conn {
+    Tcp(mio::tcp::TcpStream),
+    Stdio(tick::Stdio),
+}
+
+impl Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Conn::Tcp(ref mut tcp) => tcp.read(buf),
+            Conn::Stdio(ref mut stdio) => stdio.read(buf),
+        }
+    }
 }
 
+impl Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Conn::Tcp(ref mut tcp) => tcp.write(buf),
+            Conn::Stdio(ref mut stdio) => stdio.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
 
-impl Client {
-    fn new() -> Client {
-        Client {
-            msg: b"GET / HTTP/1.1\r\n\r\n",
-            pos: 0,
-            eof: false,
+impl tick::Transport for Conn {
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        match *self {
+            Conn::Tcp(ref tcp) => tick::Transport::take_error(tcp),
+            Conn::Stdio(_) => Ok(None),
         }
     }
 }
 
-impl Client {
+impl mio::Evented for Conn {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        match *self {
+            Conn::Tcp(ref tcp) => tcp.register(selector, token, interest, opts),
+            Conn::Stdio(ref stdio) => stdio.register(selector, token, interest, opts),
+        }
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        match *self {
+            Conn::Tcp(ref tcp) => tcp.reregister(selector, token, interest, opts),
+            Conn::Stdio(ref stdio) => stdio.reregister(selector, token, interest, opts),
+        }
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        match *self {
+            Conn::Tcp(ref tcp) => tcp.deregister(selector),
+            Conn::Stdio(ref stdio) => stdio.deregister(selector),
+        }
+    }
+}
+
+// streams are only added with Tick::stream, so nothing is ever accepted
+struct NoListener;
+
+impl TryAccept for NoListener {
+    type Output = Conn;
+    fn accept(&self) -> io::Result<Option<Conn>> {
+        Ok(None)
+    }
+}
+
+impl mio::Evented for NoListener {
+    fn register(&self, _: &mut Selector, _: Token, _: EventSet, _: PollOpt) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn reregister(&self, _: &mut Selector, _: Token, _: EventSet, _: PollOpt) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn deregister(&self, _: &mut Selector) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Pump {
+    // bytes waiting to be written to each side
+    bufs: [Vec<u8>; 2],
+    // whether each side has been read to the end
+    eof: [bool; 2],
+    transfers: [Option<tick::Transfer>; 2],
+}
+
+struct Side {
+    me: usize,
+    pump: Rc<RefCell<Pump>>,
+}
+
+impl Side {
     fn interest(&self) -> tick::Interest {
-        if self.eof {
-            tick::Interest::Remove
-        } else if self.pos < self.msg.len() {
-            tick::Interest::ReadWrite
+        let pump = self.pump.borrow();
+        let other = 1 - self.me;
+        let done = if self.me == TCP {
+            pump.eof[TCP] && pump.eof[STDIO] && pump.bufs[TCP].is_empty()
         } else {
-            tick::Interest::Read
+            pump.eof[TCP] && pump.bufs[STDIO].is_empty()
+        };
+        if done {
+            return tick::Interest::Remove;
+        }
+        let read = !pump.eof[self.me] && pump.bufs[other].len() < MAX_BUF;
+        let write = !pump.bufs[self.me].is_empty();
+        match (read, write) {
+            (true, true) => tick::Interest::ReadWrite,
+            (true, false) => tick::Interest::Read,
+            (false, true) => tick::Interest::Write,
+            (false, false) => tick::Interest::Wait,
+        }
+    }
+
+    fn wake(&self, side: usize, interest: tick::Interest) {
+        if let Some(ref transfer) = self.pump.borrow().transfers[side] {
+            let _ = transfer.interest(interest);
         }
     }
 }
 
-impl tick::Protocol<Tcp> for Client {
-    fn on_readable(&mut self, transport: &mut Tcp) -> tick::Interest {
+impl tick::Protocol<Conn> for Side {
+    fn on_readable(&mut self, transport: &mut tick::Io<Conn>) -> tick::Interest {
+        let other = 1 - self.me;
         let mut buf = [0u8; 4096];
-        loop {
-            match transport.read(&mut buf) {
-                Ok(0) => {
-                    self.eof = true;
-                    break;
-                }
-                Ok(n) => {
-                    stdout().write_all(&buf[..n]).unwrap();
+        {
+            let mut pump = self.pump.borrow_mut();
+            while pump.bufs[other].len() < MAX_BUF {
+                match transport.read(&mut buf) {
+                    Ok(0) => {
+                        pump.eof[self.me] = true;
+                        break;
+                    }
+                    Ok(n) => pump.bufs[other].extend_from_slice(&buf[..n]),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(_) => {
+                        pump.eof[self.me] = true;
+                        break;
+                    }
                 }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
-                Err(_) => return tick::Interest::Remove,
             }
         }
+        self.wake(other, tick::Interest::Write);
         self.interest()
     }
 
-    fn on_writable(&mut self, transport: &mut Tcp) -> tick::Interest {
-        while self.pos < self.msg.len() {
-            match transport.write(&self.msg[self.pos..]) {
-                Ok(0) => return tick::Interest::Remove,
-                Ok(n) => self.pos += n,
-                Err(e) => match e.kind() {
-                    io::ErrorKind::WouldBlock => break,
-                    _ => {
-                        return tick::Interest::Remove;
+    fn on_writable(&mut self, transport: &mut tick::Io<Conn>) -> tick::Interest {
+        let other = 1 - self.me;
+        {
+            let mut pump = self.pump.borrow_mut();
+            let mut pos = 0;
+            while pos < pump.bufs[self.me].len() {
+                match transport.write(&pump.bufs[self.me][pos..]) {
+                    Ok(0) => break,
+                    Ok(n) => pos += n,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(_) => {
+                        pos = pump.bufs[self.me].len();
+                        pump.eof[self.me] = true;
                     }
                 }
             }
+            pump.bufs[self.me].drain(..pos);
+            if self.me == TCP && pump.eof[STDIO] && pump.bufs[TCP].is_empty() {
+                if let Conn::Tcp(ref tcp) = **transport {
+                    let _ = tcp.shutdown(Shutdown::Write);
+                }
+            }
         }
+        self.wake(other, tick::Interest::Read);
         self.interest()
     }
 
     fn on_error(&mut self, err: tick::Error) {
-        self.eof = true;
-        println!("on_error: {:?}", err);
+        self.pump.borrow_mut().eof[self.me] = true;
+        eprintln!("on_error: {}", err);
     }
 }
 
 fn main() {
     env_logger::init().unwrap();
-    let mut tick = tick::Tick::<mio::tcp::TcpListener, _>::new(|_| (Client::new(), tick::Interest::Write));
+    let pump = Rc::new(RefCell::new(Pump::default()));
+    let factory_pump = pump.clone();
+    let mut tick = tick::Tick::<NoListener, _>::new(tick::with_context(move |transfer: tick::Transfer, ctx: &tick::Context<Conn>| {
+        let me = match *ctx.transport() {
+            Conn::Tcp(..) => TCP,
+            Conn::Stdio(..) => STDIO,
+        };
+        factory_pump.borrow_mut().transfers[me] = Some(transfer);
+        (Side { me, pump: factory_pump.clone() }, tick::Interest::Read)
+    }));
 
     let sock = mio::tcp::TcpStream::connect(&"127.0.0.1:1337".parse().unwrap()).unwrap();
-    let id = tick.stream(sock).unwrap();
-    println!("Connecting to 127.0.0.1:1337");
-    tick.run_until_complete(id).unwrap();
+    tick.stream(Conn::Tcp(sock)).unwrap();
+    let stdio = tick.stream(Conn::Stdio(tick::Stdio::new().unwrap())).unwrap();
+    eprintln!("Connecting to 127.0.0.1:1337");
+    tick.run_until_complete(stdio).unwrap();
 }
@@ -27,7 +27,7 @@ impl Echo {
 
 
 impl tick::Protocol<Tcp> for Echo {
-    fn on_readable(&mut self, transport: &mut Tcp) -> tick::Interest {
+    fn on_readable(&mut self, transport: &mut tick::Io<Tcp>) -> tick::Interest {
         if self.read_pos < self.buf.len() {
             match transport.read(&mut self.buf[self.read_pos..]) {
                 Ok(0) => self.eof = true,
@@ -45,7 +45,7 @@ impl tick::Protocol<Tcp> for Echo {
         self.interest()
     }
 
-    fn on_writable(&mut self, transport: &mut Tcp) -> tick::Interest {
+    fn on_writable(&mut self, transport: &mut tick::Io<Tcp>) -> tick::Interest {
         while self.write_pos < self.read_pos {
             match transport.write(&self.buf[self.write_pos..self.read_pos]) {
                 Ok(0) => panic!("write ZERO"),
@@ -0,0 +1,43 @@
+extern crate env_logger;
+extern crate mio;
+extern crate tick;
+
+use std::env;
+
+use tick::Interest;
+use tick::http::{Client, Request, Response};
+
+// requests each path in turn, reusing the connection while the server
+// keeps it alive
+fn main() {
+    env_logger::init().unwrap();
+    let mut args = env::args().skip(1);
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:3330".to_owned());
+    let mut paths: Vec<String> = args.collect();
+    if paths.is_empty() {
+        paths.push("/".to_owned());
+    }
+    paths.reverse();
+
+    let host = addr.clone();
+    let get = move |path: String| Request::new("GET", path).with_header("Host", host.clone());
+    let first = get(paths.pop().unwrap());
+    let mut next = Some((first, paths, get));
+    let mut tick = tick::Tick::<mio::tcp::TcpListener, _>::new(move |_| {
+        let (first, mut paths, get) = next.take().expect("only one stream");
+        let client = Client::new(first, move |res: Response| {
+            println!("{} {}", res.status, res.reason);
+            for (name, value) in &res.headers {
+                println!("{}: {}", name, value);
+            }
+            println!();
+            println!("{}", String::from_utf8_lossy(&res.body));
+            paths.pop().map(&get)
+        });
+        (client, Interest::Write)
+    });
+
+    let sock = mio::tcp::TcpStream::connect(&addr.parse().unwrap()).unwrap();
+    let id = tick.stream(sock).unwrap();
+    tick.run_until_complete(id).unwrap();
+}
@@ -0,0 +1,27 @@
+extern crate env_logger;
+extern crate mio;
+extern crate tick;
+
+use std::env;
+
+use mio::tcp::{TcpListener, TcpStream};
+use tick::http::{Request, Response, Server};
+
+// forwards every connection on 127.0.0.1:3333 to the given address, and
+// answers health checks on 127.0.0.1:3334 itself
+
+fn health(_: Request) -> Response {
+    Response::new(200).with_body("ok")
+}
+
+fn main() {
+    env_logger::init().unwrap();
+    let backend = env::args().nth(1).unwrap_or_else(|| "127.0.0.1:3330".to_owned());
+    let backend = backend.parse().unwrap();
+    let mut tick = tick::Tick::new(|_| (Server::new(health), tick::Interest::Read));
+    tick.accept(TcpListener::bind(&"127.0.0.1:3334".parse().unwrap()).unwrap()).unwrap();
+    let listener = TcpListener::bind(&"127.0.0.1:3333".parse().unwrap()).unwrap();
+    tick.proxy_accept(listener, move |_: &TcpStream| TcpStream::connect(&backend)).unwrap();
+    println!("Proxying 127.0.0.1:3333 to {}", backend);
+    tick.run().unwrap();
+}
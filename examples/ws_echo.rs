@@ -0,0 +1,36 @@
+extern crate env_logger;
+extern crate mio;
+extern crate tick;
+
+use tick::Interest;
+use tick::http::Request;
+use tick::http::ws::{code, Handler, Message, Sender, WebSocket};
+
+struct Echo;
+
+impl Handler for Echo {
+    fn on_open(&mut self, request: &Request, sender: &mut Sender) {
+        println!("opened {}", request.path);
+        sender.send(format!("welcome to {}", request.path));
+    }
+
+    fn on_message(&mut self, message: Message, sender: &mut Sender) {
+        match message {
+            Message::Text(ref text) if text == "bye" => sender.close(code::NORMAL, "bye"),
+            message => sender.send(message),
+        }
+    }
+
+    fn on_close(&mut self, code: u16, reason: &str) {
+        println!("closed {} {:?}", code, reason);
+    }
+}
+
+fn main() {
+    env_logger::init().unwrap();
+    let mut tick = tick::Tick::new(|_| (WebSocket::new(Echo), Interest::Read));
+    let sock = mio::tcp::TcpListener::bind(&"127.0.0.1:3332".parse().unwrap()).unwrap();
+    tick.accept(sock).unwrap();
+    println!("Listening on 127.0.0.1:3332");
+    tick.run().unwrap();
+}
@@ -0,0 +1,64 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The source of time for a `Tick`'s timeouts.
+///
+/// A `Tick` uses the system clock by default. A manual clock only moves when
+/// told to with `advance`, so tests can fire timeouts without sleeping.
+#[derive(Clone)]
+pub struct Clock {
+    manual: Option<Arc<Mutex<Instant>>>,
+}
+
+impl Clock {
+    /// A clock following real time.
+    pub fn system() -> Clock {
+        Clock { manual: None }
+    }
+
+    /// A virtual clock, starting at the current time and only moving when
+    /// `advance` is called.
+    pub fn manual() -> Clock {
+        Clock { manual: Some(Arc::new(Mutex::new(Instant::now()))) }
+    }
+
+    /// Whether this is a manual clock.
+    pub fn is_manual(&self) -> bool {
+        self.manual.is_some()
+    }
+
+    /// The current time according to this clock.
+    pub fn now(&self) -> Instant {
+        match self.manual {
+            Some(ref now) => *now.lock().unwrap(),
+            None => Instant::now(),
+        }
+    }
+
+    /// Moves a manual clock forward by `by`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this is the system clock.
+    pub fn advance(&self, by: Duration) {
+        match self.manual {
+            Some(ref now) => *now.lock().unwrap() += by,
+            None => panic!("cannot advance the system clock"),
+        }
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Clock {
+        Clock::system()
+    }
+}
+
+impl fmt::Debug for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Clock")
+            .field("manual", &self.is_manual())
+            .finish()
+    }
+}
@@ -1,55 +1,676 @@
+use std::collections::VecDeque;
+use std::io;
+use std::sync::Arc;
+#[cfg(unix)]
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
 use mio::{self, EventLoop, Token, EventSet, PollOpt, TryAccept};
 
+use protocol;
+use proxy::{self, Accept, Duplex, Proxy};
+use source::Source;
+#[cfg(unix)]
+use process::{self, Child, ChildPipe, Pipe, Process, Stdio};
+#[cfg(unix)]
+use pty;
+#[cfg(unix)]
+use signal::{Signal, Signals};
 use stream::Stream;
+use throttle;
+use timer::{self, Callback, Timer, Timers};
 use transfer;
-use ::{Interest, Protocol, ProtocolFactory, Transport};
-use internal::{Action, Message};
+use ::{Clock, Id, Interest, Io, LoopObserver, Protocol, ProtocolFactory, Stats, StreamStats, Transport};
+use internal::{Action, ListenerAction, Message};
 
-//pub type Thunk = Box<FnMut() + Send + 'static>;
+// how long a listener that ran out of descriptors or memory is paused for
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
 
 pub struct LoopHandler<F: ProtocolFactory<T::Output>,  T: TryAccept + mio::Evented> where <T as TryAccept>::Output: Transport {
-    pub transports: mio::util::Slab<Evented<F::Protocol, T>>,
+    transports: mio::util::Slab<Evented<F::Protocol, T>>,
+    // generation of each slot, bumped whenever the slot is emptied
+    generations: Vec<u64>,
+    // the slab starts at `capacity`, and doubles when full, up to `max`
+    capacity: usize,
+    max: Option<usize>,
     factory: F,
+    counters: Counters,
+    observer: Option<Box<dyn LoopObserver>>,
+    clock: Clock,
+    timers: Timers,
+    // register streams once, edge-triggered, tracking readiness ourselves
+    edge: bool,
+    // while stepping, events and messages are queued here instead of being
+    // dispatched, so they can be handled one at a time
+    buffering: bool,
+    pending: VecDeque<Pending>,
+    // set by Message::Shutdown, for loops driven by Tick instead of mio
+    shutdown: bool,
+    // set by Transfers whose message didn't fit in the notify queue
+    missed: Arc<AtomicBool>,
+    // an error that stopped the loop, returned from Tick's run methods
+    error: Option<::Error>,
+    // listeners paused by accept errors, until their backoff timer fires
+    backoff: Vec<Id>,
+    // the slot of the signal self-pipe, once signals are handled
+    #[cfg(unix)]
+    signals: Option<Token>,
+    // slots of spawned children, checked for exits on SIGCHLD
+    #[cfg(unix)]
+    children: Vec<Token>,
+    // commands of pty streams, with the stream ended when they exit
+    #[cfg(unix)]
+    ptys: Vec<(Id, Arc<Mutex<::std::process::Child>>)>,
+}
+
+enum Pending {
+    Ready(Token, EventSet),
+    Notify(Message),
+}
+
+#[derive(Default)]
+struct Counters {
+    accepted: u64,
+    rejected: u64,
+    removed: u64,
+    events: u64,
+    notifications: u64,
+    // bytes moved by streams that are no longer in the slab
+    bytes_read: u64,
+    bytes_written: u64,
 }
 
 pub enum Evented<P: Protocol<T::Output>, T: TryAccept + mio::Evented> where <T as TryAccept>::Output: Transport {
-    Listener(T),
+    // a listener, and whether accepting is paused
+    Listener(T, bool),
     Stream(Stream<P, T::Output>),
+    // anything else, added with Tick::register
+    Source(Box<dyn Source>),
+    // a listener whose streams are proxied, and whether accepting is paused
+    ProxyListener(Box<dyn Accept>, bool),
+    Proxy(Proxy),
+    // the second end of a proxy, which lives in the slot of the first
+    ProxyEnd(Token),
+    #[cfg(unix)]
+    Signals(Signals),
+    #[cfg(unix)]
+    Child(Child),
+    // a pipe to a child, which lives in the slot of its owning child
+    #[cfg(unix)]
+    ChildPipe(Token, Stdio),
 }
 
 impl<F: ProtocolFactory<T::Output>, T: TryAccept + mio::Evented> LoopHandler<F, T> where <T as TryAccept>::Output: Transport {
-    pub fn new(factory: F, size: usize) -> LoopHandler<F, T> {
+    pub fn new(factory: F, size: usize, max: Option<usize>, clock: Clock, edge: bool) -> LoopHandler<F, T> {
+        let size = match max {
+            Some(max) => ::std::cmp::min(size, max),
+            None => size,
+        };
         LoopHandler {
             transports: mio::util::Slab::new(size),
-            factory: factory,
+            generations: vec![0; size],
+            capacity: size,
+            max,
+            factory,
+            counters: Counters::default(),
+            observer: None,
+            clock,
+            timers: Timers::default(),
+            edge,
+            buffering: false,
+            pending: VecDeque::new(),
+            shutdown: false,
+            missed: Arc::new(AtomicBool::new(false)),
+            error: None,
+            backoff: Vec::new(),
+            #[cfg(unix)]
+            signals: None,
+            #[cfg(unix)]
+            children: Vec::new(),
+            #[cfg(unix)]
+            ptys: Vec::new(),
         }
     }
 
-    pub fn listener(&mut self, event_loop: &mut EventLoop<Self>, lis: T) -> ::Result<Token> {
-        let token = try!(self.transports.insert(Evented::Listener(lis))
-                         .map_err(|_| ::Error::TooManySockets));
+    fn id(&self, token: Token) -> Id {
+        Id(token, self.generations[token.0])
+    }
+
+    /// Whether `id` still refers to a listener or stream in the loop.
+    pub fn contains(&self, id: Id) -> bool {
+        self.transports.contains(id.0) && self.id(id.0) == id
+    }
+
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    pub fn timeout(&mut self, event_loop: &mut EventLoop<Self>, delay: Duration, callback: Callback) {
+        self.schedule(event_loop, delay, Timer::Callback(callback));
+    }
+
+    fn schedule(&mut self, event_loop: &mut EventLoop<Self>, delay: Duration, timer: Timer) {
+        trace!("timeout in {:?}, {} pending", delay, self.timers.len());
+        self.timers.insert(self.clock.now() + delay, timer);
+        if !self.clock.is_manual() {
+            // only used to wake up the loop, the timer fires from tick()
+            if let Err(e) = event_loop.timeout_ms((), timer::millis(delay)) {
+                error!("failed to schedule loop wakeup: {:?}", e);
+            }
+        }
+    }
+
+    pub fn set_buffering(&mut self, buffering: bool) {
+        self.buffering = buffering;
+    }
+
+    pub fn take_shutdown(&mut self) -> bool {
+        ::std::mem::replace(&mut self.shutdown, false)
+    }
+
+    pub fn take_error(&mut self) -> Option<::Error> {
+        self.error.take()
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Fires one due timer, or dispatches one queued event or message.
+    pub fn step(&mut self, event_loop: &mut EventLoop<Self>) -> bool {
+        if let Some(timer) = self.timers.pop_due(self.clock.now()) {
+            debug!("< Step timeout");
+            self.fire(event_loop, timer);
+            return true;
+        }
+        match self.pending.pop_front() {
+            Some(Pending::Ready(token, events)) => self.dispatch_ready(event_loop, token, events),
+            Some(Pending::Notify(msg)) => self.dispatch_notify(event_loop, msg),
+            None => return false,
+        }
+        true
+    }
+
+    pub fn flush(&mut self, event_loop: &mut EventLoop<Self>) {
+        while let Some(pending) = self.pending.pop_front() {
+            match pending {
+                Pending::Ready(token, events) => self.dispatch_ready(event_loop, token, events),
+                Pending::Notify(msg) => self.dispatch_notify(event_loop, msg),
+            }
+        }
+    }
+
+    pub fn observe(&mut self, observer: Box<dyn LoopObserver>) {
+        self.observer = Some(observer);
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            accepted: self.counters.accepted,
+            rejected: self.counters.rejected,
+            removed: self.counters.removed,
+            events: self.counters.events,
+            notifications: self.counters.notifications,
+            bytes_read: self.counters.bytes_read,
+            bytes_written: self.counters.bytes_written,
+            slots: self.transports.count(),
+            capacity: self.capacity,
+            .. Stats::default()
+        };
+        for slot in self.transports.iter() {
+            if let Evented::Stream(ref stream) = *slot {
+                let io = stream.io();
+                stats.active += 1;
+                stats.bytes_read += io.bytes_read();
+                stats.bytes_written += io.bytes_written();
+                stats.streams.push(StreamStats {
+                    id: self.id(stream.token()),
+                    bytes_read: io.bytes_read(),
+                    bytes_written: io.bytes_written(),
+                });
+            }
+        }
+        stats
+    }
+
+    /// Makes room for one more entry in the slab, growing it if needed.
+    fn reserve(&mut self) -> ::Result<()> {
+        if self.transports.count() < self.capacity {
+            return Ok(());
+        }
+        let mut grown = ::std::cmp::max(self.capacity * 2, 1);
+        if let Some(max) = self.max {
+            grown = ::std::cmp::min(grown, max);
+        }
+        if grown <= self.capacity {
+            trace!("slab at max capacity {}", self.capacity);
+            return Err(::Error::TooManySockets);
+        }
+        debug!("growing slab from {} to {}", self.capacity, grown);
+        self.transports.grow(grown - self.capacity);
+        self.generations.resize(grown, 0);
+        self.capacity = grown;
+        Ok(())
+    }
+
+    pub fn listener(&mut self, event_loop: &mut EventLoop<Self>, lis: T) -> ::Result<Id> {
+        self.reserve()?;
+        let token = self.transports.insert(Evented::Listener(lis, false))
+                        .map_err(|_| ::Error::TooManySockets)?;
         match self.transports.get(token) {
-            Some(&Evented::Listener(ref lis)) => {
-                try!(event_loop.register(
+            Some(Evented::Listener(lis, _)) => {
+                if let Err(e) = event_loop.register(
                     lis,
                     token,
                     EventSet::readable(),
                     PollOpt::level()
-                ));
-                Ok(token)
+                ) {
+                    self.transports.remove(token);
+                    self.generations[token.0] += 1;
+                    return Err(::Error::Register(e));
+                }
+                let id = self.id(token);
+                if let Some(ref mut observer) = self.observer {
+                    observer.on_listener(id);
+                }
+                Ok(id)
             }
             _ => unreachable!()
         }
     }
 
-    pub fn stream(&mut self, event_loop: &mut EventLoop<Self>, transport: T::Output) -> ::Result<Token> {
+    /// Pauses, resumes, or removes the listener for `id`.
+    pub fn listener_action(&mut self, event_loop: &mut EventLoop<Self>, id: Id, action: ListenerAction) -> ::Result<()> {
+        if !self.contains(id) {
+            return Err(::Error::Removed);
+        }
+        // an explicit pause or resume overrides any backoff
+        self.backoff.retain(|&paused| paused != id);
+        let token = id.0;
+        let (lis, paused): (&dyn mio::Evented, _) = match self.transports.get_mut(token) {
+            Some(&mut Evented::Listener(ref lis, ref mut paused)) => (lis, paused),
+            Some(&mut Evented::ProxyListener(ref lis, ref mut paused)) => (lis.evented(), paused),
+            _ => return Err(::Error::NotAListener),
+        };
+        debug!("  ListenerAction::{:?} {:?}", action, id);
+        match action {
+            ListenerAction::Pause if !*paused => {
+                event_loop.deregister(lis)?;
+                *paused = true;
+            }
+            ListenerAction::Resume if *paused => {
+                event_loop.register(
+                    lis,
+                    token,
+                    EventSet::readable(),
+                    PollOpt::level()
+                )?;
+                *paused = false;
+            }
+            ListenerAction::Remove => (),
+            _ => return Ok(()),
+        }
+        if action == ListenerAction::Remove {
+            self.action(event_loop, token, Action::Remove);
+        }
+        Ok(())
+    }
+
+    /// Adds an arbitrary `Source`, registered for `interest`.
+    pub fn register(&mut self, event_loop: &mut EventLoop<Self>, source: Box<dyn Source>, interest: Interest) -> ::Result<Id> {
+        let events = match interest {
+            Interest::Read => EventSet::readable(),
+            Interest::Write => EventSet::writable(),
+            Interest::ReadWrite => EventSet::readable() | EventSet::writable(),
+            i => panic!("Illegal initial interest {:?}", i),
+        };
+        self.reserve()?;
+        let token = self.transports.insert(Evented::Source(source))
+                        .map_err(|_| ::Error::TooManySockets)?;
+        if let Some(Evented::Source(source)) = self.transports.get(token) {
+            if let Err(e) = event_loop.register(
+                source.evented(),
+                token,
+                events,
+                PollOpt::level() | PollOpt::oneshot()
+            ) {
+                self.transports.remove(token);
+                self.generations[token.0] += 1;
+                return Err(::Error::Register(e));
+            }
+        }
+        Ok(self.id(token))
+    }
+
+    /// Adds a listener whose accepted streams are each proxied to a new
+    /// transport.
+    pub fn proxy_listener(&mut self, event_loop: &mut EventLoop<Self>, lis: Box<dyn Accept>) -> ::Result<Id> {
+        self.reserve()?;
+        let token = self.transports.insert(Evented::ProxyListener(lis, false))
+                        .map_err(|_| ::Error::TooManySockets)?;
+        if let Some(Evented::ProxyListener(lis, _)) = self.transports.get(token) {
+            if let Err(e) = event_loop.register(
+                lis.evented(),
+                token,
+                EventSet::readable(),
+                PollOpt::level()
+            ) {
+                self.transports.remove(token);
+                self.generations[token.0] += 1;
+                return Err(::Error::Register(e));
+            }
+        }
+        let id = self.id(token);
+        if let Some(ref mut observer) = self.observer {
+            observer.on_listener(id);
+        }
+        Ok(id)
+    }
+
+    /// Links two transports with a `Proxy`, taking a slot for each end.
+    pub fn proxy(&mut self, event_loop: &mut EventLoop<Self>, a: Box<dyn Duplex>, b: Box<dyn Duplex>) -> ::Result<Id> {
+        self.reserve()?;
+        let owner = self.transports.insert(Evented::Proxy(proxy::new(a, b)))
+                        .map_err(|_| ::Error::TooManySockets)?;
+        let peer = match self.reserve().and_then(|()| {
+            self.transports.insert(Evented::ProxyEnd(owner)).map_err(|_| ::Error::TooManySockets)
+        }) {
+            Ok(peer) => peer,
+            Err(e) => {
+                self.transports.remove(owner);
+                self.generations[owner.0] += 1;
+                return Err(e);
+            }
+        };
+        debug!("proxying {:?} and {:?}", owner, peer);
+        let result = match self.transports.get_mut(owner) {
+            Some(&mut Evented::Proxy(ref mut proxy)) => {
+                proxy.set_token(0, owner);
+                proxy.set_token(1, peer);
+                (0..2).try_for_each(|end| {
+                    let events = proxy.rearm(end).unwrap_or_else(EventSet::readable);
+                    event_loop.register(proxy.evented(end), proxy.token(end), events, PollOpt::level() | PollOpt::oneshot())
+                })
+            }
+            _ => unreachable!(),
+        };
+        if let Err(e) = result {
+            self.action(event_loop, owner, Action::Remove);
+            return Err(::Error::Register(e));
+        }
+        Ok(self.id(owner))
+    }
+
+    fn proxy_ready(&mut self, event_loop: &mut EventLoop<Self>, owner: Token, end: usize, events: EventSet) {
+        let done = match self.transports.get_mut(owner) {
+            Some(&mut Evented::Proxy(ref mut proxy)) => {
+                match proxy.ready(end, events) {
+                    Ok(true) => {
+                        debug!("proxy {:?} finished", owner);
+                        true
+                    }
+                    Ok(false) => match rearm(event_loop, proxy) {
+                        Ok(()) => false,
+                        Err(e) => {
+                            error!("failed to reregister proxy {:?}: {}", owner, e);
+                            true
+                        }
+                    },
+                    Err(e) => {
+                        debug!("proxy {:?} failed: {}", owner, e);
+                        true
+                    }
+                }
+            }
+            _ => {
+                warn!("end of unknown proxy {:?}", owner);
+                return;
+            }
+        };
+        if done {
+            self.action(event_loop, owner, Action::Remove);
+        }
+    }
+
+    /// Has `Interrupt` and `Terminate` shut the loop down, unless handlers
+    /// are already set for them.
+    #[cfg(unix)]
+    pub fn handle_signals(&mut self, event_loop: &mut EventLoop<Self>) -> ::Result<()> {
+        let signals = self.signals(event_loop)?;
+        for &signal in &[Signal::Interrupt, Signal::Terminate] {
+            if signals.handles(signal) {
+                continue;
+            }
+            let notify = event_loop.channel();
+            signals.handle(signal, Box::new(move || {
+                debug!("shutting down from {:?}", signal);
+                if let Err(e) = notify.send(Message::Shutdown) {
+                    error!("failed to shutdown from {:?}: {:?}", signal, e);
+                }
+            }))?;
+        }
+        Ok(())
+    }
+
+    // the signal self-pipe, added to the loop first if needed, without
+    // handling any signals yet
+    #[cfg(unix)]
+    fn signals(&mut self, event_loop: &mut EventLoop<Self>) -> ::Result<&mut Signals> {
+        if self.signals.is_none() {
+            self.add_signals(event_loop)?;
+        }
+        match self.signals.and_then(move |token| self.transports.get_mut(token)) {
+            Some(&mut Evented::Signals(ref mut signals)) => Ok(signals),
+            _ => unreachable!(),
+        }
+    }
+
+    #[cfg(unix)]
+    fn add_signals(&mut self, event_loop: &mut EventLoop<Self>) -> ::Result<()> {
+        let signals = Signals::new()?;
+        self.reserve()?;
+        let token = self.transports.insert(Evented::Signals(signals))
+                        .map_err(|_| ::Error::TooManySockets)?;
+        if let Some(Evented::Signals(signals)) = self.transports.get(token) {
+            if let Err(e) = event_loop.register(
+                signals.reader(),
+                token,
+                EventSet::readable(),
+                PollOpt::level()
+            ) {
+                self.transports.remove(token);
+                self.generations[token.0] += 1;
+                return Err(::Error::Register(e));
+            }
+        }
+        self.signals = Some(token);
+        Ok(())
+    }
+
+    /// Calls `handler` on the loop thread whenever `signal` is received.
+    #[cfg(unix)]
+    pub fn signal(&mut self, event_loop: &mut EventLoop<Self>, signal: Signal, handler: Box<dyn FnMut()>) -> ::Result<()> {
+        self.signals(event_loop)?.handle(signal, handler).map_err(From::from)
+    }
+
+    /// Spawns `command`, adding pipes to its stdin, stdout and stderr to the
+    /// loop, and watching for it to exit.
+    #[cfg(unix)]
+    pub fn spawn(&mut self, event_loop: &mut EventLoop<Self>, command: &mut ::std::process::Command, process: Box<dyn Process>) -> ::Result<Id> {
+        self.reap_children(event_loop)?;
+        self.reserve()?;
+        let (child, pipes) = process::spawn(command, process)?;
+        let token = match self.transports.insert(Evented::Child(child)) {
+            Ok(token) => token,
+            Err(Evented::Child(mut child)) => {
+                child.kill();
+                return Err(::Error::TooManySockets);
+            }
+            Err(_) => unreachable!(),
+        };
+        debug!("spawned child {:?}", token);
+        self.children.push(token);
+        for (stdio, pipe) in pipes {
+            if let Err(e) = self.child_pipe(event_loop, token, stdio, pipe) {
+                if let Some(&mut Evented::Child(ref mut child)) = self.transports.get_mut(token) {
+                    child.kill();
+                }
+                self.remove_child(event_loop, token);
+                return Err(e);
+            }
+        }
+        Ok(self.id(token))
+    }
+
+    /// Adds a pty stream, ending it once `child`, its command, exits.
+    #[cfg(unix)]
+    pub fn pty(&mut self, event_loop: &mut EventLoop<Self>, transport: T::Output, child: Arc<Mutex<::std::process::Child>>) -> ::Result<Id> {
+        self.reap_children(event_loop)?;
+        let id = self.stream(event_loop, transport, None)?;
+        self.ptys.push((id, child));
+        Ok(id)
+    }
+
+    // only SIGCHLD, to reap children; shutting down is left to
+    // handle_signals
+    #[cfg(unix)]
+    fn reap_children(&mut self, event_loop: &mut EventLoop<Self>) -> ::Result<()> {
+        let signals = self.signals(event_loop)?;
+        if !signals.handles(Signal::Child) {
+            signals.handle(Signal::Child, Box::new(|| ()))?;
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn child_pipe(&mut self, event_loop: &mut EventLoop<Self>, owner: Token, stdio: Stdio, pipe: ChildPipe) -> ::Result<()> {
+        self.reserve()?;
+        let token = self.transports.insert(Evented::ChildPipe(owner, stdio))
+                        .map_err(|_| ::Error::TooManySockets)?;
+        if let Err(e) = event_loop.register(
+            &pipe,
+            token,
+            stdio.events(),
+            PollOpt::level() | PollOpt::oneshot()
+        ) {
+            self.transports.remove(token);
+            self.generations[token.0] += 1;
+            return Err(::Error::Register(e));
+        }
+        if let Some(&mut Evented::Child(ref mut child)) = self.transports.get_mut(owner) {
+            child.set_pipe(stdio, Pipe { token, io: Io::new(pipe) });
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn child_ready(&mut self, event_loop: &mut EventLoop<Self>, owner: Token, stdio: Stdio, events: EventSet) {
+        let close = match self.transports.get_mut(owner) {
+            Some(&mut Evented::Child(ref mut child)) => {
+                match child.ready(stdio, events).into() {
+                    Action::Register(events) => {
+                        let pipe = child.pipe(stdio).expect("ready pipe");
+                        match event_loop.reregister(
+                            &*pipe.io,
+                            pipe.token,
+                            events,
+                            PollOpt::level() | PollOpt::oneshot()
+                        ) {
+                            Ok(()) => false,
+                            Err(e) => {
+                                error!("failed to reregister child {} {:?}: {}", child.id(), stdio, e);
+                                true
+                            }
+                        }
+                    }
+                    Action::Wait => false,
+                    Action::Remove => true,
+                }
+            }
+            _ => {
+                warn!("pipe for unknown child {:?}", owner);
+                return;
+            }
+        };
+        if close {
+            self.close_pipe(event_loop, owner, stdio);
+        }
+    }
+
+    #[cfg(unix)]
+    fn close_pipe(&mut self, event_loop: &mut EventLoop<Self>, owner: Token, stdio: Stdio) {
+        let pipe = match self.transports.get_mut(owner) {
+            Some(&mut Evented::Child(ref mut child)) => child.take_pipe(stdio),
+            _ => None,
+        };
+        if let Some(pipe) = pipe {
+            trace!("closing {:?} of child {:?}", stdio, owner);
+            let _ = event_loop.deregister(&*pipe.io);
+            self.transports.remove(pipe.token);
+            self.generations[pipe.token.0] += 1;
+        }
+    }
+
+    /// Tells the `Process` of every child that has exited, and removes them.
+    #[cfg(unix)]
+    fn reap(&mut self, event_loop: &mut EventLoop<Self>) {
+        for token in self.children.clone() {
+            if let Some(&mut Evented::Child(ref mut child)) = self.transports.get_mut(token) {
+                match child.try_wait() {
+                    Some(status) => child.exited(status),
+                    None => continue,
+                }
+            }
+            self.remove_child(event_loop, token);
+        }
+        // a pty stream whose command exited is read to its end, and
+        // removed, as if the master had hung up
+        let mut exited = Vec::new();
+        self.ptys.retain(|&(id, ref child)| match pty::lock(child).try_wait() {
+            Ok(None) => true,
+            _ => {
+                exited.push(id);
+                false
+            }
+        });
+        for id in exited {
+            if self.contains(id) {
+                self.dispatch_ready(event_loop, id.0, EventSet::readable() | EventSet::hup());
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn remove_child(&mut self, event_loop: &mut EventLoop<Self>, token: Token) {
+        for &stdio in &[Stdio::In, Stdio::Out, Stdio::Err] {
+            self.close_pipe(event_loop, token, stdio);
+        }
+        self.children.retain(|&child| child != token);
+        if self.transports.remove(token).is_some() {
+            self.generations[token.0] += 1;
+        }
+    }
+
+    pub fn stream(&mut self, event_loop: &mut EventLoop<Self>, transport: T::Output, listener: Option<Id>) -> ::Result<Id> {
+        self.reserve()?;
         let notify = event_loop.channel();
+        let missed = self.missed.clone();
         let factory = &mut self.factory;
+        let generations = &self.generations;
+        let clock = &self.clock;
         let maybe_token = self.transports.insert_with(move |token| {
             trace!("inserting new stream {:?}", token);
-            let transfer = transfer::new(token, notify);
-            let (proto, interest) = factory.create(transfer);
-            Evented::Stream(Stream::new(transport, proto, interest))
+            let shared = transfer::shared();
+            let id = Id(token, generations[token.0]);
+            let transfer = transfer::new(id, shared.clone(), notify, missed);
+            let context = protocol::context(&transport, listener, id);
+            let (proto, interest) = factory.create(transfer, &context);
+            let limiter = protocol::take_throttle(&context)
+                .and_then(|throttle| throttle::limiter(&throttle, clock.clone()));
+            let mut stream = Stream::new(token, transport, proto, interest, shared);
+            stream.throttle(limiter);
+            Evented::Stream(stream)
         });
         let token = match maybe_token {
             Some(token) => token,
@@ -59,28 +680,48 @@ impl<F: ProtocolFactory<T::Output>, T: TryAccept + mio::Evented> LoopHandler<F,
             }
         };
         match self.transports.get(token) {
-            Some(&Evented::Stream(ref stream)) => {
+            Some(Evented::Stream(stream)) => {
                 let events = match stream.interest() {
                     Interest::Read => EventSet::readable(),
                     Interest::Write => EventSet::writable(),
                     Interest::ReadWrite => EventSet::readable() | EventSet::writable(),
                     i => panic!("Illegal initial interest {:?}", i),
                 };
+                // hup isn't asked for, since mio maps it to EPOLLRDHUP, and
+                // a peer that only shut down its writing half would look
+                // like it hung up; reading EOF tells the protocol instead
+                let (events, opts) = if self.edge {
+                    (EventSet::readable() | EventSet::writable(), PollOpt::edge())
+                } else {
+                    (events, PollOpt::level() | PollOpt::oneshot())
+                };
                 trace!("registering initial '{:?}' for {:?}", events, token);
-                try!(event_loop.register(
+                if let Err(e) = event_loop.register(
                     stream.transport(),
                     token,
                     events,
-                    PollOpt::level() | PollOpt::oneshot()
-                ));
-                Ok(token)
+                    opts
+                ) {
+                    debug!("failed to register {:?}: {:?}", token, e);
+                    if let Some(Evented::Stream(stream)) = self.transports.remove(token) {
+                        self.generations[token.0] += 1;
+                        stream.removed();
+                    }
+                    return Err(::Error::Register(e));
+                }
+                Ok(self.id(token))
             },
             _ => unreachable!()
         }
     }
 
     fn action(&mut self, event_loop: &mut EventLoop<Self>, token: Token, action: Action) {
+        let id = self.id(token);
         let next = match action {
+            Action::Register(events) if self.edge => {
+                debug!("  Action::Register {:?}, '{:?}', edge registered", token, events);
+                None
+            }
             Action::Register(events) => {
                 match self.transports.get_mut(token) {
                     Some(&mut Evented::Stream(ref mut stream)) => {
@@ -93,7 +734,7 @@ impl<F: ProtocolFactory<T::Output>, T: TryAccept + mio::Evented> LoopHandler<F,
                         ) {
                             Ok(..) => None,
                             Err(e) => {
-                                stream.errored(e.into());
+                                errored(&mut self.observer, id, stream, ::Error::Register(e));
                                 Some(Action::Remove)
                             }
                         }
@@ -114,13 +755,56 @@ impl<F: ProtocolFactory<T::Output>, T: TryAccept + mio::Evented> LoopHandler<F,
             },
             Action::Remove => {
                 debug!("  Action::remove {:?}", token);
+                match self.transports.get(token) {
+                    #[cfg(unix)]
+                    Some(&Evented::Child(_)) | Some(&Evented::ChildPipe(..)) => {
+                        warn!("children are removed once they exit {:?}", token);
+                        return;
+                    }
+                    Some(&Evented::ProxyEnd(owner)) => {
+                        warn!("proxy ends are removed with their proxy {:?}", owner);
+                        return;
+                    }
+                    _ => (),
+                }
                 if let Some(slot) = self.transports.remove(token) {
+                    self.generations[token.0] += 1;
                     match slot {
-                        Evented::Listener(lis) => {
+                        Evented::Listener(lis, _) => {
                             let _ = event_loop.deregister(&lis);
                         }
+                        Evented::Source(source) => {
+                            let _ = event_loop.deregister(source.evented());
+                        }
+                        Evented::ProxyListener(lis, _) => {
+                            let _ = event_loop.deregister(lis.evented());
+                        }
+                        // dropping the proxy closes both ends, passing on
+                        // any error as a reset or EOF
+                        Evented::Proxy(proxy) => {
+                            let _ = event_loop.deregister(proxy.evented(0));
+                            let _ = event_loop.deregister(proxy.evented(1));
+                            let peer = proxy.token(1);
+                            if self.transports.remove(peer).is_some() {
+                                self.generations[peer.0] += 1;
+                            }
+                        }
+                        Evented::ProxyEnd(_) => unreachable!(),
+                        #[cfg(unix)]
+                        Evented::Signals(signals) => {
+                            let _ = event_loop.deregister(signals.reader());
+                            self.signals = None;
+                        }
+                        #[cfg(unix)]
+                        Evented::Child(_) | Evented::ChildPipe(..) => unreachable!(),
                         Evented::Stream(stream) => {
                             let _ = event_loop.deregister(stream.transport());
+                            self.counters.removed += 1;
+                            self.counters.bytes_read += stream.io().bytes_read();
+                            self.counters.bytes_written += stream.io().bytes_written();
+                            if let Some(ref mut observer) = self.observer {
+                                observer.on_remove(id);
+                            }
                             stream.removed();
                         }
                     }
@@ -135,28 +819,170 @@ impl<F: ProtocolFactory<T::Output>, T: TryAccept + mio::Evented> LoopHandler<F,
     }
 }
 
+fn errored<P: Protocol<T>, T: Transport>(observer: &mut Option<Box<dyn LoopObserver>>, id: Id, stream: &mut Stream<P, T>, err: ::Error) {
+    if let Some(ref mut observer) = *observer {
+        observer.on_error(id, &err);
+    }
+    stream.errored(err);
+}
+
+/// Calls an edge-triggered stream for `events`, queueing it to be called
+/// again if it is still ready once it has had its share of dispatches, so
+/// that other streams get a turn in between. If the notify queue is full,
+/// `missed` is set, for the loop's next tick to pick the stream up.
+fn edge_ready<H, P, T>(event_loop: &mut EventLoop<H>, missed: &AtomicBool, id: Id, stream: &mut Stream<P, T>, events: EventSet) -> Option<::Error>
+where H: mio::Handler<Message = Message>, P: Protocol<T>, T: Transport {
+    let err = stream.edge_ready(id.0, events);
+    if err.is_none() && stream.is_edge_ready() {
+        if let Err(e) = event_loop.channel().send(Message::Dispatch(id)) {
+            debug!("failed to dispatch {:?} again, leaving it to the next tick: {:?}", id, e);
+            missed.store(true, Ordering::SeqCst);
+        }
+    }
+    err
+}
+
+/// Reregisters each end of `proxy` that has new readiness to wait on.
+fn rearm<H: mio::Handler>(event_loop: &mut EventLoop<H>, proxy: &mut Proxy) -> io::Result<()> {
+    for end in 0..2 {
+        if let Some(events) = proxy.rearm(end) {
+            event_loop.reregister(proxy.evented(end), proxy.token(end), events, PollOpt::level() | PollOpt::oneshot())?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(PartialEq)]
+enum AcceptFailure {
+    Retry,
+    Backoff,
+    Fatal,
+}
+
+fn accept_failure(e: &io::Error) -> AcceptFailure {
+    match e.kind() {
+        io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::ConnectionReset |
+        io::ErrorKind::Interrupted => return AcceptFailure::Retry,
+        _ => (),
+    }
+    if is_exhausted(e) {
+        AcceptFailure::Backoff
+    } else {
+        AcceptFailure::Fatal
+    }
+}
+
+#[cfg(unix)]
+fn is_exhausted(e: &io::Error) -> bool {
+    match e.raw_os_error() {
+        Some(code) => [::libc::EMFILE, ::libc::ENFILE, ::libc::ENOBUFS, ::libc::ENOMEM].contains(&code),
+        None => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_exhausted(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::OutOfMemory
+}
+
 enum Ready<T: Transport> {
     Insert(T),
+    Proxy(Box<dyn Duplex>, Box<dyn Duplex>),
+    AcceptError(io::Error),
     Action(Token, Action)
 }
 
-impl<F: ProtocolFactory<T::Output>, T: TryAccept + mio::Evented> mio::Handler for LoopHandler<F, T> where <T as TryAccept>::Output: Transport {
-    type Message = Message;
-    type Timeout = ();
-    //type Timeout = Thunk;
-    fn ready(&mut self, event_loop: &mut EventLoop<Self>, token: Token, events: EventSet) {
+impl<F: ProtocolFactory<T::Output>, T: TryAccept + mio::Evented> LoopHandler<F, T> where <T as TryAccept>::Output: Transport {
+    fn dispatch_ready(&mut self, event_loop: &mut EventLoop<Self>, token: Token, events: EventSet) {
         debug!("< Ready {:?} '{:?}'", token, events);
+        self.counters.events += 1;
+        let id = match self.generations.get(token.0) {
+            Some(&generation) => Id(token, generation),
+            None => {
+                warn!("unknown token ready {:?}", token);
+                return;
+            }
+        };
         let next = match self.transports.get_mut(token) {
-            Some(&mut Evented::Listener(ref lis)) => {
+            Some(&mut Evented::Listener(ref lis, _)) => {
                 match lis.accept() {
                     Ok(Some(stream)) => Ready::Insert(stream),
                     Ok(None) => return,
-                    Err(e) => panic!("unimplemented accept error {:?}", e),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                    Err(e) => Ready::AcceptError(e),
                 }
             },
+            Some(&mut Evented::ProxyListener(ref mut lis, _)) => {
+                match lis.accept() {
+                    Ok(Some((a, b))) => Ready::Proxy(a, b),
+                    Ok(None) => return,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                    Err(e) => Ready::AcceptError(e),
+                }
+            }
+            Some(&mut Evented::Proxy(_)) => {
+                self.proxy_ready(event_loop, token, 0, events);
+                return;
+            }
+            Some(&mut Evented::ProxyEnd(owner)) => {
+                self.proxy_ready(event_loop, owner, 1, events);
+                return;
+            }
             Some(&mut Evented::Stream(ref mut stream)) => {
-                stream.ready(token, events);
-                Ready::Action(token, stream.interest().into())
+                let before = stream.interest();
+                let err = if self.edge {
+                    edge_ready(event_loop, &self.missed, id, stream, events)
+                } else {
+                    stream.ready(token, events)
+                };
+                if let Some(err) = err {
+                    errored(&mut self.observer, id, stream, err);
+                }
+                if let Some(ref mut observer) = self.observer {
+                    if stream.interest() != before {
+                        observer.on_interest(id, stream.interest());
+                    }
+                }
+                Ready::Action(token, stream.registered().into())
+            }
+            Some(&mut Evented::Source(ref mut source)) => {
+                trace!("source ready {:?}, '{:?}'", token, events);
+                match source.ready(events).into() {
+                    Action::Register(events) => {
+                        match event_loop.reregister(
+                            source.evented(),
+                            token,
+                            events,
+                            PollOpt::level() | PollOpt::oneshot()
+                        ) {
+                            Ok(()) => return,
+                            Err(e) => {
+                                error!("failed to reregister source {:?}: {}", token, e);
+                                Ready::Action(token, Action::Remove)
+                            }
+                        }
+                    }
+                    Action::Wait => return,
+                    Action::Remove => Ready::Action(token, Action::Remove),
+                }
+            }
+            #[cfg(unix)]
+            Some(&mut Evented::Signals(ref mut signals)) => {
+                if signals.dispatch() {
+                    self.reap(event_loop);
+                }
+                return;
+            }
+            #[cfg(unix)]
+            Some(&mut Evented::Child(_)) => {
+                warn!("children aren't registered {:?}", token);
+                return;
+            }
+            #[cfg(unix)]
+            Some(&mut Evented::ChildPipe(owner, stdio)) => {
+                self.child_ready(event_loop, owner, stdio, events);
+                return;
             }
             None => {
                 warn!("unknown token ready {:?}", token);
@@ -167,36 +993,197 @@ impl<F: ProtocolFactory<T::Output>, T: TryAccept + mio::Evented> mio::Handler fo
         match next {
             Ready::Action(token, action) => {
                 self.action(event_loop, token, action);
+                self.throttled(event_loop, token);
             },
+            Ready::Proxy(a, b) => {
+                match self.proxy(event_loop, a, b) {
+                    Ok(_) => self.counters.accepted += 1,
+                    Err(e) => {
+                        warn!("failed to add proxy: {:?}", e);
+                        self.counters.rejected += 1;
+                    }
+                }
+            }
+            Ready::AcceptError(e) => self.accept_failed(event_loop, id, e),
             Ready::Insert(transport) => {
-                let _ = self.stream(event_loop, transport);
+                match self.stream(event_loop, transport, Some(id)) {
+                    Ok(stream) => {
+                        self.counters.accepted += 1;
+                        if let Some(ref mut observer) = self.observer {
+                            observer.on_accept(id, stream);
+                        }
+                    }
+                    Err(e) => {
+                        warn!("failed to add accepted stream: {:?}", e);
+                        self.counters.rejected += 1;
+                    }
+                }
             }
         }
     }
 
-    /*
-    fn timeout(&mut self, _: &mut EventLoop<Self>, mut cb: Thunk) {
-        debug!("< Timeout");
-        cb();
+    fn fire(&mut self, event_loop: &mut EventLoop<Self>, timer: Timer) {
+        match timer {
+            Timer::Callback(callback) => callback(),
+            Timer::Resume(id) => self.resume(event_loop, id, EventSet::none()),
+            Timer::Accept(id) => {
+                if !self.backoff.contains(&id) {
+                    trace!("backoff of {:?} already lifted", id);
+                    return;
+                }
+                debug!("resuming accept on {:?} after backoff", id);
+                if let Err(e) = self.listener_action(event_loop, id, ListenerAction::Resume) {
+                    error!("failed to resume accept on {:?}: {:?}", id, e);
+                }
+            }
+        }
     }
-    */
 
-    fn notify(&mut self, event_loop: &mut EventLoop<Self>, msg: Message) {
+    // keeps a listener whose accept failed from spinning: transient errors
+    // are retried on the next event, running out of descriptors or memory
+    // pauses it for a while, and anything else pauses it and stops the loop
+    fn accept_failed(&mut self, event_loop: &mut EventLoop<Self>, id: Id, e: io::Error) {
+        error!("accept error on {:?}: {}", id, e);
+        let failure = accept_failure(&e);
+        let err = ::Error::Accept(e);
+        if let Some(ref mut observer) = self.observer {
+            observer.on_error(id, &err);
+        }
+        if failure == AcceptFailure::Retry {
+            return;
+        }
+        if let Err(e) = self.listener_action(event_loop, id, ListenerAction::Pause) {
+            error!("failed to pause accept on {:?}: {:?}", id, e);
+        }
+        if failure == AcceptFailure::Backoff {
+            debug!("pausing accept on {:?} for {:?}", id, ACCEPT_BACKOFF);
+            self.backoff.push(id);
+            self.schedule(event_loop, ACCEPT_BACKOFF, Timer::Accept(id));
+        } else if self.error.is_none() {
+            self.error = Some(err);
+            event_loop.shutdown();
+        }
+    }
+
+    // queues the interest Transfers added, and the dispatches of streams
+    // still edge-ready, while the notify queue was full
+    fn recheck(&mut self, event_loop: &mut EventLoop<Self>) {
+        let mut interest = Vec::new();
+        let mut dispatch = Vec::new();
+        for slot in self.transports.iter() {
+            if let Evented::Stream(ref s) = *slot {
+                if transfer::has_pending(s.shared()) {
+                    interest.push(self.id(s.token()));
+                } else if self.edge && s.is_edge_ready() {
+                    dispatch.push(self.id(s.token()));
+                }
+            }
+        }
+        for id in interest {
+            debug!("picking up interest for {:?} missed by a full queue", id);
+            mio::Handler::notify(self, event_loop, Message::Interest(id));
+        }
+        for id in dispatch {
+            debug!("dispatching {:?} again after a full queue", id);
+            mio::Handler::notify(self, event_loop, Message::Dispatch(id));
+        }
+    }
+
+    // schedules the stream in `token` to resume, if it has just run out of
+    // bytes to read or write
+    fn throttled(&mut self, event_loop: &mut EventLoop<Self>, token: Token) {
+        let delay = match self.transports.get_mut(token) {
+            Some(&mut Evented::Stream(ref mut s)) => s.schedule_resume(),
+            _ => None,
+        };
+        if let Some(delay) = delay {
+            let id = self.id(token);
+            trace!("{:?} throttled, resuming in {:?}", token, delay);
+            self.schedule(event_loop, delay, Timer::Resume(id));
+        }
+    }
+
+    // calls the protocol for whatever its refilled buckets allow again,
+    // along with `released`, directions throttled by limits since replaced
+    fn resume(&mut self, event_loop: &mut EventLoop<Self>, id: Id, released: EventSet) {
+        if !self.contains(id) {
+            trace!("resume for removed stream {:?}", id);
+            return;
+        }
+        let token = id.0;
+        let action = match self.transports.get_mut(token) {
+            Some(&mut Evented::Stream(ref mut s)) => {
+                let before = s.interest();
+                let events = s.resume(released);
+                let err = if self.edge {
+                    // readiness seen while throttled is no longer masked
+                    edge_ready(event_loop, &self.missed, id, s, EventSet::none())
+                } else if events != EventSet::none() {
+                    // the socket was left undrained when the bucket ran dry
+                    s.ready(token, events)
+                } else {
+                    None
+                };
+                if let Some(err) = err {
+                    errored(&mut self.observer, id, s, err);
+                }
+                if let Some(ref mut observer) = self.observer {
+                    if s.interest() != before {
+                        observer.on_interest(id, s.interest());
+                    }
+                }
+                s.registered().into()
+            }
+            _ => return,
+        };
+        self.action(event_loop, token, action);
+        self.throttled(event_loop, token);
+    }
+
+    fn dispatch_notify(&mut self, event_loop: &mut EventLoop<Self>, msg: Message) {
+        self.counters.notifications += 1;
         match msg {
-            Message::Interest(token, interest) => {
-                debug!("< Notify Message::Interest {:?} {:?}", token, interest);
+            Message::Interest(id) => {
+                debug!("< Notify Message::Interest {:?}", id);
+                if !self.contains(id) {
+                    warn!("interest for removed stream {:?}", id);
+                    return;
+                }
+                let token = id.0;
                 let action = match self.transports.get_mut(token) {
                     Some(&mut Evented::Stream(ref mut s)) => {
-                        let action = (s.interest() + interest).into();
-                        match action {
+                        let interest = transfer::take_interest(s.shared());
+                        let before = s.interest();
+                        let action = (before + interest).into();
+                        let action = match action {
+                            Action::Register(_) if self.edge => {
+                                // only call back for readiness already seen
+                                s.set_interest(before + interest);
+                                if let Some(err) = edge_ready(event_loop, &self.missed, id, s, EventSet::none()) {
+                                    errored(&mut self.observer, id, s, err);
+                                }
+                                s.registered().into()
+                            }
                             Action::Register(events) => {
                                 // pretend these events are ready, incase the
                                 // socket wasn't drained before
-                                s.ready(token, events);
-                                s.interest().into()
+                                if let Some(err) = s.ready(token, events) {
+                                    errored(&mut self.observer, id, s, err);
+                                }
+                                s.registered().into()
                             }
                             _ => action
+                        };
+                        if let Some(ref mut observer) = self.observer {
+                            let after = match action {
+                                Action::Remove => Interest::Remove,
+                                _ => s.interest(),
+                            };
+                            if after != before {
+                                observer.on_interest(id, after);
+                            }
                         }
+                        action
                     }
                     _ => {
                         warn!("unknown token interested {:?}", token);
@@ -204,21 +1191,679 @@ impl<F: ProtocolFactory<T::Output>, T: TryAccept + mio::Evented> mio::Handler fo
                     }
                 };
                 self.action(event_loop, token, action);
+                self.throttled(event_loop, token);
             }
-            /*
-            Message::Timeout(cb, when) => {
-                debug!("< Notify Message::Timeout {}ms", when);
-                event_loop.timeout_ms(cb, when);
+            Message::Dispatch(id) => {
+                debug!("< Notify Message::Dispatch {:?}", id);
+                if !self.contains(id) {
+                    trace!("dispatch for removed stream {:?}", id);
+                    return;
+                }
+                let token = id.0;
+                let action = match self.transports.get_mut(token) {
+                    Some(&mut Evented::Stream(ref mut s)) => {
+                        let before = s.interest();
+                        if let Some(err) = edge_ready(event_loop, &self.missed, id, s, EventSet::none()) {
+                            errored(&mut self.observer, id, s, err);
+                        }
+                        if let Some(ref mut observer) = self.observer {
+                            if s.interest() != before {
+                                observer.on_interest(id, s.interest());
+                            }
+                        }
+                        s.registered().into()
+                    }
+                    _ => return,
+                };
+                self.action(event_loop, token, action);
+                self.throttled(event_loop, token);
+            }
+            Message::Throttle(id, throttle) => {
+                debug!("< Notify Message::Throttle {:?}", id);
+                if !self.contains(id) {
+                    warn!("throttle for removed stream {:?}", id);
+                    return;
+                }
+                let limiter = throttle::limiter(&throttle, self.clock.clone());
+                let released = match self.transports.get_mut(id.0) {
+                    Some(&mut Evented::Stream(ref mut s)) => s.throttle(limiter),
+                    _ => {
+                        warn!("unknown token throttled {:?}", id.0);
+                        return;
+                    }
+                };
+                self.resume(event_loop, id, released);
+            }
+            Message::Timeout(cb, delay) => {
+                debug!("< Notify Message::Timeout {:?}", delay);
+                self.timeout(event_loop, delay, cb);
+            }
+            Message::Listener(id, action) => {
+                debug!("< Notify Message::Listener {:?} {:?}", id, action);
+                if let Err(e) = self.listener_action(event_loop, id, action) {
+                    warn!("listener {:?} failed: {:?}", action, e);
+                }
+            }
+            Message::Stats(tx) => {
+                debug!("< Notify Message::Stats");
+                let _ = tx.send(self.stats());
             }
-            */
             Message::Shutdown => {
                 debug!("< Notify Message::Shutdown");
+                self.shutdown = true;
                 event_loop.shutdown();
             }
         }
     }
 
-    fn tick(&mut self, _event_loop: &mut EventLoop<Self>) {
+}
+
+impl<F: ProtocolFactory<T::Output>, T: TryAccept + mio::Evented> mio::Handler for LoopHandler<F, T> where <T as TryAccept>::Output: Transport {
+    type Message = Message;
+    type Timeout = ();
+
+    fn ready(&mut self, event_loop: &mut EventLoop<Self>, token: Token, events: EventSet) {
+        if self.buffering {
+            self.pending.push_back(Pending::Ready(token, events));
+        } else {
+            self.dispatch_ready(event_loop, token, events);
+        }
+    }
+
+    fn notify(&mut self, event_loop: &mut EventLoop<Self>, msg: Message) {
+        if self.buffering {
+            self.pending.push_back(Pending::Notify(msg));
+        } else {
+            self.dispatch_notify(event_loop, msg);
+        }
+    }
+
+    fn tick(&mut self, event_loop: &mut EventLoop<Self>) {
         trace!("tick");
+        if !self.buffering {
+            let now = self.clock.now();
+            while let Some(timer) = self.timers.pop_due(now) {
+                debug!("< Timeout");
+                self.fire(event_loop, timer);
+            }
+        }
+        if self.missed.swap(false, Ordering::SeqCst) {
+            self.recheck(event_loop);
+        }
+        if let Some(ref mut observer) = self.observer {
+            observer.on_tick();
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::io::{self, Read, Write};
+    use std::net::Shutdown;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    use mio::EventLoop;
+    use mio::tcp::{TcpListener, TcpStream};
+
+    use internal::Action;
+    use process::Stdio;
+    use super::{Evented, LoopHandler};
+    use ::{Clock, Context, Interest, Io, MockListener, MockTransport, Protocol, Throttle, Tick, TickConfig, Transfer};
+
+    fn deadline() -> Instant {
+        Instant::now() + Duration::from_secs(5)
+    }
+
+    // reads everything it's given, and is removed at EOF
+    struct Drain;
+
+    impl Protocol<MockTransport> for Drain {
+        fn on_readable(&mut self, transport: &mut Io<MockTransport>) -> Interest {
+            let mut buf = [0; 64];
+            loop {
+                match transport.read(&mut buf) {
+                    Ok(0) => return Interest::Remove,
+                    Ok(_) => (),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Interest::Read,
+                    Err(_) => return Interest::Remove,
+                }
+            }
+        }
+
+        fn on_writable(&mut self, _: &mut Io<MockTransport>) -> Interest {
+            Interest::Read
+        }
+
+        fn on_error(&mut self, _: ::Error) {}
+    }
+
+    // reads a byte per call, counting them, and is removed at EOF
+    struct Trickle(Rc<Cell<usize>>);
+
+    impl Protocol<MockTransport> for Trickle {
+        fn on_readable(&mut self, transport: &mut Io<MockTransport>) -> Interest {
+            match transport.read(&mut [0]) {
+                Ok(0) => Interest::Remove,
+                Ok(_) => {
+                    self.0.set(self.0.get() + 1);
+                    Interest::Read
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Interest::Read,
+                Err(_) => Interest::Remove,
+            }
+        }
+
+        fn on_writable(&mut self, _: &mut Io<MockTransport>) -> Interest {
+            Interest::Read
+        }
+
+        fn on_error(&mut self, _: ::Error) {}
+    }
+
+    #[test]
+    fn edge_triggered_streams_are_dispatched_until_drained() {
+        let count = Rc::new(Cell::new(0));
+        let mut config = TickConfig::new();
+        config.edge_triggered(true);
+        let counter = count.clone();
+        let factory = move |_: Transfer| (Trickle(counter.clone()), Interest::Read);
+        let mut tick = Tick::<MockListener, _>::configured(factory, config);
+        let mut transport = MockTransport::new();
+        // far more than a single event's share of dispatches
+        transport.feed(&[7; 100]);
+        transport.feed_eof();
+        let id = tick.stream(transport).unwrap();
+        tick.run_until_complete_by(id, deadline()).unwrap();
+        assert_eq!(count.get(), 100);
+    }
+
+    #[test]
+    fn throttled_streams_resume_once_refilled() {
+        let count = Rc::new(Cell::new(0));
+        let transfers = Rc::new(RefCell::new(Vec::new()));
+        let (counter, created) = (count.clone(), transfers.clone());
+        let factory = ::with_context(move |transfer, ctx: &Context<MockTransport>| {
+            ctx.throttle(Throttle::new().read(100, Duration::from_secs(1)));
+            created.borrow_mut().push(transfer);
+            (Trickle(counter.clone()), Interest::Read)
+        });
+        let mut tick = Tick::<MockListener, _>::simulated(factory);
+        let mut transport = MockTransport::new();
+        transport.feed(&[7; 1000]);
+        transport.feed_eof();
+        let id = tick.stream(transport).unwrap();
+
+        while tick.step().unwrap() {}
+        assert_eq!(count.get(), 100);
+        // nothing more until the clock moves
+        while tick.step().unwrap() {}
+        assert_eq!(count.get(), 100);
+
+        tick.advance(Duration::from_secs(1));
+        while tick.step().unwrap() {}
+        assert_eq!(count.get(), 200);
+
+        // lifting the limits lets the rest through without the clock
+        transfers.borrow()[0].throttle(&Throttle::new()).unwrap();
+        while tick.step().unwrap() {}
+        assert_eq!(count.get(), 1000);
+        assert!(tick.stats().streams.iter().all(|stream| stream.id != id));
+    }
+
+    // reads to EOF, then replies and is removed
+    struct Reply {
+        eof: bool,
+        errors: Rc<RefCell<Vec<::Error>>>,
+        removed: Rc<Cell<bool>>,
+    }
+
+    impl Protocol<TcpStream> for Reply {
+        fn on_readable(&mut self, transport: &mut Io<TcpStream>) -> Interest {
+            let mut buf = [0; 64];
+            loop {
+                match transport.read(&mut buf) {
+                    Ok(0) => {
+                        self.eof = true;
+                        return Interest::Write;
+                    }
+                    Ok(_) => (),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Interest::Read,
+                    Err(_) => return Interest::Remove,
+                }
+            }
+        }
+
+        fn on_writable(&mut self, transport: &mut Io<TcpStream>) -> Interest {
+            if !self.eof {
+                return Interest::Read;
+            }
+            transport.write_all(b"bye").unwrap();
+            Interest::Remove
+        }
+
+        fn on_error(&mut self, err: ::Error) {
+            self.errors.borrow_mut().push(err);
+        }
+
+        fn on_remove(self, _: TcpStream) {
+            self.removed.set(true);
+        }
+    }
+
+    fn half_close(edge: bool) {
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        let removed = Rc::new(Cell::new(false));
+        let mut config = TickConfig::new();
+        config.edge_triggered(edge);
+        let (e, r) = (errors.clone(), removed.clone());
+        let factory = move |_: Transfer| {
+            (Reply { eof: false, errors: e.clone(), removed: r.clone() }, Interest::Read)
+        };
+        let mut tick = Tick::configured(factory, config);
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        tick.accept(listener).unwrap();
+
+        let mut client = ::std::net::TcpStream::connect(addr).unwrap();
+        client.write_all(b"hi").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        for _ in 0..50 {
+            if removed.get() {
+                break;
+            }
+            tick.run_once(Some(Duration::from_millis(100))).unwrap();
+        }
+
+        assert!(removed.get(), "stream wasn't removed");
+        assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).unwrap();
+        assert_eq!(reply, b"bye");
+    }
+
+    #[test]
+    fn streams_write_after_the_peer_half_closes() {
+        half_close(false);
+    }
+
+    #[test]
+    fn edge_triggered_streams_write_after_the_peer_half_closes() {
+        half_close(true);
+    }
+
+    #[test]
+    fn refused_connections_keep_their_error() {
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        let removed = Rc::new(Cell::new(false));
+        let (e, r) = (errors.clone(), removed.clone());
+        let factory = move |_: Transfer| {
+            (Reply { eof: false, errors: e.clone(), removed: r.clone() }, Interest::Read)
+        };
+        let mut tick = Tick::<TcpListener, _>::new(factory);
+        // nothing listens on a port once its listener is dropped
+        let addr = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let id = tick.stream(TcpStream::connect(&addr).unwrap()).unwrap();
+        tick.run_until_complete_by(id, deadline()).unwrap();
+
+        assert!(removed.get());
+        let errors = errors.borrow();
+        assert_eq!(errors.len(), 1);
+        match errors[0] {
+            ::Error::ConnectRefused(ref e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused),
+            ref e => panic!("expected ConnectRefused, got {:?}", e),
+        }
+        assert!(::std::error::Error::source(&errors[0]).is_some());
+    }
+
+    // writes back everything it reads, and is removed at EOF
+    struct Echo;
+
+    impl Protocol<MockTransport> for Echo {
+        fn on_readable(&mut self, transport: &mut Io<MockTransport>) -> Interest {
+            let mut buf = [0; 64];
+            loop {
+                match transport.read(&mut buf) {
+                    Ok(0) => return Interest::Remove,
+                    Ok(n) => transport.write_all(&buf[..n]).unwrap(),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Interest::Read,
+                    Err(_) => return Interest::Remove,
+                }
+            }
+        }
+
+        fn on_writable(&mut self, _: &mut Io<MockTransport>) -> Interest {
+            Interest::Read
+        }
+
+        fn on_error(&mut self, _: ::Error) {}
+    }
+
+    #[test]
+    fn stats_count_streams_and_bytes() {
+        let mut config = TickConfig::new();
+        config.transports_capacity(4);
+        let mut tick = Tick::<MockListener, _>::configured(|_| (Echo, Interest::Read), config);
+        let listener = MockListener::new();
+        tick.accept(listener.clone()).unwrap();
+        let mut accepted = MockTransport::new();
+        accepted.feed(b"hello");
+        listener.push(accepted);
+        let mut closing = MockTransport::new();
+        closing.feed(b"bye");
+        closing.feed_eof();
+        let closing = tick.stream(closing).unwrap();
+        while tick.step().unwrap() {}
+
+        let stats = tick.stats();
+        assert_eq!((stats.accepted, stats.rejected), (1, 0));
+        assert_eq!((stats.active, stats.removed), (1, 1));
+        // the listener holds a slot too
+        assert_eq!((stats.slots, stats.capacity), (2, 4));
+        assert!(stats.events >= 3);
+        assert_eq!(stats.notifications, 0);
+        assert_eq!(stats.streams.len(), 1);
+        assert!(stats.streams[0].id != closing);
+        assert_eq!((stats.streams[0].bytes_read, stats.streams[0].bytes_written), (5, 5));
+        // removed streams still count towards the totals
+        assert_eq!((stats.bytes_read, stats.bytes_written), (8, 8));
+    }
+
+    #[test]
+    fn stats_can_be_asked_for_through_notify() {
+        let mut tick = Tick::<MockListener, _>::simulated(|_| (Echo, Interest::Read));
+        let mut transport = MockTransport::new();
+        transport.feed(b"ping");
+        let id = tick.stream(transport).unwrap();
+        let notify = tick.notify();
+        let asked = ::std::thread::spawn(move || notify.stats().unwrap()).join().unwrap();
+        while tick.step().unwrap() {}
+
+        let stats = asked.recv().unwrap();
+        assert_eq!(stats.notifications, 1);
+        assert_eq!(stats.active, 1);
+        assert_eq!(stats.streams[0].id, id);
+        assert_eq!(stats.bytes_read, 4);
+        assert_eq!(tick.stats().notifications, 1);
+    }
+
+    #[test]
+    fn registry_grows_past_its_initial_capacity() {
+        let mut config = TickConfig::new();
+        config.transports_capacity(2);
+        let mut tick = Tick::<MockListener, _>::configured(|_| (Drain, Interest::Read), config);
+        for _ in 0..5 {
+            tick.stream(MockTransport::new()).unwrap();
+        }
+
+        let stats = tick.stats();
+        assert_eq!(stats.active, 5);
+        assert_eq!(stats.capacity, 8);
+    }
+
+    #[test]
+    fn registry_stops_growing_at_max_transports() {
+        let mut config = TickConfig::new();
+        config.transports_capacity(2).max_transports(Some(3));
+        let mut tick = Tick::<MockListener, _>::configured(|_| (Drain, Interest::Read), config);
+        for _ in 0..3 {
+            tick.stream(MockTransport::new()).unwrap();
+        }
+        match tick.stream(MockTransport::new()) {
+            Err(::Error::TooManySockets) => (),
+            other => panic!("expected TooManySockets, got {:?}", other),
+        }
+
+        let stats = tick.stats();
+        assert_eq!(stats.active, 3);
+        assert_eq!(stats.capacity, 3);
+    }
+
+    #[test]
+    fn stale_ids_dont_reach_reused_slots() {
+        let transfers = Rc::new(RefCell::new(Vec::new()));
+        let mut config = TickConfig::new();
+        config.transports_capacity(1);
+        let kept = transfers.clone();
+        let factory = move |transfer: Transfer| {
+            kept.borrow_mut().push(transfer);
+            (Drain, Interest::Read)
+        };
+        let mut tick = Tick::<MockListener, _>::configured(factory, config);
+        let mut first = MockTransport::new();
+        first.feed_eof();
+        let old = tick.stream(first).unwrap();
+        tick.run_until_complete_by(old, deadline()).unwrap();
+        let new = tick.stream(MockTransport::new()).unwrap();
+
+        assert_eq!(old.0, new.0);
+        assert!(old != new);
+        let ids: Vec<_> = tick.stats().streams.iter().map(|stream| stream.id).collect();
+        assert_eq!(ids, vec![new]);
+        let transfers = transfers.borrow();
+        assert!(transfers[0].is_removed());
+        match transfers[0].interest(Interest::Remove) {
+            Err(::Error::Removed) => (),
+            other => panic!("expected Removed, got {:?}", other),
+        }
+        assert!(!transfers[1].is_removed());
+        while tick.step().unwrap() {}
+        assert_eq!(tick.stats().active, 1);
+    }
+
+    #[test]
+    fn paused_listeners_accept_nothing_until_resumed() {
+        let mut tick = Tick::<MockListener, _>::simulated(|_: Transfer| (Drain, Interest::Read));
+        let listener = MockListener::new();
+        let id = tick.accept(listener.clone()).unwrap();
+        tick.pause_accept(id).unwrap();
+        listener.push(MockTransport::new());
+        while tick.step().unwrap() {}
+        assert_eq!(tick.stats().accepted, 0);
+
+        tick.resume_accept(id).unwrap();
+        while tick.step().unwrap() {}
+        assert_eq!(tick.stats().accepted, 1);
+    }
+
+    #[test]
+    fn removed_listeners_are_reported_as_removed() {
+        let mut tick = Tick::<MockListener, _>::simulated(|_: Transfer| (Drain, Interest::Read));
+        let listener = MockListener::new();
+        let id = tick.accept(listener.clone()).unwrap();
+        tick.remove_listener(id).unwrap();
+        listener.push(MockTransport::new());
+        while tick.step().unwrap() {}
+        assert_eq!(tick.stats().accepted, 0);
+
+        for result in [tick.pause_accept(id), tick.resume_accept(id), tick.remove_listener(id)] {
+            match result {
+                Err(::Error::Removed) => (),
+                other => panic!("expected Removed, got {:?}", other),
+            }
+        }
+        // nor does its slot's next occupant answer for it
+        let next = tick.accept(MockListener::new()).unwrap();
+        assert_eq!(next.0, id.0);
+        match tick.pause_accept(id) {
+            Err(::Error::Removed) => (),
+            other => panic!("expected Removed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn listener_actions_on_streams_are_refused() {
+        let mut tick = Tick::<MockListener, _>::simulated(|_: Transfer| (Drain, Interest::Read));
+        let id = tick.stream(MockTransport::new()).unwrap();
+        for result in [tick.pause_accept(id), tick.resume_accept(id), tick.remove_listener(id)] {
+            match result {
+                Err(::Error::NotAListener) => (),
+                other => panic!("expected NotAListener, got {:?}", other),
+            }
+        }
+        assert_eq!(tick.stats().active, 1);
+    }
+
+    #[test]
+    fn aborted_connections_are_skipped() {
+        let mut tick = Tick::simulated(|_| (Drain, Interest::Read));
+        let listener = MockListener::new();
+        tick.accept(listener.clone()).unwrap();
+        listener.push_error(io::ErrorKind::ConnectionAborted.into());
+        listener.push(MockTransport::new());
+        while tick.step().unwrap() {}
+
+        assert_eq!(tick.stats().accepted, 1);
+    }
+
+    #[test]
+    fn listeners_back_off_when_out_of_descriptors() {
+        let mut tick = Tick::simulated(|_| (Drain, Interest::Read));
+        let listener = MockListener::new();
+        tick.accept(listener.clone()).unwrap();
+        listener.push_error(io::Error::from_raw_os_error(::libc::EMFILE));
+        listener.push(MockTransport::new());
+        while tick.step().unwrap() {}
+        assert_eq!(tick.stats().accepted, 0);
+
+        tick.advance(super::ACCEPT_BACKOFF);
+        while tick.step().unwrap() {}
+        assert_eq!(tick.stats().accepted, 1);
+    }
+
+    #[test]
+    fn other_accept_errors_stop_the_loop() {
+        let mut tick = Tick::simulated(|_| (Drain, Interest::Read));
+        let listener = MockListener::new();
+        let id = tick.accept(listener.clone()).unwrap();
+        listener.push_error(io::Error::other("broken"));
+        listener.push(MockTransport::new());
+        loop {
+            match tick.step() {
+                Ok(true) => (),
+                Ok(false) => panic!("accept error wasn't returned"),
+                Err(::Error::Accept(ref e)) if e.to_string() == "broken" => break,
+                Err(e) => panic!("unexpected error: {:?}", e),
+            }
+        }
+        // the listener is left paused
+        tick.advance(Duration::from_secs(60));
+        while tick.step().unwrap() {}
+        assert_eq!(tick.stats().accepted, 0);
+
+        tick.resume_accept(id).unwrap();
+        while tick.step().unwrap() {}
+        assert_eq!(tick.stats().accepted, 1);
+    }
+
+    // counts the times it's called back for writing, waiting in between
+    struct Writes(Rc<Cell<usize>>);
+
+    impl Protocol<MockTransport> for Writes {
+        fn on_readable(&mut self, _: &mut Io<MockTransport>) -> Interest {
+            Interest::Wait
+        }
+
+        fn on_writable(&mut self, _: &mut Io<MockTransport>) -> Interest {
+            self.0.set(self.0.get() + 1);
+            Interest::Wait
+        }
+
+        fn on_error(&mut self, _: ::Error) {}
+    }
+
+    #[test]
+    fn interest_isnt_lost_when_the_notify_queue_is_full() {
+        let writes = Rc::new(Cell::new(0));
+        let transfers = Rc::new(RefCell::new(Vec::new()));
+        let (counter, kept) = (writes.clone(), transfers.clone());
+        let factory = move |transfer: Transfer| {
+            kept.borrow_mut().push(transfer);
+            (Writes(counter.clone()), Interest::Read)
+        };
+        let mut tick = Tick::<MockListener, _>::simulated(factory);
+        tick.stream(MockTransport::new()).unwrap();
+        let notify = tick.notify();
+        while notify.timeout(Duration::from_secs(0), || ()).is_ok() {}
+
+        let transfer = transfers.borrow()[0].clone();
+        match transfer.interest(Interest::Write) {
+            Err(::Error::NotifyFull) => (),
+            other => panic!("expected NotifyFull, got {:?}", other),
+        }
+        // merged into the interest the loop is yet to pick up
+        transfer.interest(Interest::Write).unwrap();
+        while tick.step().unwrap() {}
+        assert_eq!(writes.get(), 1);
+
+        transfer.interest(Interest::Write).unwrap();
+        while tick.step().unwrap() {}
+        assert_eq!(writes.get(), 2);
+    }
+
+    #[test]
+    fn edge_dispatches_arent_lost_when_the_notify_queue_is_full() {
+        let count = Rc::new(Cell::new(0));
+        let mut config = TickConfig::new();
+        config.edge_triggered(true);
+        let counter = count.clone();
+        let factory = move |_: Transfer| (Trickle(counter.clone()), Interest::Read);
+        let mut tick = Tick::<MockListener, _>::configured(factory, config);
+        let mut transport = MockTransport::new();
+        transport.feed(&[7; 100]);
+        transport.feed_eof();
+        let id = tick.stream(transport).unwrap();
+        let notify = tick.notify();
+        while notify.timeout(Duration::from_secs(0), || ()).is_ok() {}
+
+        // the stream can't queue its next turn, and has no more events
+        tick.run_until_complete_by(id, deadline()).unwrap();
+        assert_eq!(count.get(), 100);
+    }
+
+    #[test]
+    fn removing_a_child_pipe_leaves_it_in_place() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let mut handler = LoopHandler::<_, MockListener>::new(|_| (Drain, Interest::Read), 4, None, Clock::system(), false);
+        let token = handler.transports.insert(Evented::ChildPipe(::mio::Token(0), Stdio::Out)).ok().unwrap();
+        let id = handler.id(token);
+        handler.action(&mut event_loop, token, Action::Remove);
+        assert!(handler.contains(id));
+        match handler.transports.get(token) {
+            Some(&Evented::ChildPipe(..)) => (),
+            _ => panic!("child pipe was removed"),
+        }
+    }
+
+    #[test]
+    fn removing_a_proxy_end_leaves_it_in_place() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let mut handler = LoopHandler::<_, MockListener>::new(|_| (Drain, Interest::Read), 4, None, Clock::system(), false);
+        let token = handler.transports.insert(Evented::ProxyEnd(::mio::Token(0))).ok().unwrap();
+        let id = handler.id(token);
+        handler.action(&mut event_loop, token, Action::Remove);
+        assert!(handler.contains(id));
+        match handler.transports.get(token) {
+            Some(&Evented::ProxyEnd(_)) => (),
+            _ => panic!("proxy end was removed"),
+        }
+    }
+
+    #[test]
+    fn rejected_streams_arent_counted_as_accepted() {
+        let mut config = TickConfig::new();
+        config.max_transports(Some(2));
+        let mut tick = Tick::configured(|_| (Drain, Interest::Read), config);
+        let listener = MockListener::new();
+        tick.accept(listener.clone()).unwrap();
+        listener.push(MockTransport::new());
+        listener.push(MockTransport::new());
+        while tick.step().unwrap() {}
+
+        let stats = tick.stats();
+        assert_eq!(stats.accepted, 1);
+        assert_eq!(stats.rejected, 1);
+        assert_eq!(stats.active, 1);
     }
 }
@@ -0,0 +1,386 @@
+use std::collections::VecDeque;
+use std::io::{self, Read};
+
+use ::{Interest, Io, Protocol, Transport, WriteQueue};
+use super::{Body, BodyState, Request, Response};
+
+// how much is read from the transport at a time
+const READ_SIZE: usize = 8 * 1024;
+
+/// Receives the responses to a `Client`'s requests.
+pub trait ResponseHandler {
+    /// Called with the response to each request, in the order they were
+    /// sent, returning another request to send on the same connection.
+    fn on_response(&mut self, response: Response) -> Option<Request>;
+
+    /// Called if a request fails, after which the connection is closed.
+    ///
+    /// Defaults to logging the error.
+    fn on_error(&mut self, error: ::Error) {
+        debug!("http client error: {}", error);
+    }
+}
+
+impl<F: FnMut(Response) -> Option<Request>> ResponseHandler for F {
+    fn on_response(&mut self, response: Response) -> Option<Request> {
+        self(response)
+    }
+}
+
+/// An HTTP/1.1 client `Protocol`.
+///
+/// Requests are sent one at a time, each once the response to the one
+/// before it has been read in full, so a connection the server keeps alive
+/// is reused for each of them. Response bodies may be sent with a
+/// `Content-Length`, chunked, or delimited by the server closing the
+/// connection. Once there are no more requests, the stream is removed. If
+/// the server won't keep the connection alive, any requests still queued
+/// fail instead.
+///
+/// A `Client` starts with a request to write, so the factory should return
+/// it with `Interest::Write`.
+///
+/// ```no_run
+/// # extern crate mio;
+/// # extern crate tick;
+/// # fn main() {
+/// use tick::http::{Client, Request, Response};
+///
+/// let mut tick = tick::Tick::<mio::tcp::TcpListener, _>::new(|_| {
+///     let request = Request::new("GET", "/").with_header("Host", "localhost");
+///     let client = Client::new(request, |res: Response| {
+///         println!("{} {}", res.status, String::from_utf8_lossy(&res.body));
+///         None
+///     });
+///     (client, tick::Interest::Write)
+/// });
+/// let addr = "127.0.0.1:3330".parse().unwrap();
+/// let id = tick.stream(mio::tcp::TcpStream::connect(&addr).unwrap()).unwrap();
+/// tick.run_until_complete(id).unwrap();
+/// # }
+/// ```
+pub struct Client<H> {
+    handler: H,
+    queue: VecDeque<Request>,
+    out: WriteQueue,
+    buf: Vec<u8>,
+    // the request waiting on a response
+    sent: Option<Sent>,
+    // a response whose head has been parsed, while its body arrives, and
+    // whether the body runs until the connection closes
+    current: Option<(Response, Body, bool)>,
+    failed: bool,
+}
+
+struct Sent {
+    head_only: bool,
+    keep_alive: bool,
+}
+
+impl<H: ResponseHandler> Client<H> {
+    /// Creates a client for a single connection, which sends `request`
+    /// first and hands its response to `handler`.
+    pub fn new(request: Request, handler: H) -> Client<H> {
+        let mut client = Client {
+            handler,
+            queue: VecDeque::new(),
+            out: WriteQueue::new(),
+            buf: Vec::new(),
+            sent: None,
+            current: None,
+            failed: false,
+        };
+        client.push(request);
+        client
+    }
+
+    /// Queues another request, to be sent after those already queued.
+    pub fn push(&mut self, request: Request) {
+        self.queue.push_back(request);
+        if self.sent.is_none() {
+            self.send_next();
+        }
+    }
+
+    fn send_next(&mut self) {
+        if let Some(request) = self.queue.pop_front() {
+            trace!("http client sending {} {}", request.method, request.path);
+            self.sent = Some(Sent {
+                head_only: request.method == "HEAD",
+                keep_alive: request.keep_alive(),
+            });
+            write_request(&mut self.out, request);
+        }
+    }
+
+    fn fail(&mut self, error: ::Error) -> Interest {
+        if !self.failed {
+            self.failed = true;
+            self.handler.on_error(error);
+        }
+        Interest::Remove
+    }
+
+    /// Parses as much of a response as is buffered, handing it to the
+    /// handler once complete. Returns whether the connection can still be
+    /// used.
+    fn process(&mut self, eof: bool) -> Result<bool, &'static str> {
+        loop {
+            let head_only = match self.sent {
+                Some(ref sent) => sent.head_only,
+                None if self.buf.is_empty() => return Ok(true),
+                None => return Err("response without a request"),
+            };
+            if self.current.is_none() {
+                let (response, body, len) = match parse_response(&self.buf, head_only)? {
+                    Some(response) => response,
+                    None => return Ok(true),
+                };
+                self.buf.drain(..len);
+                // interim responses, such as 100 Continue, are skipped
+                if response.status < 200 {
+                    continue;
+                }
+                let close_delimited = body.state == BodyState::Close;
+                self.current = Some((response, body, close_delimited));
+            }
+
+            let done = {
+                let (response, body, _) = self.current.as_mut().unwrap();
+                let n = body.decode(&self.buf, &mut response.body)?;
+                self.buf.drain(..n);
+                if eof {
+                    body.eof()?;
+                }
+                body.is_done()
+            };
+            if !done {
+                return Ok(true);
+            }
+
+            let (response, _, close_delimited) = self.current.take().unwrap();
+            let sent = self.sent.take().unwrap();
+            let reusable = sent.keep_alive && response.keep_alive() && !close_delimited;
+            if let Some(request) = self.handler.on_response(response) {
+                self.queue.push_back(request);
+            }
+            if !reusable {
+                return Ok(false);
+            }
+            self.send_next();
+        }
+    }
+
+    fn flush<T: Transport>(&mut self, transport: &mut Io<T>) -> io::Result<()> {
+        match self.out.write_to(transport) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+
+    // the connection can't be reused, so any requests left fail
+    fn close(&mut self) -> Interest {
+        if self.queue.is_empty() {
+            return Interest::Remove;
+        }
+        let error = io::Error::new(io::ErrorKind::ConnectionAborted, "connection can't be reused");
+        self.fail(error.into())
+    }
+
+    fn interest(&self) -> Interest {
+        if !self.out.is_empty() {
+            Interest::ReadWrite
+        } else if self.sent.is_some() {
+            Interest::Read
+        } else {
+            Interest::Remove
+        }
+    }
+}
+
+impl<T: Transport, H: ResponseHandler> Protocol<T> for Client<H> {
+    fn on_readable(&mut self, transport: &mut Io<T>) -> Interest {
+        let mut chunk = [0; READ_SIZE];
+        let mut eof = false;
+        while !eof {
+            match transport.read(&mut chunk) {
+                Ok(0) => eof = true,
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return self.fail(e.into()),
+            }
+        }
+        let reusable = match self.process(eof) {
+            Ok(reusable) => reusable && !eof,
+            Err(msg) => return self.fail(::Error::protocol(msg)),
+        };
+        if eof && self.sent.is_some() {
+            let error = io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection");
+            return self.fail(error.into());
+        }
+        if !reusable {
+            return self.close();
+        }
+        if let Err(e) = self.flush(transport) {
+            return self.fail(e.into());
+        }
+        self.interest()
+    }
+
+    fn on_writable(&mut self, transport: &mut Io<T>) -> Interest {
+        if let Err(e) = self.flush(transport) {
+            return self.fail(e.into());
+        }
+        self.interest()
+    }
+
+    fn on_error(&mut self, error: ::Error) {
+        self.fail(error);
+    }
+}
+
+fn parse_response(buf: &[u8], head_only: bool) -> Result<Option<(Response, Body, usize)>, &'static str> {
+    let (head, len) = match super::parse_head(buf, false)? {
+        Some(head) => head,
+        None => return Ok(None),
+    };
+    let [_, status, reason] = head.start;
+    let status = status.parse::<u16>().map_err(|_| "invalid status code")?;
+    let response = Response {
+        status,
+        reason,
+        version: head.version,
+        headers: head.headers,
+        body: Vec::new(),
+    };
+    let body = if head_only || status < 200 || status == 204 || status == 304 {
+        Body::new(BodyState::Done)
+    } else {
+        super::body_length(&response.headers, true)?
+    };
+    Ok(Some((response, body, len)))
+}
+
+fn write_request(out: &mut WriteQueue, request: Request) {
+    let mut extra = Vec::new();
+    if request.header("Content-Length").is_none() && request.header("Transfer-Encoding").is_none()
+        && (!request.body.is_empty() || request.method == "POST" || request.method == "PUT") {
+        extra.push(("Content-Length", request.body.len().to_string()));
+    }
+    let start = format!("{} {} HTTP/1.{}", request.method, request.path, request.version);
+    super::write_head(out, &start, &request.headers, &extra);
+    out.push(request.body);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use ::{Interest, MockDriver};
+    use ::http::{Request, Response};
+    use super::{Client, ResponseHandler};
+
+    #[derive(Clone, Default)]
+    struct Record {
+        responses: Rc<RefCell<Vec<Response>>>,
+        errors: Rc<RefCell<Vec<::Error>>>,
+    }
+
+    impl ResponseHandler for Record {
+        fn on_response(&mut self, response: Response) -> Option<Request> {
+            self.responses.borrow_mut().push(response);
+            None
+        }
+
+        fn on_error(&mut self, error: ::Error) {
+            self.errors.borrow_mut().push(error);
+        }
+    }
+
+    // a client that has sent each of `requests`' methods to "/"
+    fn client(methods: &[&str]) -> (MockDriver<Client<Record>>, Record) {
+        let record = Record::default();
+        let handler = record.clone();
+        let requests: Vec<_> = methods.iter().map(|&method| Request::new(method, "/")).collect();
+        let mut driver = MockDriver::new(move |_| {
+            let mut requests = requests.clone().into_iter();
+            let mut client = Client::new(requests.next().unwrap(), handler.clone());
+            for request in requests {
+                client.push(request);
+            }
+            (client, Interest::Write)
+        });
+        driver.assert_writable(Interest::Read);
+        (driver, record)
+    }
+
+    fn bodies(record: &Record) -> Vec<(u16, Vec<u8>)> {
+        record.responses.borrow().iter().map(|r| (r.status, r.body.clone())).collect()
+    }
+
+    #[test]
+    fn head_responses_have_no_body() {
+        let (mut driver, record) = client(&["HEAD", "GET"]);
+        assert!(driver.transport().take_written().starts_with(b"HEAD / HTTP/1.1\r\n"));
+        driver.transport().feed(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n");
+        driver.assert_readable(Interest::Read);
+        assert!(driver.transport().take_written().starts_with(b"GET / HTTP/1.1\r\n"));
+        driver.transport().feed(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello");
+        driver.assert_readable(Interest::Remove);
+        assert_eq!(bodies(&record), vec![(200, Vec::new()), (200, b"hello".to_vec())]);
+        assert!(record.errors.borrow().is_empty());
+    }
+
+    #[test]
+    fn no_content_and_not_modified_responses_have_no_body() {
+        let (mut driver, record) = client(&["GET", "GET", "GET"]);
+        driver.transport().feed(b"HTTP/1.1 204 No Content\r\nContent-Length: 5\r\n\r\n");
+        driver.transport().feed(b"HTTP/1.1 304 Not Modified\r\nTransfer-Encoding: chunked\r\n\r\n");
+        driver.transport().feed(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+        driver.assert_readable(Interest::Remove);
+        assert_eq!(bodies(&record), vec![(204, Vec::new()), (304, Vec::new()), (200, b"ok".to_vec())]);
+        assert!(record.errors.borrow().is_empty());
+    }
+
+    #[test]
+    fn interim_responses_are_skipped() {
+        let (mut driver, record) = client(&["POST"]);
+        driver.transport().feed(b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n");
+        driver.assert_readable(Interest::Remove);
+        assert_eq!(bodies(&record), vec![(201, Vec::new())]);
+    }
+
+    #[test]
+    fn close_delimited_bodies_end_the_connection() {
+        let (mut driver, record) = client(&["GET", "GET"]);
+        driver.transport().feed(b"HTTP/1.1 200 OK\r\n\r\nuntil ");
+        driver.assert_readable(Interest::Read);
+        driver.transport().feed(b"the end");
+        driver.transport().feed_eof();
+        driver.assert_readable(Interest::Remove);
+        assert_eq!(bodies(&record), vec![(200, b"until the end".to_vec())]);
+        // the second request couldn't be sent on the same connection
+        assert_eq!(record.errors.borrow().len(), 1);
+    }
+
+    #[test]
+    fn chunked_responses_are_decoded() {
+        let (mut driver, record) = client(&["GET"]);
+        driver.transport().feed(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2;ext\r\nok\r\n");
+        driver.assert_readable(Interest::Read);
+        driver.transport().feed(b"0\r\nX-Trailer: yes\r\n\r\n");
+        driver.assert_readable(Interest::Remove);
+        assert_eq!(bodies(&record), vec![(200, b"ok".to_vec())]);
+    }
+
+    #[test]
+    fn short_bodies_are_errors() {
+        let (mut driver, record) = client(&["GET"]);
+        driver.transport().feed(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhel");
+        driver.transport().feed_eof();
+        driver.assert_readable(Interest::Remove);
+        assert!(record.responses.borrow().is_empty());
+        assert_eq!(record.errors.borrow().len(), 1);
+    }
+}
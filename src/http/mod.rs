@@ -0,0 +1,674 @@
+//! HTTP/1.1 protocols.
+//!
+//! `Server` parses requests off a stream and hands them to a `Handler`,
+//! writing back whatever `Response` it returns. `Client` does the reverse,
+//! sending requests and handing each `Response` to a `ResponseHandler`.
+//! The `ws` module upgrades a connection to a WebSocket.
+
+use std::fmt::Write as FmtWrite;
+use std::str;
+
+use ::WriteQueue;
+
+pub use self::client::{Client, ResponseHandler};
+pub use self::server::{Handler, Server};
+
+mod client;
+mod server;
+pub mod ws;
+
+// the most bytes a request or response head may take up
+const MAX_HEAD: usize = 64 * 1024;
+// the most bytes a chunk size or trailer line may take up
+const MAX_LINE: usize = 4 * 1024;
+// the error for a body longer than its limit
+const BODY_TOO_LARGE: &str = "body too large";
+// the error for a request with a transfer coding other than chunked
+const UNSUPPORTED_ENCODING: &str = "unsupported transfer encoding";
+
+/// An HTTP request.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Request {
+    /// The method, such as `GET`.
+    pub method: String,
+    /// The request target, such as `/index.html`.
+    pub path: String,
+    /// The minor version, `0` for HTTP/1.0 and `1` for HTTP/1.1.
+    pub version: u8,
+    /// The headers, in the order they were received.
+    pub headers: Vec<(String, String)>,
+    /// The body, with any chunked encoding removed.
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Creates an HTTP/1.1 request with no headers and an empty body.
+    pub fn new<M: Into<String>, P: Into<String>>(method: M, path: P) -> Request {
+        Request {
+            method: method.into(),
+            path: path.into(),
+            version: 1,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// The value of the first header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+
+    /// Adds a header.
+    pub fn with_header<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Request {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Sets the body.
+    pub fn with_body<B: Into<Vec<u8>>>(mut self, body: B) -> Request {
+        self.body = body.into();
+        self
+    }
+
+    /// Whether the connection may be used for another request after this
+    /// one, going by its version and `Connection` header.
+    pub fn keep_alive(&self) -> bool {
+        keep_alive(self.version, &self.headers)
+    }
+}
+
+/// An HTTP response.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response {
+    /// The status code, such as `200`.
+    pub status: u16,
+    /// The reason phrase, such as `OK`.
+    pub reason: String,
+    /// The minor version, `0` for HTTP/1.0 and `1` for HTTP/1.1.
+    pub version: u8,
+    /// The headers, in the order they were received.
+    pub headers: Vec<(String, String)>,
+    /// The body, with any chunked encoding removed.
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// Creates an HTTP/1.1 response with the usual reason phrase for
+    /// `status`, no headers, and an empty body.
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            reason: reason(status).to_owned(),
+            version: 1,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// The value of the first header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+
+    /// Adds a header.
+    pub fn with_header<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Response {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Sets the body.
+    pub fn with_body<B: Into<Vec<u8>>>(mut self, body: B) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    /// Whether the connection may be used for another request after this
+    /// one, going by its version and `Connection` header.
+    pub fn keep_alive(&self) -> bool {
+        keep_alive(self.version, &self.headers)
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        417 => "Expectation Failed",
+        426 => "Upgrade Required",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| &v[..])
+}
+
+// whether any `name` header lists `token` among its comma separated values
+fn has_token(headers: &[(String, String)], name: &str, token: &str) -> bool {
+    headers.iter()
+        .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+        .flat_map(|(_, v)| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case(token))
+}
+
+fn keep_alive(version: u8, headers: &[(String, String)]) -> bool {
+    if version == 0 {
+        has_token(headers, "Connection", "keep-alive")
+    } else {
+        !has_token(headers, "Connection", "close")
+    }
+}
+
+type ParseResult<T> = Result<T, &'static str>;
+
+/// The first line and headers of a request or response.
+struct Head {
+    start: [String; 3],
+    version: u8,
+    headers: Vec<(String, String)>,
+}
+
+fn parse_version(version: &str) -> ParseResult<u8> {
+    match version {
+        "HTTP/1.1" => Ok(1),
+        "HTTP/1.0" => Ok(0),
+        _ => Err("unsupported HTTP version"),
+    }
+}
+
+/// Parses a head from the front of `buf`, returning it along with its
+/// length, or `None` if it isn't all there yet.
+fn parse_head(buf: &[u8], request: bool) -> ParseResult<Option<(Head, usize)>> {
+    // leading empty lines are ignored, as RFC 7230 suggests
+    let mut start = 0;
+    while start < buf.len() && (buf[start] == b'\r' || buf[start] == b'\n') {
+        start += 1;
+    }
+    let end = match find_head_end(&buf[start..]) {
+        Some(end) if start + end > MAX_HEAD => return Err("head too large"),
+        Some(end) => start + end,
+        None if buf.len() > MAX_HEAD => return Err("head too large"),
+        None => return Ok(None),
+    };
+    let text = str::from_utf8(&buf[start..end]).map_err(|_| "head is not valid UTF-8")?;
+    let mut lines = text.split('\n').map(|line| line.trim_end_matches('\r'));
+    let first = lines.next().ok_or("missing start line")?;
+    let mut parts = first.splitn(3, ' ');
+    let a = parts.next().unwrap_or("");
+    let b = parts.next().ok_or("malformed start line")?;
+    // a response may leave out its reason phrase
+    let c = match parts.next() {
+        Some(c) => c,
+        None if !request => "",
+        None => return Err("malformed start line"),
+    };
+    let version = if request { parse_version(c)? } else { parse_version(a)? };
+    if a.is_empty() || b.is_empty() {
+        return Err("malformed start line");
+    }
+    let mut headers = Vec::new();
+    for line in lines {
+        if line.is_empty() {
+            break;
+        }
+        let colon = line.find(':').ok_or("malformed header")?;
+        let name = &line[..colon];
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err("malformed header name");
+        }
+        headers.push((name.to_owned(), line[colon + 1..].trim().to_owned()));
+    }
+    let head = Head {
+        start: [a.to_owned(), b.to_owned(), c.to_owned()],
+        version,
+        headers,
+    };
+    Ok(Some((head, end)))
+}
+
+// the index just past the empty line ending a head
+fn find_head_end(buf: &[u8]) -> Option<usize> {
+    let mut i = 0;
+    while let Some(n) = buf[i..].iter().position(|&b| b == b'\n') {
+        let line_end = i + n + 1;
+        match &buf[line_end..] {
+            [b'\n', ..] => return Some(line_end + 1),
+            [b'\r', b'\n', ..] => return Some(line_end + 2),
+            _ => i = line_end,
+        }
+    }
+    None
+}
+
+/// How the body following a head is delimited.
+///
+/// Follows RFC 7230 section 3.3.3. A response whose last transfer coding
+/// isn't chunked runs until the connection closes, while a request must be
+/// chunked, with no other coding, and no `Content-Length` alongside.
+fn body_length(headers: &[(String, String)], close_delimited: bool) -> ParseResult<Body> {
+    let codings: Vec<&str> = headers.iter()
+        .filter(|(n, _)| n.eq_ignore_ascii_case("Transfer-Encoding"))
+        .flat_map(|(_, v)| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .collect();
+    if let Some(last) = codings.last() {
+        let chunked = last.eq_ignore_ascii_case("chunked");
+        if close_delimited {
+            return Ok(Body::new(if chunked { BodyState::Size } else { BodyState::Close }));
+        }
+        // either could be used to smuggle a second request past a proxy
+        if header(headers, "Content-Length").is_some() {
+            return Err("both transfer encoding and content length");
+        }
+        if !chunked {
+            return Err("chunked is not the final transfer coding");
+        }
+        if codings.len() > 1 {
+            return Err(UNSUPPORTED_ENCODING);
+        }
+        return Ok(Body::new(BodyState::Size));
+    }
+    let mut length = None;
+    for (name, value) in headers {
+        if name.eq_ignore_ascii_case("Content-Length") {
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return Err("invalid content length");
+            }
+            let len = value.parse::<u64>().map_err(|_| "invalid content length")?;
+            if length.is_some() && length != Some(len) {
+                return Err("conflicting content lengths");
+            }
+            length = Some(len);
+        }
+    }
+    match length {
+        Some(len) => Ok(Body::new(BodyState::Length(len))),
+        None if close_delimited => Ok(Body::new(BodyState::Close)),
+        None => Ok(Body::new(BodyState::Length(0))),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BodyState {
+    // bytes left of a Content-Length body
+    Length(u64),
+    // waiting for a chunk size line
+    Size,
+    // bytes left of the current chunk
+    Data(u64),
+    // waiting for the CRLF after a chunk
+    DataEnd,
+    // waiting for the empty line after the trailers
+    Trailers,
+    // read until the connection closes
+    Close,
+    Done,
+}
+
+/// Incrementally decodes a body, whichever way it is delimited.
+#[derive(Debug)]
+struct Body {
+    state: BodyState,
+    // the most body bytes accepted, before failing with BODY_TOO_LARGE
+    max: u64,
+    // body bytes decoded so far
+    len: u64,
+    // bytes of trailers so far, capped like a head
+    trailers: usize,
+}
+
+impl Body {
+    fn new(state: BodyState) -> Body {
+        let state = match state {
+            BodyState::Length(0) => BodyState::Done,
+            state => state,
+        };
+        Body {
+            state,
+            max: u64::MAX,
+            len: 0,
+            trailers: 0,
+        }
+    }
+
+    fn with_max(mut self, max: u64) -> Body {
+        self.max = max;
+        self
+    }
+
+    fn is_done(&self) -> bool {
+        self.state == BodyState::Done
+    }
+
+    /// Whether the whole body is known to be within the limit, so far.
+    fn is_within_max(&self) -> bool {
+        match self.state {
+            BodyState::Length(len) | BodyState::Data(len) => self.fits(len),
+            _ => self.len <= self.max,
+        }
+    }
+
+    // whether `len` more bytes of body are within the limit
+    fn fits(&self, len: u64) -> bool {
+        self.len.checked_add(len).is_some_and(|total| total <= self.max)
+    }
+
+    /// Moves as much of the body at the front of `buf` as is there into
+    /// `out`, returning how many bytes of `buf` were used.
+    fn decode(&mut self, buf: &[u8], out: &mut Vec<u8>) -> ParseResult<usize> {
+        let mut pos = 0;
+        loop {
+            let rest = &buf[pos..];
+            match self.state {
+                BodyState::Done => return Ok(pos),
+                BodyState::Close => {
+                    if !self.fits(rest.len() as u64) {
+                        return Err(BODY_TOO_LARGE);
+                    }
+                    self.len += rest.len() as u64;
+                    out.extend_from_slice(rest);
+                    return Ok(buf.len());
+                }
+                BodyState::Length(remaining) | BodyState::Data(remaining) => {
+                    // a Content-Length is checked up front, before any of it
+                    if let BodyState::Length(_) = self.state {
+                        if !self.fits(remaining) {
+                            return Err(BODY_TOO_LARGE);
+                        }
+                    }
+                    if rest.is_empty() {
+                        return Ok(pos);
+                    }
+                    let n = ::std::cmp::min(remaining, rest.len() as u64) as usize;
+                    out.extend_from_slice(&rest[..n]);
+                    pos += n;
+                    self.len += n as u64;
+                    let left = remaining - n as u64;
+                    self.state = match self.state {
+                        BodyState::Length(_) if left == 0 => BodyState::Done,
+                        BodyState::Length(_) => BodyState::Length(left),
+                        _ if left == 0 => BodyState::DataEnd,
+                        _ => BodyState::Data(left),
+                    };
+                }
+                BodyState::Size => {
+                    let line = match line(rest)? {
+                        Some(line) => line,
+                        None => return Ok(pos),
+                    };
+                    pos += line.len();
+                    let text = str::from_utf8(line).map_err(|_| "invalid chunk size")?;
+                    let size = text.trim_end().split(';').next().unwrap_or("").trim();
+                    let size = u64::from_str_radix(size, 16).map_err(|_| "invalid chunk size")?;
+                    if !self.fits(size) {
+                        return Err(BODY_TOO_LARGE);
+                    }
+                    self.state = if size == 0 { BodyState::Trailers } else { BodyState::Data(size) };
+                }
+                BodyState::DataEnd => {
+                    match line(rest)? {
+                        Some(line) if line == b"\r\n" || line == b"\n" => {
+                            pos += line.len();
+                            self.state = BodyState::Size;
+                        }
+                        Some(_) => return Err("missing CRLF after chunk"),
+                        None => return Ok(pos),
+                    }
+                }
+                BodyState::Trailers => {
+                    match line(rest)? {
+                        Some(line) => {
+                            pos += line.len();
+                            self.trailers += line.len();
+                            if self.trailers > MAX_HEAD {
+                                return Err("trailers too large");
+                            }
+                            if line == b"\r\n" || line == b"\n" {
+                                self.state = BodyState::Done;
+                            }
+                        }
+                        None => return Ok(pos),
+                    }
+                }
+            }
+        }
+    }
+
+    /// The connection closed, which only ends a close-delimited body.
+    fn eof(&mut self) -> ParseResult<()> {
+        match self.state {
+            BodyState::Close | BodyState::Done => {
+                self.state = BodyState::Done;
+                Ok(())
+            }
+            _ => Err("connection closed before the end of the body"),
+        }
+    }
+}
+
+// a line at the front of `buf`, including its line ending
+fn line(buf: &[u8]) -> ParseResult<Option<&[u8]>> {
+    match buf.iter().position(|&b| b == b'\n') {
+        Some(n) => Ok(Some(&buf[..n + 1])),
+        None if buf.len() > MAX_LINE => Err("line too long"),
+        None => Ok(None),
+    }
+}
+
+/// Queues a head, in the order the headers were given.
+fn write_head(out: &mut WriteQueue, start: &str, headers: &[(String, String)], extra: &[(&str, String)]) {
+    let mut head = String::with_capacity(256);
+    head.push_str(start);
+    head.push_str("\r\n");
+    for (name, value) in headers {
+        let _ = write!(head, "{}: {}\r\n", name, value);
+    }
+    for (name, value) in extra {
+        let _ = write!(head, "{}: {}\r\n", name, value);
+    }
+    head.push_str("\r\n");
+    out.push(head);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{body_length, parse_head, Body, BodyState, MAX_HEAD};
+
+    // decodes `buf` a byte at a time, as if each arrived in its own read
+    fn decode_bytewise(body: &mut Body, buf: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut pending = Vec::new();
+        for &b in buf {
+            pending.push(b);
+            let n = body.decode(&pending, &mut out).unwrap();
+            pending.drain(..n);
+        }
+        assert!(pending.is_empty(), "{:?} left undecoded", pending);
+        out
+    }
+
+    #[test]
+    fn heads_wait_for_the_empty_line() {
+        let head = b"GET /a HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        for end in 0..head.len() {
+            assert!(parse_head(&head[..end], true).unwrap().is_none(), "parsed {} bytes", end);
+        }
+        let (head, len) = parse_head(head, true).unwrap().unwrap();
+        assert_eq!(len, 38);
+        assert_eq!(head.start, ["GET".to_owned(), "/a".to_owned(), "HTTP/1.1".to_owned()]);
+        assert_eq!(head.headers, vec![("Host".to_owned(), "example.com".to_owned())]);
+    }
+
+    #[test]
+    fn heads_may_use_bare_newlines_after_empty_lines() {
+        let buf = b"\r\n\nHTTP/1.0 204\nServer:  tick \n\nrest";
+        let (head, len) = parse_head(buf, false).unwrap().unwrap();
+        assert_eq!(&buf[len..], b"rest");
+        assert_eq!(head.version, 0);
+        assert_eq!(head.start[1], "204");
+        assert_eq!(head.headers, vec![("Server".to_owned(), "tick".to_owned())]);
+    }
+
+    #[test]
+    fn heads_are_capped() {
+        let mut buf = b"GET / HTTP/1.1\r\n".to_vec();
+        while buf.len() <= MAX_HEAD {
+            buf.extend_from_slice(b"X-Pad: aaaaaaaaaaaaaaaa\r\n");
+        }
+        assert_eq!(parse_head(&buf, true).err(), Some("head too large"));
+        buf.extend_from_slice(b"\r\n");
+        assert_eq!(parse_head(&buf, true).err(), Some("head too large"));
+        assert_eq!(parse_head(&vec![b'\n'; MAX_HEAD + 1], true).err(), Some("head too large"));
+    }
+
+    #[test]
+    fn malformed_heads_are_errors() {
+        assert!(parse_head(b"GET /\r\n\r\n", true).is_err());
+        assert!(parse_head(b"GET / HTTP/2.0\r\n\r\n", true).is_err());
+        assert!(parse_head(b"GET / HTTP/1.1\r\nno colon\r\n\r\n", true).is_err());
+        assert!(parse_head(b"GET / HTTP/1.1\r\nBad Name: x\r\n\r\n", true).is_err());
+    }
+
+    #[test]
+    fn chunked_bodies_skip_extensions_and_trailers() {
+        let headers = vec![("Transfer-Encoding".to_owned(), "chunked".to_owned())];
+        let buf = b"5;name=value\r\nhello\r\n6 ; x\r\n world\r\n0\r\nExpires: never\r\nX-Sum: 1\r\n\r\nnext";
+        let mut body = body_length(&headers, false).unwrap();
+        let mut out = Vec::new();
+        let n = body.decode(buf, &mut out).unwrap();
+        assert!(body.is_done());
+        assert_eq!(out, b"hello world");
+        assert_eq!(&buf[n..], b"next");
+
+        let mut body = body_length(&headers, false).unwrap();
+        assert_eq!(decode_bytewise(&mut body, &buf[..buf.len() - 4]), b"hello world");
+        assert!(body.is_done());
+    }
+
+    #[test]
+    fn bad_chunk_sizes_are_errors() {
+        let mut body = Body::new(BodyState::Size);
+        assert!(body.decode(b"zz\r\n", &mut Vec::new()).is_err());
+        let mut body = Body::new(BodyState::Size);
+        assert!(body.decode(b"1\r\naX\r\n", &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn content_lengths_end_the_body() {
+        let headers = vec![("Content-Length".to_owned(), "5".to_owned())];
+        let mut body = body_length(&headers, true).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(body.decode(b"hel", &mut out).unwrap(), 3);
+        assert!(!body.is_done());
+        assert_eq!(body.decode(b"loGET", &mut out).unwrap(), 2);
+        assert!(body.is_done());
+        assert_eq!(out, b"hello");
+        assert!(body_length(&[], false).unwrap().is_done());
+    }
+
+    #[test]
+    fn closed_connections_only_end_close_delimited_bodies() {
+        let mut body = body_length(&[], true).unwrap();
+        assert_eq!(decode_bytewise(&mut body, b"until the end"), b"until the end");
+        assert!(!body.is_done());
+        body.eof().unwrap();
+        assert!(body.is_done());
+
+        let headers = vec![("Content-Length".to_owned(), "5".to_owned())];
+        let mut body = body_length(&headers, true).unwrap();
+        body.decode(b"hel", &mut Vec::new()).unwrap();
+        assert!(body.eof().is_err());
+    }
+
+    #[test]
+    fn conflicting_lengths_are_errors() {
+        let headers = vec![
+            ("Content-Length".to_owned(), "5".to_owned()),
+            ("content-length".to_owned(), "6".to_owned()),
+        ];
+        assert!(body_length(&headers, false).is_err());
+        let headers = vec![("Content-Length".to_owned(), "+5".to_owned())];
+        assert!(body_length(&headers, false).is_err());
+        let headers = vec![("Content-Length".to_owned(), "".to_owned())];
+        assert!(body_length(&headers, false).is_err());
+    }
+
+    fn encoded(values: &[&str]) -> Vec<(String, String)> {
+        values.iter().map(|&v| ("Transfer-Encoding".to_owned(), v.to_owned())).collect()
+    }
+
+    #[test]
+    fn requests_must_end_with_chunked_alone() {
+        let err = |values: &[&str]| body_length(&encoded(values), false).err();
+        assert_eq!(err(&["gzip"]), Some("chunked is not the final transfer coding"));
+        assert_eq!(err(&["chunked, gzip"]), Some("chunked is not the final transfer coding"));
+        assert_eq!(err(&["chunked", "gzip"]), Some("chunked is not the final transfer coding"));
+        assert_eq!(err(&["gzip, chunked"]), Some(super::UNSUPPORTED_ENCODING));
+        assert_eq!(err(&["gzip", "chunked"]), Some(super::UNSUPPORTED_ENCODING));
+        assert_eq!(body_length(&encoded(&["Chunked"]), false).unwrap().state, BodyState::Size);
+    }
+
+    #[test]
+    fn requests_cant_have_both_lengths() {
+        let mut headers = encoded(&["chunked"]);
+        headers.push(("Content-Length".to_owned(), "5".to_owned()));
+        assert_eq!(body_length(&headers, false).err(), Some("both transfer encoding and content length"));
+        // a response's transfer coding wins
+        assert_eq!(body_length(&headers, true).unwrap().state, BodyState::Size);
+    }
+
+    #[test]
+    fn responses_not_ending_with_chunked_run_until_close() {
+        let state = |values: &[&str]| body_length(&encoded(values), true).unwrap().state;
+        assert_eq!(state(&["gzip"]), BodyState::Close);
+        assert_eq!(state(&["chunked, gzip"]), BodyState::Close);
+        assert_eq!(state(&["gzip, chunked"]), BodyState::Size);
+    }
+
+    #[test]
+    fn bodies_are_limited() {
+        let mut body = Body::new(BodyState::Close).with_max(4);
+        let mut out = Vec::new();
+        body.decode(b"abc", &mut out).unwrap();
+        assert_eq!(body.decode(b"de", &mut out).err(), Some(super::BODY_TOO_LARGE));
+
+        let mut body = Body::new(BodyState::Length(5)).with_max(4);
+        assert!(!body.is_within_max());
+        assert_eq!(body.decode(b"", &mut out).err(), Some(super::BODY_TOO_LARGE));
+
+        let mut body = Body::new(BodyState::Size).with_max(4);
+        body.decode(b"4\r\nabcd\r\n", &mut out).unwrap();
+        assert!(body.is_within_max());
+        assert_eq!(body.decode(b"1\r\n", &mut out).err(), Some(super::BODY_TOO_LARGE));
+    }
+}
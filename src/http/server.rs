@@ -0,0 +1,396 @@
+use std::io::{self, Read};
+
+use ::{Interest, Io, Protocol, Transport, WriteQueue};
+use super::{Body, Request, Response};
+
+// how much is read from the transport at a time
+const READ_SIZE: usize = 8 * 1024;
+// once this many response bytes are waiting to be written, no more requests
+// are read or handled until the client catches up
+const MAX_QUEUED: usize = 256 * 1024;
+// the default limit on a request body
+const MAX_BODY: u64 = 8 * 1024 * 1024;
+
+/// Produces a response for each request a `Server` receives.
+pub trait Handler {
+    /// Called with each complete request, in the order they arrived.
+    fn handle(&mut self, request: Request) -> Response;
+}
+
+impl<F: FnMut(Request) -> Response> Handler for F {
+    fn handle(&mut self, request: Request) -> Response {
+        self(request)
+    }
+}
+
+/// An HTTP/1.1 server `Protocol`.
+///
+/// Requests are parsed as they arrive, with bodies sent either with a
+/// `Content-Length` or chunked, and handed whole to the `Handler`. Its
+/// responses are written back in order, with a `Content-Length` added if
+/// it didn't set one, so clients may pipeline requests and keep the
+/// connection alive between them.
+///
+/// A request whose head is over 64KB, or whose body length is ambiguous,
+/// is answered with `400 Bad Request`, one with a transfer coding other
+/// than chunked with `501 Not Implemented`, and one whose body is over the
+/// limit set with `with_max_body` with `413 Payload Too Large`, closing
+/// the connection each time.
+///
+/// ```no_run
+/// # extern crate mio;
+/// # extern crate tick;
+/// # fn main() {
+/// use tick::http::{Response, Server};
+///
+/// let mut tick = tick::Tick::new(|_| {
+///     let server = Server::new(|req: tick::http::Request| {
+///         Response::new(200).with_body(format!("you asked for {}", req.path))
+///     });
+///     (server, tick::Interest::Read)
+/// });
+/// let sock = mio::tcp::TcpListener::bind(&"127.0.0.1:3330".parse().unwrap()).unwrap();
+/// tick.accept(sock).unwrap();
+/// tick.run().unwrap();
+/// # }
+/// ```
+pub struct Server<H> {
+    handler: H,
+    buf: Vec<u8>,
+    // a request whose head has been parsed, while its body arrives
+    current: Option<(Request, Body)>,
+    max_body: u64,
+    out: WriteQueue,
+    // no more requests will be handled, once `out` is written
+    closing: bool,
+    eof: bool,
+}
+
+impl<H: Handler> Server<H> {
+    /// Creates a server for a single connection, which calls `handler` for
+    /// each request.
+    pub fn new(handler: H) -> Server<H> {
+        Server {
+            handler,
+            buf: Vec::new(),
+            current: None,
+            max_body: MAX_BODY,
+            out: WriteQueue::new(),
+            closing: false,
+            eof: false,
+        }
+    }
+
+    /// Sets the most bytes a request body may have, once any chunked
+    /// encoding is removed. Defaults to 8MB.
+    pub fn with_max_body(mut self, max: u64) -> Server<H> {
+        self.max_body = max;
+        self
+    }
+
+    fn can_read(&self) -> bool {
+        !self.closing && !self.eof && self.out.len() < MAX_QUEUED
+    }
+
+    /// Handles every complete request in the buffer.
+    fn process(&mut self) {
+        while !self.closing && self.out.len() < MAX_QUEUED {
+            if self.current.is_none() {
+                match parse_request(&self.buf, self.max_body) {
+                    Ok(Some((request, body, len))) => {
+                        self.buf.drain(..len);
+                        // a body that's too large is refused before it's sent
+                        if request.header("Expect").is_some_and(|v| v.eq_ignore_ascii_case("100-continue"))
+                            && !body.is_done() && body.is_within_max() && self.buf.is_empty() {
+                            self.out.push(&b"HTTP/1.1 100 Continue\r\n\r\n"[..]);
+                        }
+                        self.current = Some((request, body));
+                    }
+                    Ok(None) => return,
+                    Err(msg) => return self.reject(msg),
+                }
+            }
+
+            let done = {
+                let (request, body) = self.current.as_mut().unwrap();
+                match body.decode(&self.buf, &mut request.body) {
+                    Ok(n) => {
+                        self.buf.drain(..n);
+                        body.is_done()
+                    }
+                    Err(msg) => return self.reject(msg),
+                }
+            };
+            if !done {
+                return;
+            }
+            let (request, _) = self.current.take().unwrap();
+            self.respond(request);
+        }
+    }
+
+    fn respond(&mut self, request: Request) {
+        let head_only = request.method == "HEAD";
+        let keep_alive = request.keep_alive();
+        trace!("http request {} {}", request.method, request.path);
+        let response = self.handler.handle(request);
+        let close = !keep_alive || !response.keep_alive();
+        write_response(&mut self.out, response, head_only, close);
+        if close {
+            self.closing = true;
+        }
+    }
+
+    // answers a request that couldn't be parsed, or was too large, and
+    // closes the connection
+    fn reject(&mut self, msg: &'static str) {
+        debug!("bad http request: {}", msg);
+        let status = match msg {
+            super::BODY_TOO_LARGE => 413,
+            super::UNSUPPORTED_ENCODING => 501,
+            _ => 400,
+        };
+        self.current = None;
+        self.buf.clear();
+        write_response(&mut self.out, Response::new(status).with_body(msg), false, true);
+        self.closing = true;
+    }
+
+    fn flush<T: Transport>(&mut self, transport: &mut Io<T>) -> io::Result<()> {
+        match self.out.write_to(transport) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+
+    fn interest(&self) -> Interest {
+        let read = if self.can_read() { Interest::Read } else { Interest::Wait };
+        if !self.out.is_empty() {
+            read + Interest::Write
+        } else if self.closing || self.eof {
+            Interest::Remove
+        } else {
+            read
+        }
+    }
+}
+
+impl<T: Transport, H: Handler> Protocol<T> for Server<H> {
+    fn on_readable(&mut self, transport: &mut Io<T>) -> Interest {
+        let mut chunk = [0; READ_SIZE];
+        while self.can_read() {
+            match transport.read(&mut chunk) {
+                Ok(0) => {
+                    self.eof = true;
+                    if self.current.is_some() || !self.buf.iter().all(|&b| b == b'\r' || b == b'\n') {
+                        debug!("http connection closed mid-request");
+                    }
+                }
+                Ok(n) => {
+                    self.buf.extend_from_slice(&chunk[..n]);
+                    self.process();
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    debug!("http read error: {}", e);
+                    return Interest::Remove;
+                }
+            }
+        }
+        if let Err(e) = self.flush(transport) {
+            debug!("http write error: {}", e);
+            return Interest::Remove;
+        }
+        self.interest()
+    }
+
+    fn on_writable(&mut self, transport: &mut Io<T>) -> Interest {
+        if let Err(e) = self.flush(transport) {
+            debug!("http write error: {}", e);
+            return Interest::Remove;
+        }
+        // pipelined requests may have been held back until the queue drained
+        if self.out.is_empty() {
+            self.process();
+            if let Err(e) = self.flush(transport) {
+                debug!("http write error: {}", e);
+                return Interest::Remove;
+            }
+        }
+        self.interest()
+    }
+
+    fn on_error(&mut self, error: ::Error) {
+        debug!("http connection error: {}", error);
+    }
+}
+
+fn parse_request(buf: &[u8], max_body: u64) -> Result<Option<(Request, Body, usize)>, &'static str> {
+    let (head, len) = match super::parse_head(buf, true)? {
+        Some(head) => head,
+        None => return Ok(None),
+    };
+    let body = super::body_length(&head.headers, false)?.with_max(max_body);
+    let [method, path, _] = head.start;
+    let request = Request {
+        method,
+        path,
+        version: head.version,
+        headers: head.headers,
+        body: Vec::new(),
+    };
+    Ok(Some((request, body, len)))
+}
+
+fn write_response(out: &mut WriteQueue, response: Response, head_only: bool, close: bool) {
+    let mut extra = Vec::new();
+    let bodiless = response.status < 200 || response.status == 204 || response.status == 304;
+    if !bodiless && response.header("Content-Length").is_none()
+        && response.header("Transfer-Encoding").is_none() {
+        extra.push(("Content-Length", response.body.len().to_string()));
+    }
+    if close && response.header("Connection").is_none() {
+        extra.push(("Connection", "close".to_owned()));
+    }
+    let start = format!("HTTP/1.1 {} {}", response.status, response.reason);
+    super::write_head(out, &start, &response.headers, &extra);
+    if !head_only && !bodiless {
+        out.push(response.body);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::{Interest, MockDriver, MockTransport};
+    use ::http::{Request, Response};
+    use super::Server;
+
+    type Echo = fn(Request) -> Response;
+
+    fn echo(request: Request) -> Response {
+        Response::new(200).with_body(request.body)
+    }
+
+    fn server(max: u64) -> MockDriver<Server<Echo>> {
+        MockDriver::new(move |_| (Server::new(echo as Echo).with_max_body(max), Interest::Read))
+    }
+
+    fn response(transport: &mut MockTransport) -> String {
+        String::from_utf8(transport.take_written()).unwrap()
+    }
+
+    #[test]
+    fn bodies_within_the_limit_are_handled() {
+        let mut driver = server(5);
+        driver.transport().feed(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello");
+        driver.assert_readable(Interest::Read);
+        let written = response(driver.transport());
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\n"), "{}", written);
+        assert!(written.ends_with("\r\n\r\nhello"), "{}", written);
+    }
+
+    #[test]
+    fn split_requests_wait_for_the_rest() {
+        let mut driver = server(1024);
+        driver.transport().feed(b"POST / HTTP/1.1\r\nContent-");
+        driver.assert_readable(Interest::Read);
+        driver.transport().feed(b"Length: 2\r\n\r\na");
+        driver.assert_readable(Interest::Read);
+        assert!(driver.transport().written().is_empty());
+        driver.transport().feed(b"b");
+        driver.assert_readable(Interest::Read);
+        assert!(response(driver.transport()).ends_with("\r\n\r\nab"));
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        let mut driver = server(1024);
+        driver.transport().feed(b"POST / HTTP/1.1\r\nContent-Length: 1\r\n\r\naHEAD / HTTP/1.1\r\n\r\n");
+        driver.transport().feed(b"POST / HTTP/1.0\r\nContent-Length: 1\r\n\r\nc");
+        driver.readable();
+        let written = response(driver.transport());
+        let responses: Vec<_> = written.split("HTTP/1.1 200 OK\r\n").skip(1).collect();
+        assert_eq!(responses.len(), 3, "{}", written);
+        assert!(responses[0].ends_with("\r\n\r\na"), "{}", written);
+        assert!(responses[1].ends_with("Content-Length: 0\r\n\r\n"), "{}", written);
+        assert!(responses[2].contains("Connection: close\r\n"), "{}", written);
+        assert!(responses[2].ends_with("\r\n\r\nc"), "{}", written);
+        assert_eq!(driver.interest(), Interest::Remove);
+    }
+
+    #[test]
+    fn malformed_requests_are_refused() {
+        let mut driver = server(1024);
+        driver.transport().feed(b"GET /\r\n\r\n");
+        driver.readable();
+        assert!(response(driver.transport()).starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert_eq!(driver.interest(), Interest::Remove);
+    }
+
+    #[test]
+    fn ambiguous_bodies_are_refused() {
+        let heads: [&[u8]; 4] = [
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: +3\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\n",
+        ];
+        for head in &heads {
+            let mut driver = server(1024);
+            driver.transport().feed(head);
+            driver.readable();
+            let written = response(driver.transport());
+            assert!(written.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", written);
+            assert_eq!(driver.interest(), Interest::Remove);
+        }
+    }
+
+    #[test]
+    fn other_transfer_codings_are_not_implemented() {
+        let mut driver = server(1024);
+        driver.transport().feed(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n");
+        driver.readable();
+        let written = response(driver.transport());
+        assert!(written.starts_with("HTTP/1.1 501 Not Implemented\r\n"), "{}", written);
+        assert_eq!(driver.interest(), Interest::Remove);
+    }
+
+    #[test]
+    fn long_content_lengths_are_refused_before_the_body() {
+        let mut driver = server(4);
+        driver.transport().feed(b"POST / HTTP/1.1\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n");
+        driver.readable();
+        let written = response(driver.transport());
+        assert!(written.starts_with("HTTP/1.1 413 Payload Too Large\r\n"), "{}", written);
+        assert!(written.contains("Connection: close\r\n"), "{}", written);
+        assert_eq!(driver.interest(), Interest::Remove);
+    }
+
+    #[test]
+    fn long_chunked_bodies_are_refused() {
+        let mut driver = server(4);
+        driver.transport().feed(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n");
+        driver.assert_readable(Interest::Read);
+        assert!(driver.transport().written().is_empty());
+        driver.transport().feed(b"2\r\nde\r\n0\r\n\r\n");
+        driver.readable();
+        let written = response(driver.transport());
+        assert!(written.starts_with("HTTP/1.1 413 Payload Too Large\r\n"), "{}", written);
+        assert_eq!(driver.interest(), Interest::Remove);
+    }
+
+    #[test]
+    fn long_trailers_are_refused() {
+        let mut driver = server(1024);
+        driver.transport().feed(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n");
+        driver.assert_readable(Interest::Read);
+        let trailer = format!("X-Pad: {}\r\n", "a".repeat(1000));
+        for _ in 0..70 {
+            driver.transport().feed(trailer.as_bytes());
+        }
+        driver.readable();
+        let written = response(driver.transport());
+        assert!(written.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", written);
+        assert!(written.ends_with("trailers too large"), "{}", written);
+    }
+}
@@ -0,0 +1,766 @@
+//! WebSocket connections, upgraded from HTTP.
+//!
+//! `WebSocket` answers the HTTP upgrade handshake, then speaks the framing
+//! of RFC 6455, handing whole messages to a `Handler`.
+
+use std::io::{self, Read};
+use std::str;
+
+use ::{Interest, Io, Protocol, Transport, WriteQueue};
+use super::{has_token, Request, Response};
+
+// how much is read from the transport at a time
+const READ_SIZE: usize = 8 * 1024;
+// once this many bytes are waiting to be written, no more frames are read
+// until the client catches up
+const MAX_QUEUED: usize = 256 * 1024;
+// the largest message accepted, after putting fragments back together
+const MAX_MESSAGE: usize = 16 * 1024 * 1024;
+
+// appended to a client's key to prove the server understood the handshake
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+/// Close codes, from section 7.4.1 of RFC 6455.
+pub mod code {
+    /// The connection is done with.
+    pub const NORMAL: u16 = 1000;
+    /// The endpoint is going away, such as a server shutting down.
+    pub const GOING_AWAY: u16 = 1001;
+    /// The peer broke the protocol.
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    /// The peer sent a kind of message that can't be handled.
+    pub const UNSUPPORTED: u16 = 1003;
+    /// The close frame had no status code. Never sent.
+    pub const NO_STATUS: u16 = 1005;
+    /// The connection closed without a close frame. Never sent.
+    pub const ABNORMAL: u16 = 1006;
+    /// A text message wasn't valid UTF-8.
+    pub const INVALID_DATA: u16 = 1007;
+    /// A message broke the endpoint's policy.
+    pub const POLICY: u16 = 1008;
+    /// A message was too big to handle.
+    pub const TOO_BIG: u16 = 1009;
+    /// The endpoint hit an unexpected condition.
+    pub const INTERNAL_ERROR: u16 = 1011;
+}
+
+/// A complete WebSocket message.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    /// A UTF-8 text message.
+    Text(String),
+    /// A binary message.
+    Binary(Vec<u8>),
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Message {
+        Message::Text(text)
+    }
+}
+
+impl<'a> From<&'a str> for Message {
+    fn from(text: &'a str) -> Message {
+        Message::Text(text.to_owned())
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(data: Vec<u8>) -> Message {
+        Message::Binary(data)
+    }
+}
+
+/// Handles the messages on a `WebSocket`.
+pub trait Handler {
+    /// Called once the handshake has completed, with the upgrade request.
+    ///
+    /// Defaults to doing nothing.
+    fn on_open(&mut self, request: &Request, sender: &mut Sender) {
+        let _ = (request, sender);
+    }
+
+    /// Called with each message the client sends.
+    fn on_message(&mut self, message: Message, sender: &mut Sender);
+
+    /// Called once the connection is closing, with the close code and
+    /// reason. If the client closed without a code, `code::NO_STATUS` is
+    /// given, and if the connection was lost, `code::ABNORMAL`.
+    ///
+    /// Defaults to doing nothing.
+    fn on_close(&mut self, code: u16, reason: &str) {
+        let _ = (code, reason);
+    }
+}
+
+/// Queues frames to be sent to the client.
+#[derive(Debug, Default)]
+pub struct Sender {
+    out: WriteQueue,
+    closed: bool,
+}
+
+impl Sender {
+    /// Sends a message as a single frame.
+    ///
+    /// Messages sent after closing are dropped.
+    pub fn send<M: Into<Message>>(&mut self, message: M) {
+        match message.into() {
+            Message::Text(text) => self.frame(TEXT, text.into_bytes()),
+            Message::Binary(data) => self.frame(BINARY, data),
+        }
+    }
+
+    /// Sends a ping, which the client answers with a pong.
+    ///
+    /// # Panics
+    ///
+    /// If `data` is longer than 125 bytes.
+    pub fn ping<B: Into<Vec<u8>>>(&mut self, data: B) {
+        let data = data.into();
+        assert!(data.len() <= 125, "ping data longer than 125 bytes");
+        self.frame(PING, data);
+    }
+
+    /// Starts the close handshake. The connection closes once the client
+    /// answers.
+    ///
+    /// # Panics
+    ///
+    /// If `reason` is longer than 123 bytes.
+    pub fn close(&mut self, code: u16, reason: &str) {
+        assert!(reason.len() <= 123, "close reason longer than 123 bytes");
+        let mut payload = Vec::with_capacity(2 + reason.len());
+        payload.push((code >> 8) as u8);
+        payload.push(code as u8);
+        payload.extend_from_slice(reason.as_bytes());
+        self.frame(CLOSE, payload);
+        self.closed = true;
+    }
+
+    /// Whether a close frame has been sent.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    fn frame(&mut self, opcode: u8, payload: Vec<u8>) {
+        if self.closed {
+            trace!("websocket dropping frame sent after close");
+            return;
+        }
+        // frames from a server are never masked
+        let mut head = Vec::with_capacity(10);
+        head.push(0x80 | opcode);
+        let len = payload.len();
+        if len < 126 {
+            head.push(len as u8);
+        } else if len <= 0xFFFF {
+            head.push(126);
+            head.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            head.push(127);
+            head.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+        self.out.push(head);
+        self.out.push(payload);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    // waiting for the upgrade request
+    Handshake,
+    Open,
+    // a close frame was sent, and the client's answer is awaited
+    Closing,
+    // nothing more will be read, and the stream is removed once written
+    Closed,
+}
+
+/// A WebSocket server `Protocol`.
+///
+/// Starts by reading an HTTP upgrade request, answering anything else with
+/// `426 Upgrade Required`. Once upgraded, fragmented messages are put back
+/// together, pings are answered, and each message is handed to the
+/// `Handler`.
+///
+/// ```no_run
+/// # extern crate mio;
+/// # extern crate tick;
+/// # fn main() {
+/// use tick::http::ws::{Handler, Message, Sender, WebSocket};
+///
+/// struct Echo;
+///
+/// impl Handler for Echo {
+///     fn on_message(&mut self, message: Message, sender: &mut Sender) {
+///         sender.send(message);
+///     }
+/// }
+///
+/// let mut tick = tick::Tick::new(|_| (WebSocket::new(Echo), tick::Interest::Read));
+/// let sock = mio::tcp::TcpListener::bind(&"127.0.0.1:3330".parse().unwrap()).unwrap();
+/// tick.accept(sock).unwrap();
+/// tick.run().unwrap();
+/// # }
+/// ```
+pub struct WebSocket<H> {
+    handler: H,
+    state: State,
+    buf: Vec<u8>,
+    sender: Sender,
+    // the opcode and data of a message still missing fragments
+    partial: Option<(u8, Vec<u8>)>,
+}
+
+impl<H: Handler> WebSocket<H> {
+    /// Creates a WebSocket for a single connection, which calls `handler`
+    /// once upgraded.
+    pub fn new(handler: H) -> WebSocket<H> {
+        WebSocket {
+            handler,
+            state: State::Handshake,
+            buf: Vec::new(),
+            sender: Sender::default(),
+            partial: None,
+        }
+    }
+
+    // whether the handler has been opened, and not yet closed
+    fn is_open(&self) -> bool {
+        self.state == State::Open || self.state == State::Closing
+    }
+
+    fn can_read(&self) -> bool {
+        self.state != State::Closed && self.sender.out.len() < MAX_QUEUED
+    }
+
+    fn process(&mut self) {
+        if self.state == State::Handshake {
+            self.handshake();
+        }
+        while self.can_read() && self.state != State::Handshake {
+            let frame = match parse_frame(&self.buf) {
+                Ok(Some((frame, len))) => {
+                    self.buf.drain(..len);
+                    frame
+                }
+                Ok(None) => return,
+                Err((code, reason)) => return self.fail(code, reason),
+            };
+            if let Err((code, reason)) = self.frame(frame) {
+                return self.fail(code, reason);
+            }
+        }
+    }
+
+    fn handshake(&mut self) {
+        let (head, len) = match super::parse_head(&self.buf, true) {
+            Ok(Some(head)) => head,
+            Ok(None) => return,
+            Err(msg) => return self.reject(Response::new(400).with_body(msg)),
+        };
+        self.buf.drain(..len);
+        let [method, path, _] = head.start;
+        let request = Request {
+            method,
+            path,
+            version: head.version,
+            headers: head.headers,
+            body: Vec::new(),
+        };
+        let key = match accept_key(&request) {
+            Ok(key) => key,
+            Err(response) => return self.reject(response),
+        };
+        debug!("websocket upgraded {}", request.path);
+        let response = Response::new(101)
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade")
+            .with_header("Sec-WebSocket-Accept", key);
+        let start = format!("HTTP/1.1 {} {}", response.status, response.reason);
+        super::write_head(&mut self.sender.out, &start, &response.headers, &[]);
+        self.state = State::Open;
+        self.handler.on_open(&request, &mut self.sender);
+        self.closed_by_handler();
+    }
+
+    fn reject(&mut self, response: Response) {
+        debug!("websocket handshake rejected, {}", response.status);
+        let extra = [
+            ("Content-Length", response.body.len().to_string()),
+            ("Connection", "close".to_owned()),
+        ];
+        let start = format!("HTTP/1.1 {} {}", response.status, response.reason);
+        super::write_head(&mut self.sender.out, &start, &response.headers, &extra);
+        self.sender.out.push(response.body);
+        self.state = State::Closed;
+    }
+
+    fn frame(&mut self, frame: Frame) -> Result<(), (u16, &'static str)> {
+        match frame.opcode {
+            PING => {
+                if self.state == State::Open {
+                    self.sender.frame(PONG, frame.payload);
+                }
+                Ok(())
+            }
+            PONG => Ok(()),
+            CLOSE => self.close_frame(&frame.payload),
+            // data arriving after our close frame is ignored
+            _ if self.state == State::Closing => Ok(()),
+            opcode => {
+                let (opcode, data) = match (self.partial.take(), opcode) {
+                    (None, CONTINUATION) => return Err((code::PROTOCOL_ERROR, "unexpected continuation frame")),
+                    (None, opcode) => (opcode, frame.payload),
+                    (Some((opcode, mut data)), CONTINUATION) => {
+                        if data.len() + frame.payload.len() > MAX_MESSAGE {
+                            return Err((code::TOO_BIG, "message too big"));
+                        }
+                        data.extend_from_slice(&frame.payload);
+                        (opcode, data)
+                    }
+                    (Some(_), _) => return Err((code::PROTOCOL_ERROR, "expected a continuation frame")),
+                };
+                if !frame.fin {
+                    self.partial = Some((opcode, data));
+                    return Ok(());
+                }
+                let message = if opcode == TEXT {
+                    let text = String::from_utf8(data).map_err(|_| (code::INVALID_DATA, "text is not valid UTF-8"))?;
+                    Message::Text(text)
+                } else {
+                    Message::Binary(data)
+                };
+                self.handler.on_message(message, &mut self.sender);
+                self.closed_by_handler();
+                Ok(())
+            }
+        }
+    }
+
+    fn close_frame(&mut self, payload: &[u8]) -> Result<(), (u16, &'static str)> {
+        let (code, reason) = match payload.len() {
+            0 => (code::NO_STATUS, ""),
+            1 => return Err((code::PROTOCOL_ERROR, "invalid close frame")),
+            _ => {
+                let code = u16::from(payload[0]) << 8 | u16::from(payload[1]);
+                let reason = str::from_utf8(&payload[2..]).map_err(|_| (code::INVALID_DATA, "close reason is not valid UTF-8"))?;
+                if !valid_close_code(code) {
+                    return Err((code::PROTOCOL_ERROR, "invalid close code"));
+                }
+                (code, reason)
+            }
+        };
+        debug!("websocket closed by client, {} {:?}", code, reason);
+        if self.state == State::Open {
+            // echo the client's close, as the handshake asks
+            let echo = if payload.is_empty() { Vec::new() } else { payload[..2].to_vec() };
+            self.sender.frame(CLOSE, echo);
+            self.sender.closed = true;
+        }
+        self.handler.on_close(code, reason);
+        self.state = State::Closed;
+        Ok(())
+    }
+
+    // the handler may have started the close handshake
+    fn closed_by_handler(&mut self) {
+        if self.sender.closed && self.state == State::Open {
+            debug!("websocket closing");
+            self.state = State::Closing;
+        }
+    }
+
+    // closes the connection after a protocol error
+    fn fail(&mut self, code: u16, reason: &'static str) {
+        debug!("websocket failed: {}", reason);
+        if self.is_open() {
+            self.handler.on_close(code, reason);
+        }
+        self.sender.close(code, reason);
+        self.state = State::Closed;
+        self.buf.clear();
+    }
+
+    // the connection ended without finishing the close handshake
+    fn lost(&mut self) {
+        if self.is_open() {
+            self.handler.on_close(code::ABNORMAL, "connection lost");
+        }
+        self.state = State::Closed;
+    }
+
+    fn flush<T: Transport>(&mut self, transport: &mut Io<T>) -> io::Result<()> {
+        match self.sender.out.write_to(transport) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+
+    fn interest(&self) -> Interest {
+        let read = if self.can_read() { Interest::Read } else { Interest::Wait };
+        if !self.sender.out.is_empty() {
+            read + Interest::Write
+        } else if self.state == State::Closed {
+            Interest::Remove
+        } else {
+            read
+        }
+    }
+}
+
+impl<T: Transport, H: Handler> Protocol<T> for WebSocket<H> {
+    fn on_readable(&mut self, transport: &mut Io<T>) -> Interest {
+        let mut chunk = [0; READ_SIZE];
+        while self.can_read() {
+            match transport.read(&mut chunk) {
+                Ok(0) => {
+                    self.lost();
+                    return Interest::Remove;
+                }
+                Ok(n) => {
+                    self.buf.extend_from_slice(&chunk[..n]);
+                    self.process();
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    debug!("websocket read error: {}", e);
+                    self.lost();
+                    return Interest::Remove;
+                }
+            }
+        }
+        if let Err(e) = self.flush(transport) {
+            debug!("websocket write error: {}", e);
+            self.lost();
+            return Interest::Remove;
+        }
+        self.interest()
+    }
+
+    fn on_writable(&mut self, transport: &mut Io<T>) -> Interest {
+        if let Err(e) = self.flush(transport) {
+            debug!("websocket write error: {}", e);
+            self.lost();
+            return Interest::Remove;
+        }
+        // frames may have been held back until the queue drained
+        if self.sender.out.is_empty() {
+            self.process();
+            if let Err(e) = self.flush(transport) {
+                debug!("websocket write error: {}", e);
+                self.lost();
+                return Interest::Remove;
+            }
+        }
+        self.interest()
+    }
+
+    fn on_error(&mut self, error: ::Error) {
+        debug!("websocket error: {}", error);
+        self.lost();
+    }
+}
+
+/// Checks an upgrade request, returning the `Sec-WebSocket-Accept` value
+/// for it, or the response rejecting it.
+fn accept_key(request: &Request) -> Result<String, Response> {
+    if request.method != "GET" || request.version != 1 {
+        return Err(Response::new(400).with_body("websocket upgrades must be HTTP/1.1 GET requests"));
+    }
+    if !has_token(&request.headers, "Upgrade", "websocket") || !has_token(&request.headers, "Connection", "upgrade") {
+        return Err(Response::new(426)
+            .with_header("Upgrade", "websocket")
+            .with_body("this endpoint only speaks websocket"));
+    }
+    if request.header("Sec-WebSocket-Version") != Some("13") {
+        return Err(Response::new(426)
+            .with_header("Sec-WebSocket-Version", "13")
+            .with_body("unsupported websocket version"));
+    }
+    let key = match request.header("Sec-WebSocket-Key") {
+        Some(key) if !key.is_empty() => key,
+        _ => return Err(Response::new(400).with_body("missing Sec-WebSocket-Key")),
+    };
+    let mut hashed = key.as_bytes().to_vec();
+    hashed.extend_from_slice(GUID.as_bytes());
+    Ok(base64(&sha1(&hashed)))
+}
+
+fn valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999)
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Parses a frame from the front of `buf`, returning it along with its
+/// length, or `None` if it isn't all there yet.
+fn parse_frame(buf: &[u8]) -> Result<Option<(Frame, usize)>, (u16, &'static str)> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0F;
+    if buf[0] & 0x70 != 0 {
+        return Err((code::PROTOCOL_ERROR, "reserved bits set"));
+    }
+    match opcode {
+        CONTINUATION | TEXT | BINARY => (),
+        CLOSE | PING | PONG if !fin => return Err((code::PROTOCOL_ERROR, "fragmented control frame")),
+        CLOSE | PING | PONG => (),
+        _ => return Err((code::PROTOCOL_ERROR, "unknown opcode")),
+    }
+    // frames from a client are always masked
+    if buf[1] & 0x80 == 0 {
+        return Err((code::PROTOCOL_ERROR, "unmasked frame"));
+    }
+    let (len, mut pos) = match buf[1] & 0x7F {
+        126 if buf.len() < 4 => return Ok(None),
+        126 => (u64::from(buf[2]) << 8 | u64::from(buf[3]), 4),
+        127 if buf.len() < 10 => return Ok(None),
+        127 => {
+            let mut len = [0; 8];
+            len.copy_from_slice(&buf[2..10]);
+            (u64::from_be_bytes(len), 10)
+        }
+        len => (u64::from(len), 2),
+    };
+    if opcode >= CLOSE && len > 125 {
+        return Err((code::PROTOCOL_ERROR, "control frame too long"));
+    }
+    if len > MAX_MESSAGE as u64 {
+        return Err((code::TOO_BIG, "message too big"));
+    }
+    let len = len as usize;
+    if buf.len() < pos + 4 + len {
+        return Ok(None);
+    }
+    let mut mask = [0; 4];
+    mask.copy_from_slice(&buf[pos..pos + 4]);
+    pos += 4;
+    let mut payload = buf[pos..pos + len].to_vec();
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+    Ok(Some((Frame { fin, opcode, payload }, pos + len)))
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([block[i * 4], block[i * 4 + 1], block[i * 4 + 2], block[i * 4 + 3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a.rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, v) in h.iter_mut().zip(&[a, b, c, d, e]) {
+            *h = h.wrapping_add(*v);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (i, v) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&v.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(CHARS[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use ::{Interest, MockDriver};
+    use ::http::Request;
+    use super::{accept_key, base64, code, parse_frame, sha1, Handler, Message, Sender, WebSocket, MAX_MESSAGE};
+
+    const MASK: [u8; 4] = [0x37, 0xFA, 0x21, 0x3D];
+
+    // a frame as a client would send it
+    fn masked(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![first];
+        if payload.len() < 126 {
+            frame.push(0x80 | payload.len() as u8);
+        } else {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        frame.extend_from_slice(&MASK);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ MASK[i % 4]));
+        frame
+    }
+
+    #[derive(Clone, Default)]
+    struct Record {
+        messages: Rc<RefCell<Vec<Message>>>,
+        closed: Rc<RefCell<Option<u16>>>,
+    }
+
+    impl Handler for Record {
+        fn on_message(&mut self, message: Message, _: &mut Sender) {
+            self.messages.borrow_mut().push(message);
+        }
+
+        fn on_close(&mut self, code: u16, _: &str) {
+            *self.closed.borrow_mut() = Some(code);
+        }
+    }
+
+    fn upgraded() -> (MockDriver<WebSocket<Record>>, Record) {
+        let record = Record::default();
+        let handler = record.clone();
+        let mut driver = MockDriver::new(move |_| (WebSocket::new(handler.clone()), Interest::Read));
+        driver.transport().feed(b"GET /chat HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\n\
+            Connection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n");
+        driver.assert_readable(Interest::Read);
+        let written = String::from_utf8(driver.transport().take_written()).unwrap();
+        assert!(written.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{}", written);
+        assert!(written.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"), "{}", written);
+        (driver, record)
+    }
+
+    #[test]
+    fn keys_are_accepted_as_the_rfc_shows() {
+        let upgrade = |version| Request::new("GET", "/chat")
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade")
+            .with_header("Sec-WebSocket-Version", version)
+            .with_header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==");
+        assert_eq!(accept_key(&upgrade("13")).unwrap(), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(accept_key(&upgrade("8")).unwrap_err().status, 426);
+    }
+
+    #[test]
+    fn digests_and_encodings_match_known_values() {
+        assert_eq!(base64(&sha1(b"abc")), "qZk+NkcGgWq6PiVxeFDCbJzQ2J0=");
+        assert_eq!(base64(&sha1(&[b'a'; 64])), "AJi6gktcFkJ716ESKlpEKiXsZE0=");
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+    }
+
+    #[test]
+    fn masked_frames_are_unmasked() {
+        let frame = masked(0x81, b"Hello");
+        assert_eq!(frame, [0x81, 0x85, 0x37, 0xFA, 0x21, 0x3D, 0x7F, 0x9F, 0x4D, 0x51, 0x58]);
+        for end in 0..frame.len() {
+            assert!(parse_frame(&frame[..end]).unwrap().is_none());
+        }
+        let (frame, len) = parse_frame(&frame).unwrap().unwrap();
+        assert_eq!((frame.fin, frame.opcode, len), (true, 0x1, 11));
+        assert_eq!(frame.payload, b"Hello");
+
+        let long = vec![7; 300];
+        let (frame, len) = parse_frame(&masked(0x82, &long)).unwrap().unwrap();
+        assert_eq!((frame.payload, len), (long, 308));
+    }
+
+    #[test]
+    fn bad_frames_are_errors() {
+        let err = |buf: &[u8]| parse_frame(buf).err().map(|(code, _)| code);
+        assert_eq!(err(&[0x81, 0x05, b'H', b'e', b'l', b'l', b'o']), Some(code::PROTOCOL_ERROR));
+        assert_eq!(err(&masked(0xC1, b"")), Some(code::PROTOCOL_ERROR));
+        assert_eq!(err(&masked(0x83, b"")), Some(code::PROTOCOL_ERROR));
+        assert_eq!(err(&masked(0x09, b"")), Some(code::PROTOCOL_ERROR));
+        assert_eq!(err(&masked(0x89, &[0; 126])), Some(code::PROTOCOL_ERROR));
+        // too big is known from the length alone
+        let mut head = vec![0x82, 0x80 | 127];
+        head.extend_from_slice(&(MAX_MESSAGE as u64 + 1).to_be_bytes());
+        assert_eq!(err(&head), Some(code::TOO_BIG));
+    }
+
+    #[test]
+    fn fragments_are_put_back_together() {
+        let (mut driver, record) = upgraded();
+        driver.transport().feed(&masked(0x01, b"Hel"));
+        // control frames may come between fragments
+        driver.transport().feed(&masked(0x89, b"hi"));
+        driver.transport().feed(&masked(0x80, b"lo"));
+        driver.assert_readable(Interest::Read);
+        assert_eq!(*record.messages.borrow(), vec![Message::Text("Hello".to_owned())]);
+        assert_eq!(driver.transport().take_written(), b"\x8A\x02hi");
+    }
+
+    #[test]
+    fn unexpected_fragments_fail_the_connection() {
+        let (mut driver, record) = upgraded();
+        driver.transport().feed(&masked(0x02, b"a"));
+        driver.transport().feed(&masked(0x82, b"b"));
+        driver.readable();
+        assert!(record.messages.borrow().is_empty());
+        assert_eq!(*record.closed.borrow(), Some(code::PROTOCOL_ERROR));
+        assert!(driver.transport().take_written().starts_with(b"\x88"));
+        driver.assert_writable(Interest::Remove);
+    }
+
+    #[test]
+    fn oversized_frames_close_with_too_big() {
+        let (mut driver, record) = upgraded();
+        let mut head = vec![0x82, 0x80 | 127];
+        head.extend_from_slice(&(MAX_MESSAGE as u64 + 1).to_be_bytes());
+        driver.transport().feed(&head);
+        driver.readable();
+        assert_eq!(*record.closed.borrow(), Some(code::TOO_BIG));
+        let written = driver.transport().take_written();
+        assert_eq!(&written[..4], &[0x88, 0x11, 0x03, 0xF1]);
+    }
+}
@@ -4,68 +4,219 @@
 //!
 //! # Example
 //!
-//! ```rust
-//! use tick::{Tick, Protocol, Transfer};
+//! ```rust,no_run
+//! extern crate mio;
+//! extern crate tick;
+//!
+//! use std::io::{Read, Write};
+//! use tick::{Tick, Protocol, Interest, Io};
+//!
+//! type Tcp = mio::tcp::TcpStream;
+//!
+//! struct Echo(Vec<u8>);
 //!
-//! struct Echo(Transfer);
 //! impl Protocol<Tcp> for Echo {
-//!     fn on_data(&mut self, data: &[u8]) {
-//!         println!("data received: {:?}", data);
-//!         self.0.write(data);
+//!     fn on_readable(&mut self, transport: &mut Io<Tcp>) -> Interest {
+//!         let mut buf = [0; 1024];
+//!         if let Ok(n) = transport.read(&mut buf) {
+//!             self.0.extend_from_slice(&buf[..n]);
+//!         }
+//!         Interest::ReadWrite
+//!     }
+//!
+//!     fn on_writable(&mut self, transport: &mut Io<Tcp>) -> Interest {
+//!         if let Ok(n) = transport.write(&self.0) {
+//!             self.0.drain(..n);
+//!         }
+//!         Interest::Read
+//!     }
+//!
+//!     fn on_error(&mut self, err: tick::Error) {
+//!         println!("error: {:?}", err);
 //!     }
 //! }
 //!
-//! let mut tick = Tick::new(Echo);
-//! tick.accept(listener);
-//! tick.run();
+//! fn main() {
+//!     let mut tick = Tick::new(|_| (Echo(Vec::new()), Interest::Read));
+//!     let listener = mio::tcp::TcpListener::bind(&"127.0.0.1:3000".parse().unwrap()).unwrap();
+//!     tick.accept(listener).unwrap();
+//!     tick.run().unwrap();
+//! }
 //! ```
 
 #![cfg_attr(test, deny(warnings))]
 #![cfg_attr(test, deny(missing_docs))]
 
 #[macro_use] extern crate log;
+#[cfg(unix)]
+extern crate libc;
 extern crate mio;
 extern crate slab;
 
-pub use mio::Evented;
-pub use tick::{Tick, Notify};
+use std::error::Error as StdError;
+
+pub use mio::{Evented, EventSet};
+pub use clock::Clock;
+pub use tick::{Tick, TickConfig, Notify};
+#[cfg(unix)]
+pub use mock::{MockTransport, MockListener, MockDriver};
+pub use observer::LoopObserver;
+#[cfg(unix)]
+pub use process::{Process, ChildPipe};
 pub use protocol::{Protocol, Interest};
 pub use protocol::Factory as ProtocolFactory;
+pub use protocol::{Addressed, Context, WithContext, with_context};
+pub use proxy::Duplex;
+#[cfg(unix)]
+pub use pty::Pty;
+pub use queue::WriteQueue;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use sendfile::Splice;
+#[cfg(unix)]
+pub use signal::Signal;
+pub use stats::{Stats, StreamStats};
+#[cfg(unix)]
+pub use stdio::Stdio;
+pub use throttle::Throttle;
 pub use transfer::Transfer;
-pub use transport::Transport;
+pub use transport::{Transport, Io};
 
+mod clock;
 mod handler;
+pub mod http;
+#[cfg(unix)]
+mod mock;
+mod observer;
+#[cfg(unix)]
+mod process;
 mod protocol;
+mod proxy;
+#[cfg(unix)]
+mod pty;
+mod queue;
+#[cfg(unix)]
+mod sendfile;
+#[cfg(unix)]
+mod signal;
+mod source;
+mod stats;
+#[cfg(unix)]
+mod stdio;
 mod stream;
+mod throttle;
 mod tick;
+mod timer;
 mod transfer;
 mod transport;
 
 
 
+/// Errors that can occur while running the loop or handling a stream.
 #[derive(Debug)]
 pub enum Error {
+    /// The loop has no room left for another listener or stream.
     TooManySockets,
+    /// An operation did not complete in time.
     Timeout,
+    /// A listener failed to accept a new stream.
+    Accept(::std::io::Error),
+    /// A listener or stream could not be registered with the loop.
+    Register(::std::io::Error),
+    /// The remote end hung up.
+    Hangup,
+    /// The remote end refused the connection.
+    ConnectRefused(::std::io::Error),
+    /// The listener or stream has already been removed from the loop.
+    Removed,
+    /// The `Id` given to a listener action is of a stream, or something
+    /// else that isn't a listener.
+    NotAListener,
+    /// The loop's notify queue is full. Try again once it has caught up.
+    NotifyFull,
+    /// The loop has shut down, and can no longer be notified.
+    Closed,
+    /// The loop was shut down before the streams it ran for completed.
+    Shutdown,
+    /// An error defined by a `Protocol`.
+    Protocol(Box<dyn StdError + Send + Sync>),
+    /// An IO error from the underlying transport or event loop.
     Io(::std::io::Error)
 }
 
+impl Error {
+    /// Wraps an error defined by a `Protocol`.
+    pub fn protocol<E: Into<Box<dyn StdError + Send + Sync>>>(err: E) -> Error {
+        Error::Protocol(err.into())
+    }
+}
+
+impl ::std::fmt::Display for Error {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            Error::TooManySockets => f.write_str("too many sockets"),
+            Error::Timeout => f.write_str("timed out"),
+            Error::Accept(ref e) => write!(f, "accept failed: {}", e),
+            Error::Register(ref e) => write!(f, "registration failed: {}", e),
+            Error::Hangup => f.write_str("remote end hung up"),
+            Error::ConnectRefused(_) => f.write_str("connection refused"),
+            Error::Removed => f.write_str("already removed from the loop"),
+            Error::NotAListener => f.write_str("not a listener"),
+            Error::NotifyFull => f.write_str("notify queue is full"),
+            Error::Closed => f.write_str("event loop is closed"),
+            Error::Shutdown => f.write_str("event loop was shut down"),
+            Error::Protocol(ref e) => write!(f, "protocol error: {}", e),
+            Error::Io(ref e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            Error::Accept(ref e) |
+            Error::Register(ref e) |
+            Error::ConnectRefused(ref e) |
+            Error::Io(ref e) => Some(e),
+            Error::Protocol(ref e) => Some(&**e),
+            _ => None,
+        }
+    }
+}
+
 impl From<::std::io::Error> for Error {
     fn from(e: ::std::io::Error) -> Error {
-        Error::Io(e)
+        match e.kind() {
+            ::std::io::ErrorKind::ConnectionRefused => Error::ConnectRefused(e),
+            _ => Error::Io(e),
+        }
     }
 }
 
+impl<M> From<::mio::NotifyError<M>> for Error {
+    fn from(e: ::mio::NotifyError<M>) -> Error {
+        match e {
+            ::mio::NotifyError::Io(e) => Error::Io(e),
+            ::mio::NotifyError::Full(_) => Error::NotifyFull,
+            ::mio::NotifyError::Closed(_) => Error::Closed,
+        }
+    }
+}
+
+/// A `Result` using `tick::Error`.
 pub type Result<T> = std::result::Result<T, Error>;
 
 /// Opaque ID returned when adding listeners and streams to the loop.
+///
+/// Slots in the loop are reused once a stream is removed, so an `Id` also
+/// carries the generation of its slot, and never refers to a later stream.
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct Id(::mio::Token);
+pub struct Id(::mio::Token, u64);
 
 impl ::std::fmt::Debug for Id {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         f.debug_tuple("Id")
             .field(&(self.0).0)
+            .field(&self.1)
             .finish()
     }
 }
@@ -73,7 +224,7 @@ impl ::std::fmt::Debug for Id {
 
 impl slab::Index for Id {
     fn from_usize(i: usize) -> Id {
-        Id(::mio::Token(i))
+        Id(::mio::Token(i), 0)
     }
 
     fn as_usize(&self) -> usize {
@@ -81,6 +232,7 @@ impl slab::Index for Id {
     }
 }
 
+/// A `Slab` indexed by `Id`.
 pub type Slab<T> = slab::Slab<T, Id>;
 
 mod internal {
@@ -91,9 +243,21 @@ mod internal {
         Remove,
     }
 
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub enum ListenerAction {
+        Pause,
+        Resume,
+        Remove,
+    }
+
     pub enum Message {
-        Interest(::mio::Token, ::Interest),
-        //Timeout(Box<FnMut() + Send + 'static>, u64),
+        Interest(::Id),
+        // an edge-triggered stream still ready after its share of dispatches
+        Dispatch(::Id),
+        Timeout(Box<dyn FnOnce() + Send + 'static>, ::std::time::Duration),
+        Listener(::Id, ListenerAction),
+        Stats(::std::sync::mpsc::Sender<::Stats>),
+        Throttle(::Id, ::Throttle),
         Shutdown,
     }
 }
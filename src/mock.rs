@@ -0,0 +1,416 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+
+use mio::{self, EventLoop, EventSet, PollOpt, Selector, Token, TryAccept};
+use mio::unix::EventedFd;
+
+use internal::Message;
+use protocol;
+use transfer::{self, Shared};
+use ::{Id, Interest, Io, Protocol, ProtocolFactory, Transport};
+
+enum Script {
+    Data(Vec<u8>),
+    Eof,
+    Error(io::ErrorKind),
+}
+
+/// An in-memory `Transport`, for testing `Protocol`s without sockets.
+///
+/// Reads are served from scripted chunks, returning `WouldBlock` once the
+/// script runs dry. Writes are collected, and can be capped to force
+/// `WouldBlock`.
+///
+/// For use in a real loop, the mock registers a socket that is always
+/// writable, and readable whenever scripted reads are pending.
+pub struct MockTransport {
+    reads: VecDeque<Script>,
+    written: Vec<u8>,
+    write_budget: Option<usize>,
+    readiness: Readiness,
+}
+
+// A socket pair, where one end is registered with the loop, and becomes
+// readable while the other end has written to it.
+struct Readiness {
+    signaled: bool,
+    registered: UnixStream,
+    signal: UnixStream,
+}
+
+impl Readiness {
+    fn new() -> Readiness {
+        let (registered, signal) = UnixStream::pair().expect("mock socket pair");
+        registered.set_nonblocking(true).expect("mock socket nonblocking");
+        Readiness {
+            signaled: false,
+            registered,
+            signal,
+        }
+    }
+
+    fn set(&mut self) {
+        if !self.signaled {
+            self.signaled = (&self.signal).write_all(&[1]).is_ok();
+        }
+    }
+
+    fn clear(&mut self) {
+        if self.signaled {
+            let _ = (&self.registered).read(&mut [0]);
+            self.signaled = false;
+        }
+    }
+}
+
+impl mio::Evented for Readiness {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.registered.as_raw_fd()).register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.registered.as_raw_fd()).reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        EventedFd(&self.registered.as_raw_fd()).deregister(selector)
+    }
+}
+
+impl MockTransport {
+    /// Creates a mock with nothing to read, and no cap on writes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the socket pair used as a readiness source can't be created.
+    pub fn new() -> MockTransport {
+        MockTransport {
+            reads: VecDeque::new(),
+            written: Vec::new(),
+            write_budget: None,
+            readiness: Readiness::new(),
+        }
+    }
+
+    /// Queues `data` to be returned from reads.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.script(Script::Data(data.to_vec()));
+    }
+
+    /// Queues an end-of-file, returning `Ok(0)` from a read.
+    pub fn feed_eof(&mut self) {
+        self.script(Script::Eof);
+    }
+
+    /// Queues an error to be returned from a read.
+    pub fn feed_error(&mut self, kind: io::ErrorKind) {
+        self.script(Script::Error(kind));
+    }
+
+    /// Caps the number of bytes further writes will accept before returning
+    /// `WouldBlock`. `None` removes the cap.
+    pub fn limit_writes(&mut self, limit: Option<usize>) {
+        self.write_budget = limit;
+    }
+
+    /// Everything written to this transport so far.
+    pub fn written(&self) -> &[u8] {
+        &self.written
+    }
+
+    /// Takes everything written to this transport so far.
+    pub fn take_written(&mut self) -> Vec<u8> {
+        ::std::mem::take(&mut self.written)
+    }
+
+    /// Whether any scripted reads are left.
+    pub fn has_pending_reads(&self) -> bool {
+        !self.reads.is_empty()
+    }
+
+    fn script(&mut self, script: Script) {
+        self.reads.push_back(script);
+        self.readiness.set();
+    }
+}
+
+impl Default for MockTransport {
+    fn default() -> MockTransport {
+        MockTransport::new()
+    }
+}
+
+impl Read for MockTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let ret = match self.reads.pop_front() {
+            Some(Script::Data(mut data)) => {
+                let n = ::std::cmp::min(buf.len(), data.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                if n < data.len() {
+                    data.drain(..n);
+                    self.reads.push_front(Script::Data(data));
+                }
+                Ok(n)
+            }
+            Some(Script::Eof) => Ok(0),
+            Some(Script::Error(kind)) => Err(io::Error::new(kind, "MockTransport scripted error")),
+            None => Err(io::Error::new(io::ErrorKind::WouldBlock, "MockTransport has no data")),
+        };
+        if self.reads.is_empty() {
+            self.readiness.clear();
+        }
+        ret
+    }
+}
+
+impl Write for MockTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = match self.write_budget {
+            Some(0) => return Err(io::Error::new(io::ErrorKind::WouldBlock, "MockTransport write limit")),
+            Some(ref mut budget) => {
+                let n = ::std::cmp::min(*budget, buf.len());
+                *budget -= n;
+                n
+            }
+            None => buf.len(),
+        };
+        self.written.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MockTransport {}
+
+impl mio::Evented for MockTransport {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.readiness.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.readiness.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.readiness.deregister(selector)
+    }
+}
+
+/// A listener handing out queued `MockTransport`s, so a `Tick` can be
+/// created for them.
+///
+/// Clones share the same queue, so a test can keep one to push transports
+/// after handing the listener to `Tick::accept`. It is readable whenever
+/// transports are waiting to be accepted.
+#[derive(Clone)]
+pub struct MockListener {
+    inner: Rc<RefCell<ListenerInner>>,
+}
+
+struct ListenerInner {
+    queue: VecDeque<io::Result<MockTransport>>,
+    readiness: Readiness,
+}
+
+impl MockListener {
+    /// Creates a listener with nothing to accept.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the socket pair used as a readiness source can't be created.
+    pub fn new() -> MockListener {
+        MockListener {
+            inner: Rc::new(RefCell::new(ListenerInner {
+                queue: VecDeque::new(),
+                readiness: Readiness::new(),
+            })),
+        }
+    }
+
+    /// Queues a transport to be accepted.
+    pub fn push(&self, transport: MockTransport) {
+        let mut inner = self.inner.borrow_mut();
+        inner.queue.push_back(Ok(transport));
+        inner.readiness.set();
+    }
+
+    /// Queues an error to be returned by `accept`, in turn with any queued
+    /// transports.
+    pub fn push_error(&self, err: io::Error) {
+        let mut inner = self.inner.borrow_mut();
+        inner.queue.push_back(Err(err));
+        inner.readiness.set();
+    }
+}
+
+impl Default for MockListener {
+    fn default() -> MockListener {
+        MockListener::new()
+    }
+}
+
+impl TryAccept for MockListener {
+    type Output = MockTransport;
+
+    fn accept(&self) -> io::Result<Option<MockTransport>> {
+        let mut inner = self.inner.borrow_mut();
+        let transport = inner.queue.pop_front();
+        if inner.queue.is_empty() {
+            inner.readiness.clear();
+        }
+        transport.map_or(Ok(None), |transport| transport.map(Some))
+    }
+}
+
+impl mio::Evented for MockListener {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.inner.borrow().readiness.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.inner.borrow().readiness.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.inner.borrow().readiness.deregister(selector)
+    }
+}
+
+/// Drives a `Protocol` over a `MockTransport`, without a `Tick`.
+///
+/// # Example
+///
+/// ```rust
+/// # extern crate tick;
+/// # use std::io::{Read, Write};
+/// # use tick::{Interest, Io, Protocol, MockTransport, MockDriver};
+/// struct Upper;
+///
+/// impl Protocol<MockTransport> for Upper {
+///     fn on_readable(&mut self, transport: &mut Io<MockTransport>) -> Interest {
+///         let mut buf = [0; 16];
+///         let n = transport.read(&mut buf).unwrap();
+///         let upper = buf[..n].to_ascii_uppercase();
+///         transport.write_all(&upper).unwrap();
+///         Interest::Read
+///     }
+///
+///     fn on_writable(&mut self, _: &mut Io<MockTransport>) -> Interest {
+///         Interest::Read
+///     }
+///
+///     fn on_error(&mut self, _: tick::Error) {}
+/// }
+///
+/// # fn main() {
+/// let mut driver = MockDriver::new(|_| (Upper, Interest::Read));
+/// driver.transport().feed(b"hello");
+/// driver.assert_readable(Interest::Read);
+/// assert_eq!(driver.transport().written(), b"HELLO");
+/// # }
+/// ```
+pub struct MockDriver<P: Protocol<MockTransport>> {
+    protocol: P,
+    transport: Io<MockTransport>,
+    interest: Interest,
+    event_loop: EventLoop<Collector>,
+    collector: Collector,
+}
+
+impl<P: Protocol<MockTransport>> MockDriver<P> {
+    /// Creates the protocol from `factory`, as if a stream was added to a loop.
+    pub fn new<F: ProtocolFactory<MockTransport, Protocol=P>>(mut factory: F) -> MockDriver<P> {
+        let event_loop = EventLoop::new().expect("MockDriver event loop");
+        let id = Id(Token(0), 0);
+        let shared = transfer::shared();
+        let missed = Arc::new(AtomicBool::new(false));
+        let transfer = transfer::new(id, shared.clone(), event_loop.channel(), missed);
+        let transport = MockTransport::new();
+        let (protocol, interest) = factory.create(transfer, &protocol::context(&transport, None, id));
+        MockDriver {
+            protocol,
+            transport: Io::new(transport),
+            interest,
+            event_loop,
+            collector: Collector(shared, Vec::new()),
+        }
+    }
+
+    /// The mock transport the protocol is operating on.
+    pub fn transport(&mut self) -> &mut MockTransport {
+        &mut self.transport
+    }
+
+    /// The protocol being driven.
+    pub fn protocol(&mut self) -> &mut P {
+        &mut self.protocol
+    }
+
+    /// The interest most recently returned by the protocol.
+    pub fn interest(&self) -> Interest {
+        self.interest
+    }
+
+    /// Calls `on_readable`, returning the protocol's new interest.
+    pub fn readable(&mut self) -> Interest {
+        self.interest = self.protocol.on_readable(&mut self.transport);
+        self.interest
+    }
+
+    /// Calls `on_writable`, returning the protocol's new interest.
+    pub fn writable(&mut self) -> Interest {
+        self.interest = self.protocol.on_writable(&mut self.transport);
+        self.interest
+    }
+
+    /// Calls `on_error` with `err`.
+    pub fn error(&mut self, err: ::Error) {
+        self.protocol.on_error(err);
+    }
+
+    /// Calls `on_readable`, panicking if the returned interest isn't `expected`.
+    pub fn assert_readable(&mut self, expected: Interest) {
+        let interest = self.readable();
+        assert_eq!(interest, expected, "on_readable returned {:?}, expected {:?}", interest, expected);
+    }
+
+    /// Calls `on_writable`, panicking if the returned interest isn't `expected`.
+    pub fn assert_writable(&mut self, expected: Interest) {
+        let interest = self.writable();
+        assert_eq!(interest, expected, "on_writable returned {:?}, expected {:?}", interest, expected);
+    }
+
+    /// Takes the interests sent through the protocol's `Transfer` so far,
+    /// coalesced the same way the loop would see them.
+    pub fn transferred(&mut self) -> Vec<Interest> {
+        let _ = self.event_loop.run_once(&mut self.collector, Some(0));
+        ::std::mem::take(&mut self.collector.1)
+    }
+
+    /// Calls `on_remove`, as if the stream was removed from a loop.
+    pub fn remove(self) {
+        self.protocol.on_remove(self.transport.into_inner());
+    }
+}
+
+struct Collector(Arc<Shared>, Vec<Interest>);
+
+impl mio::Handler for Collector {
+    type Timeout = ();
+    type Message = Message;
+
+    fn notify(&mut self, _: &mut EventLoop<Collector>, msg: Message) {
+        if let Message::Interest(_) = msg {
+            self.1.push(transfer::take_interest(&self.0));
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use ::{Error, Id, Interest};
+
+/// Hooks into the lifecycle of a `Tick`'s listeners and streams.
+///
+/// All methods are called on the loop thread, and default to doing nothing.
+#[allow(unused_variables)]
+pub trait LoopObserver {
+    /// A listener was registered with the loop.
+    fn on_listener(&mut self, id: Id) {}
+
+    /// A listener accepted a new stream.
+    fn on_accept(&mut self, listener: Id, stream: Id) {}
+
+    /// A stream's interest changed, either from a `Protocol` callback or a
+    /// `Transfer`.
+    fn on_interest(&mut self, id: Id, interest: Interest) {}
+
+    /// The loop hit an error operating on a stream.
+    fn on_error(&mut self, id: Id, error: &Error) {}
+
+    /// A stream was removed from the loop.
+    fn on_remove(&mut self, id: Id) {}
+
+    /// The loop finished an iteration.
+    fn on_tick(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{self, Read, Write};
+    use std::rc::Rc;
+
+    use super::LoopObserver;
+    use ::{Id, Interest, Io, MockListener, MockTransport, Protocol, Tick, Transfer};
+
+    #[derive(Debug, PartialEq)]
+    enum Seen {
+        Listener(Id),
+        Accept(Id, Id),
+        Interest(Id, Interest),
+        Remove(Id),
+    }
+
+    struct Record(Rc<RefCell<Vec<Seen>>>);
+
+    impl LoopObserver for Record {
+        fn on_listener(&mut self, id: Id) {
+            self.0.borrow_mut().push(Seen::Listener(id));
+        }
+
+        fn on_accept(&mut self, listener: Id, stream: Id) {
+            self.0.borrow_mut().push(Seen::Accept(listener, stream));
+        }
+
+        fn on_interest(&mut self, id: Id, interest: Interest) {
+            self.0.borrow_mut().push(Seen::Interest(id, interest));
+        }
+
+        fn on_remove(&mut self, id: Id) {
+            self.0.borrow_mut().push(Seen::Remove(id));
+        }
+    }
+
+    // reads a request to EOF, replies, and is removed
+    struct Reply;
+
+    impl Protocol<MockTransport> for Reply {
+        fn on_readable(&mut self, transport: &mut Io<MockTransport>) -> Interest {
+            let mut buf = [0; 64];
+            loop {
+                match transport.read(&mut buf) {
+                    Ok(0) => return Interest::Write,
+                    Ok(_) => (),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Interest::Read,
+                    Err(_) => return Interest::Remove,
+                }
+            }
+        }
+
+        fn on_writable(&mut self, transport: &mut Io<MockTransport>) -> Interest {
+            transport.write_all(b"bye").unwrap();
+            Interest::Remove
+        }
+
+        fn on_error(&mut self, _: ::Error) {}
+    }
+
+    #[test]
+    fn callbacks_follow_a_stream_from_accept_to_remove() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut tick = Tick::<MockListener, _>::simulated(|_: Transfer| (Reply, Interest::Read));
+        tick.observe(Record(seen.clone()));
+        let listener = MockListener::new();
+        let lid = tick.accept(listener.clone()).unwrap();
+        let mut transport = MockTransport::new();
+        transport.feed(b"hi");
+        transport.feed_eof();
+        listener.push(transport);
+        while tick.step().unwrap() {}
+
+        let sid = match seen.borrow()[1] {
+            Seen::Accept(_, sid) => sid,
+            ref other => panic!("expected an accept, got {:?}", other),
+        };
+        assert_eq!(*seen.borrow(), vec![
+            Seen::Listener(lid),
+            Seen::Accept(lid, sid),
+            Seen::Interest(sid, Interest::Write),
+            Seen::Interest(sid, Interest::Remove),
+            Seen::Remove(sid),
+        ]);
+    }
+}
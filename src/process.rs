@@ -0,0 +1,337 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::process::{self, ExitStatus};
+
+use libc;
+use mio::{EventSet, PollOpt, Selector, Token};
+use mio::unix::EventedFd;
+
+use ::{Evented, Interest, Io, Transport};
+use transport;
+
+/// Handles a child process spawned with `Tick::spawn`.
+///
+/// The pipe methods are called like a `Protocol`'s, with the returned
+/// `Interest` deciding what happens to that pipe next. `Interest::Remove`
+/// closes it, which for stdin lets the child see EOF.
+pub trait Process {
+    /// Called when the child's stdin is writable.
+    ///
+    /// Defaults to closing stdin.
+    fn on_stdin(&mut self, stdin: &mut Io<ChildPipe>) -> Interest {
+        let _ = stdin;
+        Interest::Remove
+    }
+
+    /// Called when the child's stdout is readable.
+    ///
+    /// Defaults to discarding the output.
+    fn on_stdout(&mut self, stdout: &mut Io<ChildPipe>) -> Interest {
+        discard(stdout)
+    }
+
+    /// Called when the child's stderr is readable.
+    ///
+    /// Defaults to discarding the output.
+    fn on_stderr(&mut self, stderr: &mut Io<ChildPipe>) -> Interest {
+        discard(stderr)
+    }
+
+    /// Called once the child has exited, after any output left in its pipes
+    /// has been handed to `on_stdout` and `on_stderr`.
+    fn on_exit(&mut self, status: ExitStatus);
+}
+
+fn discard(pipe: &mut Io<ChildPipe>) -> Interest {
+    let mut buf = [0u8; 4096];
+    loop {
+        match pipe.read(&mut buf) {
+            Ok(0) => return Interest::Remove,
+            Ok(_) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Interest::Read,
+            Err(_) => return Interest::Remove,
+        }
+    }
+}
+
+/// A non-blocking pipe to one of a child process's standard streams.
+#[derive(Debug)]
+pub struct ChildPipe {
+    file: File,
+}
+
+impl ChildPipe {
+    fn new<F: IntoRawFd>(fd: F) -> io::Result<ChildPipe> {
+        let fd = fd.into_raw_fd();
+        let pipe = ChildPipe { file: unsafe { File::from_raw_fd(fd) } };
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(pipe)
+    }
+}
+
+impl Read for ChildPipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for ChildPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        self.file.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for ChildPipe {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl Transport for ChildPipe {}
+
+impl Evented for ChildPipe {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.as_raw_fd()).register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.as_raw_fd()).reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        EventedFd(&self.as_raw_fd()).deregister(selector)
+    }
+}
+
+/// Which of a child's standard streams a pipe is connected to.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Stdio {
+    In = 0,
+    Out = 1,
+    Err = 2,
+}
+
+impl Stdio {
+    /// The readiness the pipe waits on initially.
+    pub fn events(self) -> EventSet {
+        match self {
+            Stdio::In => EventSet::writable(),
+            Stdio::Out | Stdio::Err => EventSet::readable(),
+        }
+    }
+}
+
+pub struct Pipe {
+    pub token: Token,
+    pub io: Io<ChildPipe>,
+}
+
+/// A spawned child, its `Process`, and the pipes still open to it.
+pub struct Child {
+    child: process::Child,
+    process: Box<dyn Process>,
+    pipes: [Option<Pipe>; 3],
+}
+
+/// Spawns `command` with all of its standard streams piped.
+pub fn spawn(command: &mut process::Command, process: Box<dyn Process>) -> io::Result<(Child, Vec<(Stdio, ChildPipe)>)> {
+    let mut child = command
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()?;
+    let mut pipes = Vec::with_capacity(3);
+    let result = (|| {
+        if let Some(stdin) = child.stdin.take() {
+            pipes.push((Stdio::In, ChildPipe::new(stdin)?));
+        }
+        if let Some(stdout) = child.stdout.take() {
+            pipes.push((Stdio::Out, ChildPipe::new(stdout)?));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            pipes.push((Stdio::Err, ChildPipe::new(stderr)?));
+        }
+        Ok(())
+    })();
+    if let Err(e) = result {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(e);
+    }
+    Ok((Child { child, process, pipes: [None, None, None] }, pipes))
+}
+
+impl Child {
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    pub fn set_pipe(&mut self, stdio: Stdio, pipe: Pipe) {
+        self.pipes[stdio as usize] = Some(pipe);
+    }
+
+    pub fn take_pipe(&mut self, stdio: Stdio) -> Option<Pipe> {
+        self.pipes[stdio as usize].take()
+    }
+
+    pub fn pipe(&self, stdio: Stdio) -> Option<&Pipe> {
+        self.pipes[stdio as usize].as_ref()
+    }
+
+    /// Calls the process for `events` on the pipe for `stdio`, returning
+    /// the pipe's next interest.
+    pub fn ready(&mut self, stdio: Stdio, events: EventSet) -> Interest {
+        let pipe = match self.pipes[stdio as usize] {
+            Some(ref mut pipe) => pipe,
+            None => return Interest::Remove,
+        };
+        trace!("child {} {:?} ready '{:?}'", self.child.id(), stdio, events);
+        match stdio {
+            Stdio::In if events.is_error() || events.is_hup() => Interest::Remove,
+            Stdio::In => self.process.on_stdin(&mut pipe.io),
+            // the other end is closed, so there is nothing left to wait on
+            Stdio::Out | Stdio::Err if events.is_error() || events.is_hup() => {
+                drain(&mut *self.process, stdio, &mut pipe.io);
+                Interest::Remove
+            }
+            Stdio::Out | Stdio::Err => read(&mut *self.process, stdio, &mut pipe.io),
+        }
+    }
+
+    /// Checks whether the child has exited, without blocking.
+    pub fn try_wait(&mut self) -> Option<ExitStatus> {
+        match self.child.try_wait() {
+            Ok(status) => status,
+            Err(e) => {
+                error!("failed to wait on child {}: {}", self.child.id(), e);
+                None
+            }
+        }
+    }
+
+    /// Hands any output left in the pipes to the process, then tells it the
+    /// child has exited.
+    pub fn exited(&mut self, status: ExitStatus) {
+        debug!("child {} exited, {}", self.child.id(), status);
+        for &stdio in &[Stdio::Out, Stdio::Err] {
+            if let Some(ref mut pipe) = self.pipes[stdio as usize] {
+                drain(&mut *self.process, stdio, &mut pipe.io);
+            }
+        }
+        self.process.on_exit(status);
+    }
+
+    /// Kills the child, after a failure to add it to the loop.
+    pub fn kill(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn read(process: &mut dyn Process, stdio: Stdio, pipe: &mut Io<ChildPipe>) -> Interest {
+    if stdio == Stdio::Out {
+        process.on_stdout(pipe)
+    } else {
+        process.on_stderr(pipe)
+    }
+}
+
+// hands the process what's left in a pipe whose writer is gone, since no
+// more readiness will come for it, until it stops reading, or a read finds
+// EOF or would block
+fn drain(process: &mut dyn Process, stdio: Stdio, pipe: &mut Io<ChildPipe>) {
+    transport::take_blocked(pipe);
+    loop {
+        let before = pipe.bytes_read();
+        match read(process, stdio, pipe) {
+            Interest::Read | Interest::ReadWrite => (),
+            _ => return,
+        }
+        if transport::take_blocked(pipe).is_readable() || pipe.bytes_read() == before {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::io::{self, Read};
+    use std::process::{Command, ExitStatus};
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    use signal::test_lock;
+    use super::{ChildPipe, Process};
+    use ::{Interest, Io, MockListener, MockTransport, Protocol, Tick};
+
+    struct Idle;
+
+    impl Protocol<MockTransport> for Idle {
+        fn on_readable(&mut self, _: &mut Io<MockTransport>) -> Interest {
+            Interest::Read
+        }
+
+        fn on_writable(&mut self, _: &mut Io<MockTransport>) -> Interest {
+            Interest::Read
+        }
+
+        fn on_error(&mut self, _: ::Error) {}
+    }
+
+    // reads a small chunk of stdout per call
+    struct Chunks {
+        out: Rc<RefCell<Vec<u8>>>,
+        // how many bytes had arrived when the exit was seen
+        at_exit: Rc<Cell<Option<usize>>>,
+    }
+
+    impl Process for Chunks {
+        fn on_stdout(&mut self, stdout: &mut Io<ChildPipe>) -> Interest {
+            let mut buf = [0; 1024];
+            match stdout.read(&mut buf) {
+                Ok(0) => Interest::Remove,
+                Ok(n) => {
+                    self.out.borrow_mut().extend_from_slice(&buf[..n]);
+                    Interest::Read
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Interest::Read,
+                Err(_) => Interest::Remove,
+            }
+        }
+
+        fn on_exit(&mut self, _: ExitStatus) {
+            self.at_exit.set(Some(self.out.borrow().len()));
+        }
+    }
+
+    #[test]
+    fn output_left_at_exit_is_all_read() {
+        let _lock = test_lock();
+        let mut tick = Tick::<MockListener, _>::new(|_| (Idle, Interest::Read));
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let at_exit = Rc::new(Cell::new(None));
+        let process = Chunks { out: out.clone(), at_exit: at_exit.clone() };
+        // many reads' worth, but small enough for the pipe to hold it all
+        let mut command = Command::new("head");
+        command.args(["-c", "40000", "/dev/zero"]);
+        let id = tick.spawn(&mut command, process).unwrap();
+        tick.run_until_complete_by(id, Instant::now() + Duration::from_secs(5)).unwrap();
+        assert_eq!(out.borrow().len(), 40000);
+        assert_eq!(at_exit.get(), Some(40000));
+    }
+}
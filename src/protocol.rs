@@ -1,26 +1,50 @@
+use std::cell::Cell;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+
 use mio::EventSet;
+use mio::tcp::TcpStream;
 
 use ::internal::Action;
-use ::Transport;
+use ::{Id, Io, Transport};
 
 
+/// A state machine driven by the readiness of a single `Transport`.
+///
+/// Callbacks are handed the transport wrapped in an `Io`, rather than the
+/// bare transport, so that the loop sees every read and write: it counts
+/// bytes for `Stats`, notices `WouldBlock` to track edge-triggered
+/// readiness, enforces a `Throttle`, and sends files queued with
+/// `Io::send_file`. `Io<T>` derefs to `T`, so protocols written against
+/// `&mut T` only need their signatures changed.
 pub trait Protocol<T: Transport> {
-    fn on_readable(&mut self, transport: &mut T) -> Interest;
-    fn on_writable(&mut self, transport: &mut T) -> Interest;
+    /// Called when the transport is readable, returning the next interest.
+    fn on_readable(&mut self, transport: &mut Io<T>) -> Interest;
+    /// Called when the transport is writable, returning the next interest.
+    fn on_writable(&mut self, transport: &mut Io<T>) -> Interest;
 
+    /// Called when the loop hits an error operating on this stream.
     fn on_error(&mut self, error: ::Error);
 
+    /// Called once the stream has been removed from the loop.
     fn on_remove(self, _transport: T) where Self: Sized {
         trace!("on_remove; default just drops");
     }
 }
 
+/// What a `Protocol` wants to happen next with its transport.
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Interest {
+    /// Remove the stream from the loop.
     Remove,
+    /// Stay in the loop, but don't wait on any readiness.
     Wait,
+    /// Wait until the transport is readable.
     Read,
+    /// Wait until the transport is writable.
     Write,
+    /// Wait until the transport is readable or writable.
     ReadWrite,
 }
 
@@ -57,9 +81,9 @@ impl ::std::ops::Sub for Interest {
     }
 }
 
-impl Into<Action> for Interest {
-    fn into(self) -> Action {
-        match self {
+impl From<Interest> for Action {
+    fn from(interest: Interest) -> Action {
+        match interest {
             Interest::Read => Action::Register(EventSet::readable()),
             Interest::Write => Action::Register(EventSet::writable()),
             Interest::ReadWrite => Action::Register(EventSet::readable() | EventSet::writable()),
@@ -69,14 +93,264 @@ impl Into<Action> for Interest {
     }
 }
 
+/// Creates a `Protocol` for every stream added to the loop.
+///
+/// Closures taking just a `Transfer` are factories, ignoring the `Context`.
+/// Wrap a closure with `with_context` to receive it as well.
 pub trait Factory<T: Transport> {
+    /// The `Protocol` this factory creates.
     type Protocol: Protocol<T>;
-    fn create(&mut self, ::Transfer) -> (Self::Protocol, Interest);
+    /// Creates a protocol for a new stream, along with its initial interest.
+    fn create(&mut self, transfer: ::Transfer, context: &Context<T>) -> (Self::Protocol, Interest);
 }
 
 impl<F, P, T> Factory<T> for F where F: FnMut(::Transfer) -> (P, Interest), P: Protocol<T>, T: Transport {
     type Protocol = P;
-    fn create(&mut self, transfer: ::Transfer) -> (P, Interest) {
+    fn create(&mut self, transfer: ::Transfer, _context: &Context<T>) -> (P, Interest) {
         self(transfer)
     }
 }
+
+/// A `Factory` made from a closure that also takes the `Context`.
+///
+/// Created with `with_context`.
+pub struct WithContext<F>(F);
+
+/// Wraps a closure so it is called with the `Context` of each new stream.
+pub fn with_context<F, P, T>(f: F) -> WithContext<F>
+where F: FnMut(::Transfer, &Context<T>) -> (P, Interest), P: Protocol<T>, T: Transport {
+    WithContext(f)
+}
+
+impl<F, P, T> Factory<T> for WithContext<F> where F: FnMut(::Transfer, &Context<T>) -> (P, Interest), P: Protocol<T>, T: Transport {
+    type Protocol = P;
+    fn create(&mut self, transfer: ::Transfer, context: &Context<T>) -> (P, Interest) {
+        (self.0)(transfer, context)
+    }
+}
+
+/// Details about a new stream, given to a `Factory` when creating its
+/// `Protocol`.
+pub struct Context<'a, T: 'a> {
+    transport: &'a T,
+    listener: Option<Id>,
+    id: Id,
+    throttle: Cell<Option<::Throttle>>,
+}
+
+#[inline]
+pub fn context<T>(transport: &T, listener: Option<Id>, id: Id) -> Context<'_, T> {
+    Context {
+        transport,
+        listener,
+        id,
+        throttle: Cell::new(None),
+    }
+}
+
+/// Takes the limits the factory attached with `Context::throttle`.
+#[inline]
+pub fn take_throttle<T>(context: &Context<T>) -> Option<::Throttle> {
+    context.throttle.take()
+}
+
+impl<'a, T> Context<'a, T> {
+    /// The `Id` of the new stream.
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    /// The `Id` of the listener that accepted the stream, or `None` if it
+    /// was added with `Tick::stream`.
+    pub fn listener(&self) -> Option<Id> {
+        self.listener
+    }
+
+    /// The transport of the new stream.
+    pub fn transport(&self) -> &T {
+        self.transport
+    }
+
+    /// Limits how fast the new stream reads and writes, from the start.
+    pub fn throttle(&self, throttle: &::Throttle) {
+        self.throttle.set(Some(*throttle));
+    }
+}
+
+impl<'a, T: Addressed> Context<'a, T> {
+    /// The address of the remote end of the stream.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.transport.peer_addr()
+    }
+
+    /// The local address of the stream.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.transport.local_addr()
+    }
+}
+
+/// A transport connected between two socket addresses, which a `Context`
+/// reports.
+///
+/// Implemented for `TcpStream`. Transports wrapping one, or enums of
+/// several, can implement it to have the addresses reach their factories.
+pub trait Addressed {
+    /// The address of the remote end.
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// The local address.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl Addressed for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+}
+
+impl<'a, T> fmt::Debug for Context<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Context")
+            .field("id", &self.id)
+            .field("listener", &self.listener)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io;
+    use std::net::{self, SocketAddr};
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use mio::{EventSet, PollOpt, Selector, Token};
+    use mio::tcp::{TcpListener, TcpStream};
+
+    use super::{with_context, Context};
+    use ::{Addressed, Evented, Id, Interest, Io, Protocol, Tick, Transfer};
+
+    struct Idle;
+
+    impl Protocol<TcpStream> for Idle {
+        fn on_readable(&mut self, _: &mut Io<TcpStream>) -> Interest {
+            Interest::Read
+        }
+
+        fn on_writable(&mut self, _: &mut Io<TcpStream>) -> Interest {
+            Interest::Read
+        }
+
+        fn on_error(&mut self, _: ::Error) {}
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Seen {
+        id: Id,
+        listener: Option<Id>,
+        peer: SocketAddr,
+        local: SocketAddr,
+    }
+
+    fn record(seen: &Rc<RefCell<Vec<Seen>>>, ctx: &Context<TcpStream>) {
+        seen.borrow_mut().push(Seen {
+            id: ctx.id(),
+            listener: ctx.listener(),
+            peer: ctx.peer_addr().unwrap(),
+            local: ctx.local_addr().unwrap(),
+        });
+    }
+
+    #[test]
+    fn factories_see_the_new_stream() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let s = seen.clone();
+        let factory = with_context(move |_: Transfer, ctx: &Context<TcpStream>| {
+            record(&s, ctx);
+            (Idle, Interest::Read)
+        });
+        let mut tick = Tick::new(factory);
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let lid = tick.accept(listener).unwrap();
+
+        let client = net::TcpStream::connect(addr).unwrap();
+        for _ in 0..50 {
+            if !seen.borrow().is_empty() {
+                break;
+            }
+            tick.run_once(Some(Duration::from_millis(100))).unwrap();
+        }
+        let accepted = tick.stats().streams[0].id;
+        assert_eq!(*seen.borrow(), vec![Seen {
+            id: accepted,
+            listener: Some(lid),
+            peer: client.local_addr().unwrap(),
+            local: addr,
+        }]);
+
+        // added directly, with no listener
+        let direct = TcpStream::connect(&addr).unwrap();
+        let local = direct.local_addr().unwrap();
+        let id = tick.stream(direct).unwrap();
+        assert_eq!(seen.borrow()[1], Seen { id, listener: None, peer: addr, local });
+    }
+
+    // wraps a socket, as a tls stream would
+    struct Wrapped(TcpStream);
+
+    impl io::Read for Wrapped {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl io::Write for Wrapped {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl Evented for Wrapped {
+        fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+            self.0.register(selector, token, interest, opts)
+        }
+
+        fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+            self.0.reregister(selector, token, interest, opts)
+        }
+
+        fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+            self.0.deregister(selector)
+        }
+    }
+
+    impl Addressed for Wrapped {
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            self.0.peer_addr()
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.0.local_addr()
+        }
+    }
+
+    #[test]
+    fn other_transports_can_report_addresses() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stream = Wrapped(TcpStream::connect(&addr).unwrap());
+        let ctx = super::context(&stream, None, Id(Token(0), 0));
+        assert_eq!(ctx.peer_addr().unwrap(), addr);
+        assert_eq!(ctx.local_addr().unwrap(), stream.0.local_addr().unwrap());
+    }
+}
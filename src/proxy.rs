@@ -0,0 +1,513 @@
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use mio::{EventSet, Token, TryAccept};
+use mio::tcp::TcpStream;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use sendfile::{self, Splice};
+use ::Evented;
+
+// the most bytes buffered in each direction, before reading from the
+// sending side is suspended
+const MAX_BUF: usize = 64 * 1024;
+// how much is read at a time
+const CHUNK: usize = 16 * 1024;
+
+/// A transport that can be proxied with `Tick::proxy`.
+///
+/// Besides reading and writing, it must be able to close just its writing
+/// half, so that EOF from one side can be passed on to the other while
+/// bytes still flow the other way.
+pub trait Duplex: Read + Write + Evented {
+    /// Closes the writing half, so the remote end reads EOF.
+    fn close_write(&mut self) -> io::Result<()>;
+
+    /// The file descriptor to read and write, if there is one.
+    ///
+    /// On Linux, a proxy whose ends both have one moves bytes between them
+    /// with `splice`, rather than copying them through userspace.
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+impl Duplex for TcpStream {
+    fn close_write(&mut self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+/// The two ends of a new proxy.
+pub type Ends = (Box<dyn Duplex>, Box<dyn Duplex>);
+
+/// Accepts transports, pairing each with a new transport to proxy it to.
+pub trait Accept {
+    fn evented(&self) -> &dyn Evented;
+    fn accept(&mut self) -> io::Result<Option<Ends>>;
+}
+
+struct Connector<L, C> {
+    listener: L,
+    connect: C,
+}
+
+#[inline]
+pub fn listener<L, C, B>(listener: L, connect: C) -> Box<dyn Accept>
+where L: TryAccept + Evented + 'static,
+      L::Output: Duplex + 'static,
+      C: FnMut(&L::Output) -> io::Result<B> + 'static,
+      B: Duplex + 'static {
+    Box::new(Connector { listener, connect })
+}
+
+impl<L, C, B> Accept for Connector<L, C>
+where L: TryAccept + Evented,
+      L::Output: Duplex + 'static,
+      C: FnMut(&L::Output) -> io::Result<B>,
+      B: Duplex + 'static {
+    fn evented(&self) -> &dyn Evented {
+        &self.listener
+    }
+
+    fn accept(&mut self) -> io::Result<Option<Ends>> {
+        let stream = match self.listener.accept()? {
+            Some(stream) => stream,
+            None => return Ok(None),
+        };
+        match (self.connect)(&stream) {
+            Ok(out) => Ok(Some((Box::new(stream), Box::new(out)))),
+            Err(e) => {
+                // the accepted stream is dropped, closing it
+                warn!("proxy failed to connect: {}", e);
+                Ok(None)
+            }
+        }
+    }
+}
+
+struct End {
+    io: Box<dyn Duplex>,
+    token: Token,
+    // bytes read from the other end, waiting to be written to this one
+    buf: Buffer,
+    // this end has been read to EOF
+    eof: bool,
+    // this end's writing half has been closed
+    shut: bool,
+    // what this end is registered for, or `None` if its oneshot has fired
+    armed: Option<EventSet>,
+}
+
+// bytes moving in one direction
+enum Buffer {
+    Copy(Vec<u8>),
+    // spliced through a pipe, between the file descriptors of two ends
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    Pipe(Splice, RawFd, RawFd),
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn buffer(from: &dyn Duplex, to: &dyn Duplex) -> Buffer {
+    if let (Some(from), Some(to)) = (from.raw_fd(), to.raw_fd()) {
+        match Splice::new() {
+            Ok(pipe) => return Buffer::Pipe(pipe, from, to),
+            Err(e) => debug!("proxy copying, since creating a pipe failed: {}", e),
+        }
+    }
+    Buffer::Copy(Vec::new())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn buffer(_from: &dyn Duplex, _to: &dyn Duplex) -> Buffer {
+    Buffer::Copy(Vec::new())
+}
+
+impl Buffer {
+    fn is_empty(&self) -> bool {
+        match *self {
+            Buffer::Copy(ref buf) => buf.is_empty(),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Buffer::Pipe(ref pipe, ..) => pipe.buffered() == 0,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        match *self {
+            Buffer::Copy(ref buf) => buf.len() >= MAX_BUF,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Buffer::Pipe(ref pipe, ..) => pipe.is_full(),
+        }
+    }
+
+    // reads once from `from`, returning 0 at EOF
+    fn fill(&mut self, from: &mut dyn Duplex) -> io::Result<usize> {
+        match *self {
+            Buffer::Copy(ref mut buf) => {
+                let mut chunk = [0; CHUNK];
+                let room = ::std::cmp::min(CHUNK, MAX_BUF - buf.len());
+                let n = from.read(&mut chunk[..room])?;
+                buf.extend_from_slice(&chunk[..n]);
+                Ok(n)
+            }
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Buffer::Pipe(ref mut pipe, from, _) => sendfile::fill(pipe, from, MAX_BUF),
+        }
+    }
+
+    // writes once to `to`
+    fn drain(&mut self, to: &mut dyn Duplex) -> io::Result<usize> {
+        match *self {
+            Buffer::Copy(ref mut buf) => {
+                let n = to.write(buf)?;
+                buf.drain(..n);
+                Ok(n)
+            }
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Buffer::Pipe(ref mut pipe, _, to) => sendfile::drain(pipe, to, MAX_BUF),
+        }
+    }
+}
+
+/// Two transports linked in the loop, with bytes moved between them.
+///
+/// The first end lives in the slot of the proxy, and the second in a slot
+/// of its own, pointing back at the first.
+pub struct Proxy {
+    ends: [End; 2],
+}
+
+#[inline]
+pub fn new(a: Box<dyn Duplex>, b: Box<dyn Duplex>) -> Proxy {
+    // each end's buffer holds what the other end sends it
+    let (to_a, to_b) = (buffer(&*b, &*a), buffer(&*a, &*b));
+    let end = |io, buf| End {
+        io,
+        token: Token(0),
+        buf,
+        eof: false,
+        shut: false,
+        armed: None,
+    };
+    Proxy { ends: [end(a, to_a), end(b, to_b)] }
+}
+
+impl Proxy {
+    pub fn set_token(&mut self, end: usize, token: Token) {
+        self.ends[end].token = token;
+    }
+
+    pub fn token(&self, end: usize) -> Token {
+        self.ends[end].token
+    }
+
+    pub fn evented(&self, end: usize) -> &dyn Evented {
+        &*self.ends[end].io
+    }
+
+    /// The readiness `end` should wait on.
+    pub fn events(&self, end: usize) -> EventSet {
+        let this = &self.ends[end];
+        let mut events = EventSet::none();
+        if !this.eof && !self.ends[1 - end].buf.is_full() {
+            events = events | EventSet::readable();
+        }
+        if !this.buf.is_empty() {
+            events = events | EventSet::writable();
+        }
+        events
+    }
+
+    /// Copies bytes for `events` on `end`, returning whether both
+    /// directions are finished.
+    pub fn ready(&mut self, end: usize, events: EventSet) -> io::Result<bool> {
+        trace!("proxy end {} ready '{:?}'", end, events);
+        self.ends[end].armed = None;
+        if events.is_readable() || events.is_hup() || events.is_error() {
+            self.read(end)?;
+        }
+        if events.is_writable() {
+            self.write(end)?;
+        }
+        // pass on EOF, once everything before it has been written
+        for i in 0..2 {
+            if self.ends[1 - i].eof && self.ends[i].buf.is_empty() && !self.ends[i].shut {
+                trace!("proxy closing write half of end {}", i);
+                self.ends[i].shut = true;
+                self.ends[i].io.close_write()?;
+            }
+        }
+        Ok(self.ends.iter().all(|end| end.eof && end.shut))
+    }
+
+    /// The readiness `end` needs registering for, if it isn't already.
+    ///
+    /// An end waiting on nothing is left as it is; if it fires anyway, its
+    /// oneshot is spent, and it stays quiet until there is work for it.
+    pub fn rearm(&mut self, end: usize) -> Option<EventSet> {
+        let events = self.events(end);
+        if events == EventSet::none() || self.ends[end].armed == Some(events) {
+            return None;
+        }
+        self.ends[end].armed = Some(events);
+        Some(events)
+    }
+
+    fn read(&mut self, end: usize) -> io::Result<()> {
+        let (this, other) = self.pair(end);
+        while !this.eof && !other.buf.is_full() {
+            match other.buf.fill(&mut *this.io) {
+                Ok(0) => this.eof = true,
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, end: usize) -> io::Result<()> {
+        let this = &mut self.ends[end];
+        while !this.buf.is_empty() {
+            match this.buf.drain(&mut *this.io) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "proxy end wrote 0 bytes")),
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn pair(&mut self, end: usize) -> (&mut End, &mut End) {
+        let (a, b) = self.ends.split_at_mut(1);
+        if end == 0 {
+            (&mut a[0], &mut b[0])
+        } else {
+            (&mut b[0], &mut a[0])
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::cell::RefCell;
+    use std::cmp;
+    use std::io::{self, Read, Write};
+    use std::net::{self, Shutdown};
+    use std::rc::Rc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use mio::{EventSet, PollOpt, Selector, Token};
+    use mio::tcp::{TcpListener, TcpStream};
+
+    use ::{Interest, Io, Protocol, Tick};
+    use super::{Duplex, MAX_BUF};
+
+    #[derive(Default)]
+    struct Fake {
+        // bytes waiting to be read, then EOF once `eof` is set
+        input: Vec<u8>,
+        eof: bool,
+        written: Vec<u8>,
+        // how many more bytes may be written before blocking
+        room: usize,
+        shut: bool,
+    }
+
+    // a duplex without a file descriptor, so bytes are copied
+    #[derive(Clone, Default)]
+    struct FakeEnd(Rc<RefCell<Fake>>);
+
+    impl Read for FakeEnd {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut fake = self.0.borrow_mut();
+            if fake.input.is_empty() {
+                return if fake.eof { Ok(0) } else { Err(io::ErrorKind::WouldBlock.into()) };
+            }
+            let n = cmp::min(buf.len(), fake.input.len());
+            buf[..n].copy_from_slice(&fake.input[..n]);
+            fake.input.drain(..n);
+            Ok(n)
+        }
+    }
+
+    impl Write for FakeEnd {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut fake = self.0.borrow_mut();
+            assert!(!fake.shut, "written after close_write");
+            if fake.room == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = cmp::min(buf.len(), fake.room);
+            fake.room -= n;
+            fake.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ::Evented for FakeEnd {
+        fn register(&self, _: &mut Selector, _: Token, _: EventSet, _: PollOpt) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn reregister(&self, _: &mut Selector, _: Token, _: EventSet, _: PollOpt) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn deregister(&self, _: &mut Selector) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Duplex for FakeEnd {
+        fn close_write(&mut self) -> io::Result<()> {
+            self.0.borrow_mut().shut = true;
+            Ok(())
+        }
+    }
+
+    fn fakes() -> (super::Proxy, FakeEnd, FakeEnd) {
+        let (a, b) = (FakeEnd::default(), FakeEnd::default());
+        (super::new(Box::new(a.clone()), Box::new(b.clone())), a, b)
+    }
+
+    #[test]
+    fn full_buffers_stop_reading() {
+        let (mut proxy, a, b) = fakes();
+        a.0.borrow_mut().input = vec![7; 3 * MAX_BUF];
+        assert!(!proxy.ready(0, EventSet::readable()).unwrap());
+        assert_eq!(a.0.borrow().input.len(), 2 * MAX_BUF);
+        assert_eq!(proxy.events(0), EventSet::none());
+        assert_eq!(proxy.events(1), EventSet::readable() | EventSet::writable());
+
+        // once the other end takes some, reading resumes
+        b.0.borrow_mut().room = 1000;
+        assert!(!proxy.ready(1, EventSet::writable()).unwrap());
+        assert_eq!(b.0.borrow().written.len(), 1000);
+        assert_eq!(proxy.events(0), EventSet::readable());
+        assert!(!proxy.ready(0, EventSet::readable()).unwrap());
+        assert_eq!(a.0.borrow().input.len(), 2 * MAX_BUF - 1000);
+    }
+
+    #[test]
+    fn eof_is_passed_on_once_written() {
+        let (mut proxy, a, b) = fakes();
+        {
+            let mut a = a.0.borrow_mut();
+            a.input = b"hello".to_vec();
+            a.eof = true;
+        }
+        b.0.borrow_mut().room = 2;
+        assert!(!proxy.ready(0, EventSet::readable()).unwrap());
+        assert!(!proxy.ready(1, EventSet::writable()).unwrap());
+        assert!(!b.0.borrow().shut);
+        assert_eq!(proxy.events(0), EventSet::none());
+
+        b.0.borrow_mut().room = 100;
+        assert!(!proxy.ready(1, EventSet::writable()).unwrap());
+        assert_eq!(b.0.borrow().written, b"hello");
+        assert!(b.0.borrow().shut);
+        // the other direction still flows
+        assert!(!a.0.borrow().shut);
+        assert_eq!(proxy.events(1), EventSet::readable());
+    }
+
+    #[test]
+    fn proxies_finish_once_both_ways_are_closed() {
+        let (mut proxy, a, b) = fakes();
+        a.0.borrow_mut().eof = true;
+        {
+            let mut b = b.0.borrow_mut();
+            b.input = b"bye".to_vec();
+            b.eof = true;
+        }
+        assert!(!proxy.ready(0, EventSet::readable()).unwrap());
+        assert!(b.0.borrow().shut);
+        assert!(!proxy.ready(1, EventSet::readable()).unwrap());
+        assert!(!a.0.borrow().shut);
+
+        a.0.borrow_mut().room = 100;
+        assert!(proxy.ready(0, EventSet::writable()).unwrap());
+        assert_eq!(a.0.borrow().written, b"bye");
+        assert!(a.0.borrow().shut);
+    }
+
+    struct Unused;
+
+    impl Protocol<TcpStream> for Unused {
+        fn on_readable(&mut self, _: &mut Io<TcpStream>) -> Interest {
+            Interest::Remove
+        }
+
+        fn on_writable(&mut self, _: &mut Io<TcpStream>) -> Interest {
+            Interest::Remove
+        }
+
+        fn on_error(&mut self, _: ::Error) {}
+    }
+
+    // a connected pair, one end for the loop, and a blocking one for a test
+    fn pair() -> (TcpStream, net::TcpStream) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let ours = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        let (theirs, _) = listener.accept().unwrap();
+        (ours, theirs)
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn tcp_ends_are_spliced() {
+        let (a, _client) = pair();
+        let (b, _server) = pair();
+        let proxy = super::new(Box::new(a), Box::new(b));
+        for end in &proxy.ends {
+            match end.buf {
+                super::Buffer::Pipe(..) => (),
+                super::Buffer::Copy(_) => panic!("tcp ends weren't spliced"),
+            }
+        }
+    }
+
+    #[test]
+    fn tcp_proxies_relay_both_ways() {
+        let (a, mut client) = pair();
+        let (b, mut server) = pair();
+        let mut tick = Tick::<TcpListener, _>::new(|_| (Unused, Interest::Read));
+        let id = tick.proxy(a, b).unwrap();
+
+        // several times what either direction buffers
+        let data: Vec<u8> = (0..256 * 1024).map(|i| i as u8).collect();
+        let sent = data.clone();
+        let client = thread::spawn(move || {
+            client.write_all(&sent).unwrap();
+            client.shutdown(Shutdown::Write).unwrap();
+            let mut reply = Vec::new();
+            client.read_to_end(&mut reply).unwrap();
+            reply
+        });
+        let server = thread::spawn(move || {
+            let mut got = Vec::new();
+            server.read_to_end(&mut got).unwrap();
+            server.write_all(b"done").unwrap();
+            got
+        });
+        tick.run_until_complete_by(id, Instant::now() + Duration::from_secs(10)).unwrap();
+
+        assert!(server.join().unwrap() == data);
+        assert_eq!(client.join().unwrap(), b"done");
+    }
+}
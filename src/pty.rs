@@ -0,0 +1,362 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{self, Command, ExitStatus};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use libc;
+use mio::{EventSet, PollOpt, Selector, Token};
+use mio::unix::EventedFd;
+
+use ::{Evented, Transport};
+
+/// The master side of a pseudo-terminal, with a command running on the
+/// slave side, as a non-blocking `Transport`.
+///
+/// Reads return the command's terminal output, and writes are its terminal
+/// input. Once the command exits and the slave side is closed, reads fail
+/// with an error (`EIO` on Linux) rather than returning EOF, so protocols
+/// should treat any read error as the end of the session. On a `Tick`
+/// (`Tick::spawn_pty`), the command exiting also ends it on `SIGCHLD`, even
+/// if something it started still holds the slave side open.
+#[derive(Debug)]
+pub struct Pty {
+    master: File,
+    // shared with the loop, which reaps it on SIGCHLD
+    child: Arc<Mutex<process::Child>>,
+}
+
+impl Pty {
+    /// Opens a pty pair of the given size, and spawns `command` as the
+    /// leader of a new session, with the slave side as its controlling
+    /// terminal and its stdin, stdout and stderr. `command` is left with
+    /// inherited stdio.
+    pub fn spawn(command: &mut Command, rows: u16, cols: u16) -> io::Result<Pty> {
+        let (master, slave) = open(rows, cols)?;
+        let stdio = |file: &File| file.try_clone().map(process::Stdio::from);
+        command
+            .stdin(stdio(&slave)?)
+            .stdout(stdio(&slave)?)
+            .stderr(stdio(&slave)?);
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY as _, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = command.spawn();
+        // the command keeps its copies of the slave side until it's given
+        // others, and reads only see the end of the session once every
+        // copy is closed
+        command
+            .stdin(process::Stdio::inherit())
+            .stdout(process::Stdio::inherit())
+            .stderr(process::Stdio::inherit());
+        drop(slave);
+        let child = child?;
+        Ok(Pty { master, child: Arc::new(Mutex::new(child)) })
+    }
+
+    /// Changes the terminal's window size, which sends `SIGWINCH` to the
+    /// command.
+    pub fn resize(&self, rows: u16, cols: u16) -> io::Result<()> {
+        let size = winsize(rows, cols);
+        if unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCSWINSZ as _, &size) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// The terminal's window size, as `(rows, cols)`.
+    pub fn size(&self) -> io::Result<(u16, u16)> {
+        let mut size = winsize(0, 0);
+        if unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCGWINSZ as _, &mut size) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((size.ws_row, size.ws_col))
+    }
+
+    /// The process id of the command.
+    pub fn id(&self) -> u32 {
+        lock(&self.child).id()
+    }
+
+    /// Checks whether the command has exited, without blocking.
+    pub fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        lock(&self.child).try_wait()
+    }
+}
+
+/// The command's process, for the loop to reap.
+pub fn child(pty: &Pty) -> Arc<Mutex<process::Child>> {
+    pty.child.clone()
+}
+
+pub fn lock(child: &Mutex<process::Child>) -> MutexGuard<'_, process::Child> {
+    child.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn winsize(rows: u16, cols: u16) -> libc::winsize {
+    libc::winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
+
+// both ends are opened close-on-exec, so that a command spawned on another
+// thread can't inherit them
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn open(rows: u16, cols: u16) -> io::Result<(File, File)> {
+    unsafe {
+        let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC | libc::O_NONBLOCK);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let master = File::from_raw_fd(fd);
+        let mut name = [0 as libc::c_char; 128];
+        if libc::grantpt(fd) < 0 || libc::unlockpt(fd) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let e = libc::ptsname_r(fd, name.as_mut_ptr(), name.len());
+        if e != 0 {
+            return Err(io::Error::from_raw_os_error(e));
+        }
+        let slave = libc::open(name.as_ptr(), libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC);
+        if slave < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let slave = File::from_raw_fd(slave);
+        let size = winsize(rows, cols);
+        if libc::ioctl(fd, libc::TIOCSWINSZ as _, &size) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((master, slave))
+    }
+}
+
+// openpty can't open close-on-exec, so a command spawned on another thread
+// before the fcntl can inherit the fds
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn open(rows: u16, cols: u16) -> io::Result<(File, File)> {
+    let mut master: RawFd = -1;
+    let mut slave: RawFd = -1;
+    let size = winsize(rows, cols);
+    if unsafe { libc::openpty(&mut master, &mut slave, ::std::ptr::null_mut(), ::std::ptr::null(), &size) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+    unsafe {
+        for &fd in &[master.as_raw_fd(), slave.as_raw_fd()] {
+            if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        let fd = master.as_raw_fd();
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok((master, slave))
+}
+
+// the command is killed, if still running, and reaped; when a loop is
+// watching it, that's left to the loop's SIGCHLD rather than blocking here
+impl Drop for Pty {
+    fn drop(&mut self) {
+        let watched = Arc::strong_count(&self.child) > 1;
+        let mut child = lock(&self.child);
+        if let Ok(None) = child.try_wait() {
+            let _ = child.kill();
+            if !watched {
+                let _ = child.wait();
+            }
+        }
+    }
+}
+
+impl Read for Pty {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.master.read(buf)
+    }
+}
+
+impl Write for Pty {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.master.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        self.master.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for Pty {
+    fn as_raw_fd(&self) -> RawFd {
+        self.master.as_raw_fd()
+    }
+}
+
+impl Transport for Pty {}
+
+impl Evented for Pty {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.as_raw_fd()).register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.as_raw_fd()).reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        EventedFd(&self.as_raw_fd()).deregister(selector)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::io::{self, Read, Write};
+    use std::process::Command;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    use mio::{self, EventSet, PollOpt, Selector, Token, TryAccept};
+
+    use signal::test_lock;
+    use super::Pty;
+    use ::{Interest, Io, Protocol, Tick, Transfer};
+
+    // ptys aren't accepted, only spawned
+    struct NoListener;
+
+    impl TryAccept for NoListener {
+        type Output = Pty;
+
+        fn accept(&self) -> io::Result<Option<Pty>> {
+            Ok(None)
+        }
+    }
+
+    impl mio::Evented for NoListener {
+        fn register(&self, _: &mut Selector, _: Token, _: EventSet, _: PollOpt) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn reregister(&self, _: &mut Selector, _: Token, _: EventSet, _: PollOpt) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn deregister(&self, _: &mut Selector) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // types a line and an end of file, and keeps what the terminal shows
+    struct Session {
+        input: &'static [u8],
+        output: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Protocol<Pty> for Session {
+        fn on_readable(&mut self, transport: &mut Io<Pty>) -> Interest {
+            let mut buf = [0; 256];
+            loop {
+                match transport.read(&mut buf) {
+                    Ok(0) => return Interest::Remove,
+                    Ok(n) => self.output.borrow_mut().extend_from_slice(&buf[..n]),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Interest::Read,
+                    // EIO, once the slave side is closed
+                    Err(_) => return Interest::Remove,
+                }
+            }
+        }
+
+        fn on_writable(&mut self, transport: &mut Io<Pty>) -> Interest {
+            transport.write_all(self.input).unwrap();
+            Interest::Read
+        }
+
+        fn on_error(&mut self, _: ::Error) {}
+    }
+
+    // reads a byte per call, counting them, until the session ends
+    struct Trickle(Rc<Cell<usize>>);
+
+    impl Protocol<Pty> for Trickle {
+        fn on_readable(&mut self, transport: &mut Io<Pty>) -> Interest {
+            match transport.read(&mut [0]) {
+                Ok(0) => Interest::Remove,
+                Ok(_) => {
+                    self.0.set(self.0.get() + 1);
+                    Interest::Read
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Interest::Read,
+                Err(_) => Interest::Remove,
+            }
+        }
+
+        fn on_writable(&mut self, _: &mut Io<Pty>) -> Interest {
+            Interest::Read
+        }
+
+        fn on_error(&mut self, _: ::Error) {}
+    }
+
+    fn run(command: &mut Command) -> (String, Duration) {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let out = output.clone();
+        let mut tick: Tick<NoListener, _> = Tick::new(move |_: Transfer| {
+            (Session { input: b"hello\n\x04", output: out.clone() }, Interest::Write)
+        });
+        let id = tick.spawn_pty(command, 24, 80).unwrap();
+        let start = Instant::now();
+        tick.run_until_complete_by(id, start + Duration::from_secs(5)).unwrap();
+        let output = String::from_utf8_lossy(&output.borrow()).into_owned();
+        (output, start.elapsed())
+    }
+
+    #[test]
+    fn commands_run_on_the_terminal() {
+        let _lock = test_lock();
+        let (output, _) = run(&mut Command::new("cat"));
+        // echoed by the terminal, then written back by cat
+        assert_eq!(output, "hello\r\nhello\r\n");
+    }
+
+    #[test]
+    fn exiting_ends_the_session_while_the_terminal_is_open() {
+        let _lock = test_lock();
+        // the sleep keeps the slave side open, so there's no EIO until it
+        // ends, but the exit of cat is seen on SIGCHLD
+        let mut command = Command::new("sh");
+        command.args(["-c", "(trap '' HUP; sleep 3) & exec cat"]);
+        let (output, elapsed) = run(&mut command);
+        assert_eq!(output, "hello\r\nhello\r\n");
+        assert!(elapsed < Duration::from_secs(2), "took {:?}", elapsed);
+    }
+
+    #[test]
+    fn output_left_at_the_hangup_is_all_read() {
+        let _lock = test_lock();
+        let count = Rc::new(Cell::new(0));
+        let counter = count.clone();
+        let mut tick: Tick<NoListener, _> = Tick::new(move |_: Transfer| {
+            (Trickle(counter.clone()), Interest::Read)
+        });
+        let mut command = Command::new("head");
+        command.args(["-c", "3000", "/dev/zero"]);
+        let id = tick.spawn_pty(&mut command, 24, 80).unwrap();
+        tick.run_until_complete_by(id, Instant::now() + Duration::from_secs(5)).unwrap();
+        assert_eq!(count.get(), 3000);
+    }
+}
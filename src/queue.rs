@@ -0,0 +1,227 @@
+use std::collections::VecDeque;
+use std::io::{self, IoSlice, Write};
+
+// how many buffers are handed to a single vectored write, well under any
+// platform's IOV_MAX
+pub const MAX_SLICES: usize = 64;
+
+/// A queue of buffers waiting to be written to a transport.
+///
+/// Rather than copying a header and body into one buffer, or writing each
+/// separately, a `Protocol` can push both here and flush the queue from
+/// `on_writable`. Flushing writes as many buffers as it can with a single
+/// vectored write, and keeps track of buffers only partially written.
+///
+/// ```
+/// # use tick::WriteQueue;
+/// let mut queue = WriteQueue::new();
+/// queue.push(&b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n"[..]);
+/// queue.push(&b"hello"[..]);
+///
+/// let mut out = Vec::new();
+/// queue.write_to(&mut out).unwrap();
+/// assert!(queue.is_empty());
+/// assert!(out.ends_with(b"\r\n\r\nhello"));
+/// ```
+#[derive(Debug, Default)]
+pub struct WriteQueue {
+    bufs: VecDeque<Vec<u8>>,
+    // how much of the front buffer has already been written
+    pos: usize,
+    len: usize,
+}
+
+impl WriteQueue {
+    /// Creates an empty queue.
+    pub fn new() -> WriteQueue {
+        WriteQueue::default()
+    }
+
+    /// Adds `buf` to the back of the queue.
+    pub fn push<B: Into<Vec<u8>>>(&mut self, buf: B) {
+        let buf = buf.into();
+        if !buf.is_empty() {
+            self.len += buf.len();
+            self.bufs.push_back(buf);
+        }
+    }
+
+    /// The number of bytes waiting to be written.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether everything queued has been written.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Writes queued buffers to `transport` with `write_vectored`, until
+    /// the queue is empty or the transport can't take any more.
+    ///
+    /// Returns an error of kind `WouldBlock` if the transport filled up
+    /// first, in which case the protocol should wait until it is writable.
+    /// How many buffers a single call writes depends on the transport's
+    /// `write_vectored`; see `writev_to` for transports with a file
+    /// descriptor.
+    pub fn write_to<W: Write>(&mut self, transport: &mut W) -> io::Result<()> {
+        self.drain(|slices| transport.write_vectored(slices))
+    }
+
+    /// Writes queued buffers to `io` with `writev`, until the queue is empty
+    /// or the transport can't take any more.
+    ///
+    /// Works the same as `write_to`, but doesn't rely on the transport
+    /// implementing `write_vectored`.
+    #[cfg(unix)]
+    pub fn writev_to<T>(&mut self, io: &mut ::Io<T>) -> io::Result<()>
+    where T: ::Transport + ::std::os::unix::io::AsRawFd {
+        self.drain(|slices| io.writev(slices))
+    }
+
+    fn drain<F>(&mut self, mut write: F) -> io::Result<()>
+    where F: FnMut(&[IoSlice]) -> io::Result<usize> {
+        while !self.is_empty() {
+            let n = {
+                let mut slices = Vec::with_capacity(::std::cmp::min(self.bufs.len(), MAX_SLICES));
+                for (i, buf) in self.bufs.iter().take(MAX_SLICES).enumerate() {
+                    let buf = if i == 0 { &buf[self.pos..] } else { &buf[..] };
+                    slices.push(IoSlice::new(buf));
+                }
+                write(&slices)?
+            };
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "transport wrote 0 bytes"));
+            }
+            self.advance(n);
+        }
+        Ok(())
+    }
+
+    fn advance(&mut self, mut n: usize) {
+        self.len -= n;
+        while n > 0 {
+            let remaining = self.bufs[0].len() - self.pos;
+            if n < remaining {
+                self.pos += n;
+                return;
+            }
+            n -= remaining;
+            self.pos = 0;
+            self.bufs.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+    use std::io::{self, IoSlice, Write};
+
+    use super::{WriteQueue, MAX_SLICES};
+
+    // takes up to `per_call` bytes per write, across slices, and blocks once
+    // `budget` bytes have been taken
+    struct Chunky {
+        out: Vec<u8>,
+        per_call: usize,
+        budget: usize,
+        calls: usize,
+    }
+
+    impl Chunky {
+        fn new(per_call: usize, budget: usize) -> Chunky {
+            Chunky { out: Vec::new(), per_call, budget, calls: 0 }
+        }
+    }
+
+    impl Write for Chunky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+            if self.budget == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.calls += 1;
+            let mut left = cmp::min(self.per_call, self.budget);
+            let mut written = 0;
+            for buf in bufs {
+                let n = cmp::min(left, buf.len());
+                self.out.extend_from_slice(&buf[..n]);
+                left -= n;
+                written += n;
+                if left == 0 {
+                    break;
+                }
+            }
+            self.budget -= written;
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn partial_writes_resume_mid_buffer() {
+        let mut queue = WriteQueue::new();
+        queue.push(&b"abc"[..]);
+        queue.push(&b"defgh"[..]);
+        queue.push(&b"ij"[..]);
+        let mut out = Chunky::new(3, 5);
+
+        let err = queue.write_to(&mut out).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(out.out, b"abcde");
+        assert_eq!(queue.len(), 5);
+
+        out.budget = 100;
+        queue.write_to(&mut out).unwrap();
+        assert_eq!(out.out, b"abcdefghij");
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn writes_span_buffers_in_one_call() {
+        let mut queue = WriteQueue::new();
+        for buf in &[&b"ab"[..], b"cd", b"ef"] {
+            queue.push(*buf);
+        }
+        let mut out = Chunky::new(5, 100);
+        queue.write_to(&mut out).unwrap();
+        assert_eq!(out.out, b"abcdef");
+        assert_eq!(out.calls, 2);
+    }
+
+    #[test]
+    fn more_buffers_than_a_single_write_takes() {
+        let mut queue = WriteQueue::new();
+        for i in 0..MAX_SLICES * 2 + 1 {
+            queue.push(vec![i as u8]);
+        }
+        let mut out = Chunky::new(usize::MAX, usize::MAX);
+        queue.write_to(&mut out).unwrap();
+        assert_eq!(out.out, (0..MAX_SLICES * 2 + 1).map(|i| i as u8).collect::<Vec<_>>());
+        assert_eq!(out.calls, 3);
+    }
+
+    #[test]
+    fn empty_buffers_are_skipped() {
+        let mut queue = WriteQueue::new();
+        queue.push(Vec::new());
+        assert!(queue.is_empty());
+        queue.write_to(&mut Chunky::new(1, 0)).unwrap();
+    }
+
+    #[test]
+    fn writing_nothing_is_an_error() {
+        let mut queue = WriteQueue::new();
+        queue.push(&b"abc"[..]);
+        let err = queue.write_to(&mut Chunky::new(0, 100)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+        assert_eq!(queue.len(), 3);
+    }
+}
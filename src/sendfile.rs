@@ -0,0 +1,310 @@
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use libc;
+
+// the most handed to a single sendfile or splice
+const CHUNK: u64 = 1 << 30;
+
+/// A region of a file queued with `Io::send_file`.
+#[derive(Debug)]
+pub struct Region {
+    file: File,
+    out: RawFd,
+    offset: u64,
+    remaining: u64,
+}
+
+#[inline]
+pub fn region(file: File, out: RawFd, offset: u64, len: u64) -> Region {
+    Region {
+        file,
+        out,
+        offset,
+        remaining: len,
+    }
+}
+
+impl Region {
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// Sends as much of the region as the transport will take, up to
+    /// `limit` bytes, with a single call.
+    pub fn send(&mut self, limit: usize) -> io::Result<usize> {
+        let len = cmp::min(cmp::min(self.remaining, CHUNK), limit as u64);
+        let n = self.send_chunk(len as usize)?;
+        if n == 0 && self.remaining > 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file ended before the queued region"));
+        }
+        self.offset += n as u64;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn send_chunk(&mut self, len: usize) -> io::Result<usize> {
+        let mut offset = self.offset as libc::off_t;
+        let n = unsafe { libc::sendfile(self.out, self.file.as_raw_fd(), &mut offset, len) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    // without sendfile, the chunk is read into userspace, and only what was
+    // written is counted as sent, so the rest is read again next time
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn send_chunk(&mut self, len: usize) -> io::Result<usize> {
+        use std::os::unix::fs::FileExt;
+        let mut buf = vec![0u8; cmp::min(len, 64 * 1024)];
+        let read = self.file.read_at(&mut buf, self.offset)?;
+        if read == 0 {
+            return Ok(0);
+        }
+        let n = unsafe { libc::write(self.out, buf.as_ptr() as *const libc::c_void, read) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+}
+
+/// Moves bytes from one stream to another through a pipe with `splice`,
+/// without copying them into userspace.
+///
+/// The reading side fills the pipe with `read_from`, and the writing side
+/// empties it with `write_to`. Since the two sides are usually different
+/// streams, a `Splice` is typically shared between their protocols, with
+/// `Transfer`s waking each side once the other has made progress.
+///
+/// `Tick::proxy` moves bytes this way by itself, whenever both of its ends
+/// have a file descriptor.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[derive(Debug)]
+pub struct Splice {
+    reader: File,
+    writer: File,
+    // bytes sitting in the pipe
+    buffered: usize,
+    // the pipe ran out of room before reaching PIPE_CAPACITY, which happens
+    // when small reads each take a page of it
+    stalled: bool,
+}
+
+// the default capacity of a pipe on Linux
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Splice {
+    /// Creates the pipe used to move bytes between streams.
+    pub fn new() -> io::Result<Splice> {
+        use std::os::unix::io::FromRawFd;
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Splice {
+            reader: unsafe { File::from_raw_fd(fds[0]) },
+            writer: unsafe { File::from_raw_fd(fds[1]) },
+            buffered: 0,
+            stalled: false,
+        })
+    }
+
+    /// The number of bytes read but not yet written.
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    /// Whether the pipe has no room for more bytes, until some are written.
+    pub fn is_full(&self) -> bool {
+        self.stalled || self.buffered >= PIPE_CAPACITY
+    }
+
+    /// Moves as many bytes from `from` into the pipe as both will take.
+    ///
+    /// Returns `Ok(0)` once `from` reaches EOF, and an error of kind
+    /// `WouldBlock` if it has nothing to read, or the pipe is full.
+    pub fn read_from<T>(&mut self, from: &mut ::Io<T>) -> io::Result<usize>
+    where T: ::Transport + AsRawFd {
+        if self.is_full() {
+            return Err(full());
+        }
+        let room = PIPE_CAPACITY - self.buffered;
+        let room = ::transport::allow(from, ::EventSet::readable(), room);
+        if room == 0 {
+            return Err(::transport::throttled_error());
+        }
+        let result = fill(self, from.as_raw_fd(), room);
+        if self.stalled {
+            // it's the pipe that's full, so `from` keeps its readiness
+            return result;
+        }
+        ::transport::counted_read(from, result)
+    }
+
+    /// Moves as many bytes from the pipe into `to` as it will take.
+    ///
+    /// Returns `Ok(0)` if the pipe is empty, and an error of kind
+    /// `WouldBlock` if `to` can't take any more.
+    pub fn write_to<T>(&mut self, to: &mut ::Io<T>) -> io::Result<usize>
+    where T: ::Transport + AsRawFd {
+        if self.buffered == 0 {
+            return Ok(0);
+        }
+        let len = ::transport::allow(to, ::EventSet::writable(), self.buffered);
+        if len == 0 {
+            return Err(::transport::throttled_error());
+        }
+        let result = drain(self, to.as_raw_fd(), len);
+        ::transport::counted_write(to, result)
+    }
+}
+
+/// Splices up to `len` bytes from `from` into the pipe.
+///
+/// Returns `Ok(0)` once `from` reaches EOF, and an error of kind
+/// `WouldBlock` if it has nothing to read, or the pipe is full.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn fill(pipe: &mut Splice, from: RawFd, len: usize) -> io::Result<usize> {
+    let len = cmp::min(len, PIPE_CAPACITY.saturating_sub(pipe.buffered));
+    if pipe.is_full() || len == 0 {
+        return Err(full());
+    }
+    match splice(from, pipe.writer.as_raw_fd(), len) {
+        Ok(n) => {
+            pipe.buffered += n;
+            Ok(n)
+        }
+        Err(e) => {
+            // splice can't tell an empty source from a full pipe, so one
+            // holding anything counts as full until some is written, rather
+            // than spinning on a source that stays readable
+            if e.kind() == io::ErrorKind::WouldBlock && pipe.buffered > 0 {
+                pipe.stalled = true;
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Splices up to `len` bytes from the pipe into `to`.
+///
+/// Returns `Ok(0)` if the pipe is empty, and an error of kind `WouldBlock`
+/// if `to` can't take any more.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn drain(pipe: &mut Splice, to: RawFd, len: usize) -> io::Result<usize> {
+    let len = cmp::min(len, pipe.buffered);
+    if len == 0 {
+        return Ok(0);
+    }
+    let n = splice(pipe.reader.as_raw_fd(), to, len)?;
+    pipe.buffered -= n;
+    if n > 0 {
+        pipe.stalled = false;
+    }
+    Ok(n)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn full() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "splice pipe is full")
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let n = unsafe {
+        libc::splice(
+            from,
+            ::std::ptr::null_mut(),
+            to,
+            ::std::ptr::null_mut(),
+            cmp::min(len as u64, CHUNK) as usize,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+mod tests {
+    use std::io::{self, Read, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+    use std::os::unix::net;
+
+    use mio::EventSet;
+    use mio::unix::UnixStream;
+
+    use super::{drain, fill, Splice};
+
+    fn pair() -> (net::UnixStream, net::UnixStream) {
+        let (a, b) = net::UnixStream::pair().unwrap();
+        a.set_nonblocking(true).unwrap();
+        b.set_nonblocking(true).unwrap();
+        (a, b)
+    }
+
+    #[test]
+    fn bytes_move_through_the_pipe() {
+        let (mut from, source) = pair();
+        let (sink, mut to) = pair();
+        let mut pipe = Splice::new().unwrap();
+        from.write_all(b"spliced").unwrap();
+
+        assert_eq!(fill(&mut pipe, source.as_raw_fd(), 1024).unwrap(), 7);
+        assert_eq!(pipe.buffered(), 7);
+        assert_eq!(drain(&mut pipe, sink.as_raw_fd(), 1024).unwrap(), 7);
+        assert_eq!(pipe.buffered(), 0);
+        let mut buf = [0; 16];
+        assert_eq!(to.read(&mut buf).unwrap(), 7);
+        assert_eq!(&buf[..7], b"spliced");
+
+        drop(from);
+        assert_eq!(fill(&mut pipe, source.as_raw_fd(), 1024).unwrap(), 0);
+    }
+
+    #[test]
+    fn a_blocked_fill_counts_as_full_until_drained() {
+        let (mut from, source) = pair();
+        let (sink, _to) = pair();
+        let mut pipe = Splice::new().unwrap();
+        from.write_all(b"abc").unwrap();
+        fill(&mut pipe, source.as_raw_fd(), 1024).unwrap();
+        assert!(!pipe.is_full());
+
+        let err = fill(&mut pipe, source.as_raw_fd(), 1024).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(pipe.is_full());
+
+        drain(&mut pipe, sink.as_raw_fd(), 1).unwrap();
+        assert!(!pipe.is_full());
+        assert_eq!(pipe.buffered(), 2);
+    }
+
+    #[test]
+    fn only_an_empty_source_counts_as_blocked() {
+        let (mut from, source) = pair();
+        let (sink, _to) = pair();
+        let mut source = ::Io::new(unsafe { UnixStream::from_raw_fd(source.into_raw_fd()) });
+        let mut pipe = Splice::new().unwrap();
+        from.write_all(b"abc").unwrap();
+        pipe.read_from(&mut source).unwrap();
+
+        // a stalled pipe is full, and says nothing of the source
+        assert!(pipe.read_from(&mut source).is_err());
+        assert!(pipe.is_full());
+        assert_eq!(::transport::take_blocked(&mut source), EventSet::none());
+
+        drain(&mut pipe, sink.as_raw_fd(), 1024).unwrap();
+        assert!(pipe.read_from(&mut source).is_err());
+        assert_eq!(::transport::take_blocked(&mut source), EventSet::readable());
+    }
+}
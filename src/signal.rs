@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use libc;
+use mio::unix::{self, PipeReader, PipeWriter};
+
+// the write end of the self-pipe, for the signal handler to wake the loop
+static WRITE_FD: AtomicI32 = AtomicI32::new(-1);
+
+/// A Unix signal that a `Tick` can handle on the loop thread.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Signal {
+    /// `SIGHUP`, commonly used to ask for a reload.
+    Hangup,
+    /// `SIGINT`, sent by Ctrl-C.
+    Interrupt,
+    /// `SIGQUIT`.
+    Quit,
+    /// `SIGTERM`, commonly used to ask for a graceful shutdown.
+    Terminate,
+    /// `SIGUSR1`.
+    User1,
+    /// `SIGUSR2`.
+    User2,
+    /// `SIGCHLD`, sent when a child process exits.
+    Child,
+    /// `SIGWINCH`, sent when the terminal window size changes.
+    WindowChange,
+}
+
+impl Signal {
+    /// The raw signal number.
+    pub fn number(&self) -> i32 {
+        match *self {
+            Signal::Hangup => libc::SIGHUP,
+            Signal::Interrupt => libc::SIGINT,
+            Signal::Quit => libc::SIGQUIT,
+            Signal::Terminate => libc::SIGTERM,
+            Signal::User1 => libc::SIGUSR1,
+            Signal::User2 => libc::SIGUSR2,
+            Signal::Child => libc::SIGCHLD,
+            Signal::WindowChange => libc::SIGWINCH,
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "emscripten"))]
+use libc::__errno_location as errno_location;
+#[cfg(any(target_os = "android", target_os = "netbsd", target_os = "openbsd"))]
+use libc::__errno as errno_location;
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+use libc::__error as errno_location;
+
+// elsewhere, errno isn't reachable from here, and is left to the write
+#[cfg(not(any(target_os = "linux", target_os = "emscripten", target_os = "android",
+              target_os = "netbsd", target_os = "openbsd", target_os = "macos",
+              target_os = "ios", target_os = "freebsd")))]
+unsafe fn errno_location() -> *mut libc::c_int {
+    ::std::ptr::null_mut()
+}
+
+extern "C" fn on_signal(signum: libc::c_int) {
+    // the interrupted code may be about to read errno, which write can change
+    let errno = unsafe { errno_location() };
+    let saved = if errno.is_null() { 0 } else { unsafe { *errno } };
+    let fd = WRITE_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        let byte = signum as u8;
+        // if the pipe is full, the loop already has a wakeup coming
+        unsafe {
+            libc::write(fd, &byte as *const u8 as *const libc::c_void, 1);
+        }
+    }
+    if !errno.is_null() {
+        unsafe { *errno = saved };
+    }
+}
+
+/// The self-pipe signal handlers write to, and the handlers to call on the
+/// loop thread once it is readable.
+pub struct Signals {
+    reader: PipeReader,
+    writer: PipeWriter,
+    handlers: HashMap<libc::c_int, Handler>,
+}
+
+struct Handler {
+    callback: Box<dyn FnMut()>,
+    // the disposition to restore once dropped
+    previous: libc::sigaction,
+}
+
+impl Signals {
+    /// Creates the self-pipe. Only one `Signals` may exist at a time, since
+    /// signal dispositions are process wide.
+    pub fn new() -> io::Result<Signals> {
+        let (reader, writer) = unix::pipe()?;
+        if WRITE_FD.compare_exchange(-1, writer.as_raw_fd(), Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "signals are already handled by another Tick"));
+        }
+        Ok(Signals {
+            reader,
+            writer,
+            handlers: HashMap::new(),
+        })
+    }
+
+    pub fn reader(&self) -> &PipeReader {
+        &self.reader
+    }
+
+    /// Whether a handler has been set for `signal`.
+    pub fn handles(&self, signal: Signal) -> bool {
+        self.handlers.contains_key(&signal.number())
+    }
+
+    /// Sets the handler for `signal`, installing the signal handler if this
+    /// is the first one.
+    pub fn handle(&mut self, signal: Signal, handler: Box<dyn FnMut()>) -> io::Result<()> {
+        let signum = signal.number();
+        if let Some(entry) = self.handlers.get_mut(&signum) {
+            entry.callback = handler;
+            return Ok(());
+        }
+        let previous = unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            let mut old: libc::sigaction = mem::zeroed();
+            if libc::sigaction(signum, &action, &mut old) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            old
+        };
+        debug!("handling {:?}", signal);
+        self.handlers.insert(signum, Handler {
+            callback: handler,
+            previous,
+        });
+        Ok(())
+    }
+
+    /// Drains the self-pipe, calling the handler for every signal received.
+    ///
+    /// Returns whether `SIGCHLD` was among them, so children can be reaped.
+    pub fn dispatch(&mut self) -> bool {
+        let mut buf = [0u8; 64];
+        let mut child = false;
+        loop {
+            match self.reader.read(&mut buf) {
+                Ok(0) => return child,
+                Ok(n) => {
+                    for &signum in &buf[..n] {
+                        debug!("< Signal {}", signum);
+                        child |= signum as libc::c_int == libc::SIGCHLD;
+                        match self.handlers.get_mut(&(signum as libc::c_int)) {
+                            Some(entry) => (entry.callback)(),
+                            None => warn!("no handler for signal {}", signum),
+                        }
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return child,
+                Err(e) => {
+                    error!("failed to read signals: {}", e);
+                    return child;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Signals {
+    fn drop(&mut self) {
+        for (&signum, entry) in &self.handlers {
+            unsafe {
+                libc::sigaction(signum, &entry.previous, ::std::ptr::null_mut());
+            }
+        }
+        let _ = WRITE_FD.compare_exchange(self.writer.as_raw_fd(), -1, Ordering::SeqCst, Ordering::SeqCst);
+    }
+}
+
+// dispositions are process wide, so tests using them, or spawning
+// children, take turns
+#[cfg(test)]
+pub fn test_lock() -> ::std::sync::MutexGuard<'static, ()> {
+    static SIGNALS: ::std::sync::Mutex<()> = ::std::sync::Mutex::new(());
+    SIGNALS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::mem;
+    use std::process::{Command, ExitStatus};
+    use std::ptr;
+    use std::rc::Rc;
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
+
+    use libc;
+
+    use super::{errno_location, on_signal, test_lock, WRITE_FD};
+    use ::{Interest, Io, MockListener, MockTransport, Process, Protocol, Tick};
+
+    fn handler(signum: libc::c_int) -> libc::sighandler_t {
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            assert_eq!(libc::sigaction(signum, ptr::null(), &mut action), 0);
+            action.sa_sigaction
+        }
+    }
+
+    fn on_signal_handler() -> libc::sighandler_t {
+        on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t
+    }
+
+    struct Idle;
+
+    impl Protocol<MockTransport> for Idle {
+        fn on_readable(&mut self, _: &mut Io<MockTransport>) -> Interest {
+            Interest::Read
+        }
+
+        fn on_writable(&mut self, _: &mut Io<MockTransport>) -> Interest {
+            Interest::Read
+        }
+
+        fn on_error(&mut self, _: ::Error) {}
+    }
+
+    struct Exit(Rc<Cell<bool>>);
+
+    impl Process for Exit {
+        fn on_exit(&mut self, _: ExitStatus) {
+            self.0.set(true);
+        }
+    }
+
+    #[test]
+    fn handler_leaves_errno_alone() {
+        let _lock = test_lock();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        // writing to the read end fails, setting errno to EBADF
+        WRITE_FD.compare_exchange(-1, fds[0], Ordering::SeqCst, Ordering::SeqCst).unwrap();
+        unsafe { *errno_location() = libc::EINTR };
+        on_signal(libc::SIGUSR1);
+        let errno = unsafe { *errno_location() };
+        WRITE_FD.store(-1, Ordering::SeqCst);
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+        assert_eq!(errno, libc::EINTR);
+    }
+
+    #[test]
+    fn spawn_only_handles_child_exits() {
+        let _lock = test_lock();
+        let mut tick = Tick::<MockListener, _>::new(|_| (Idle, Interest::Read));
+        let exited = Rc::new(Cell::new(false));
+        let id = tick.spawn(&mut Command::new("true"), Exit(exited.clone())).unwrap();
+        assert_eq!(handler(libc::SIGCHLD), on_signal_handler());
+        assert_eq!(handler(libc::SIGINT), libc::SIG_DFL);
+        assert_eq!(handler(libc::SIGTERM), libc::SIG_DFL);
+
+        tick.run_until_complete_by(id, Instant::now() + Duration::from_secs(5)).unwrap();
+        assert!(exited.get());
+
+        tick.handle_signals().unwrap();
+        assert_eq!(handler(libc::SIGINT), on_signal_handler());
+        assert_eq!(handler(libc::SIGTERM), on_signal_handler());
+        drop(tick);
+        assert_eq!(handler(libc::SIGINT), libc::SIG_DFL);
+        assert_eq!(handler(libc::SIGCHLD), libc::SIG_DFL);
+    }
+}
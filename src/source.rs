@@ -0,0 +1,34 @@
+use mio::EventSet;
+
+use ::{Evented, Interest};
+
+/// An `Evented` added with `Tick::register`, along with its handler.
+pub trait Source {
+    fn evented(&self) -> &dyn Evented;
+    fn ready(&mut self, events: EventSet) -> Interest;
+}
+
+struct Registered<E, H> {
+    evented: E,
+    handler: H,
+}
+
+#[inline]
+pub fn new<E, H>(evented: E, handler: H) -> Box<dyn Source>
+where E: Evented + 'static, H: FnMut(&mut E, EventSet) -> Interest + 'static {
+    Box::new(Registered {
+        evented,
+        handler,
+    })
+}
+
+impl<E, H> Source for Registered<E, H>
+where E: Evented, H: FnMut(&mut E, EventSet) -> Interest {
+    fn evented(&self) -> &dyn Evented {
+        &self.evented
+    }
+
+    fn ready(&mut self, events: EventSet) -> Interest {
+        (self.handler)(&mut self.evented, events)
+    }
+}
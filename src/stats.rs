@@ -0,0 +1,40 @@
+use ::Id;
+
+/// A snapshot of what a `Tick` has been up to.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    /// Streams accepted from listeners and added to the loop.
+    pub accepted: u64,
+    /// Streams accepted from listeners, but dropped since they couldn't be
+    /// added to the loop.
+    pub rejected: u64,
+    /// Streams currently in the loop.
+    pub active: usize,
+    /// Streams that have been removed from the loop.
+    pub removed: u64,
+    /// Readiness events dispatched to listeners and streams.
+    pub events: u64,
+    /// Messages handled from `Notify` and `Transfer`s.
+    pub notifications: u64,
+    /// Bytes read across all streams, including removed ones.
+    pub bytes_read: u64,
+    /// Bytes written across all streams, including removed ones.
+    pub bytes_written: u64,
+    /// Occupied slots in the transport slab, listeners included.
+    pub slots: usize,
+    /// The current capacity of the transport slab, which grows on demand.
+    pub capacity: usize,
+    /// Per stream counters, for every stream currently in the loop.
+    pub streams: Vec<StreamStats>,
+}
+
+/// Counters for a single stream.
+#[derive(Clone, Copy, Debug)]
+pub struct StreamStats {
+    /// The `Id` of the stream.
+    pub id: Id,
+    /// Bytes read from the stream's transport.
+    pub bytes_read: u64,
+    /// Bytes written to the stream's transport.
+    pub bytes_written: u64,
+}
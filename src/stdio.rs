@@ -0,0 +1,202 @@
+use std::cell::Cell;
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::os::unix::io::RawFd;
+
+use libc;
+use mio::{EventSet, PollOpt, Selector, Token};
+use mio::unix::EventedFd;
+
+use ::{Evented, Transport};
+use queue::MAX_SLICES;
+
+const STDIN: RawFd = 0;
+const STDOUT: RawFd = 1;
+
+/// The process's stdin and stdout as a single, non-blocking `Transport`.
+///
+/// Reads come from stdin, writes go to stdout, and both are registered
+/// with the loop under the same token, so a terminal can be added with
+/// `Tick::stream` like any socket. Both must be pipes, sockets or ttys;
+/// regular files can't be waited on.
+///
+/// Once stdin reaches EOF, it is no longer waited on, since a closed pipe
+/// or terminal would otherwise keep reporting a hangup.
+///
+/// Non-blocking mode is shared with every process using the same terminal
+/// or pipe, so the original flags are restored when a `Stdio` is dropped.
+#[derive(Debug)]
+pub struct Stdio {
+    // the flags of stdin and stdout before they were made non-blocking
+    flags: [libc::c_int; 2],
+    stdin_eof: bool,
+    stdin_registered: Cell<bool>,
+}
+
+impl Stdio {
+    /// Puts stdin and stdout in non-blocking mode.
+    pub fn new() -> io::Result<Stdio> {
+        // a terminal's stdin and stdout usually share their flags, so both
+        // are saved before either is changed
+        let stdin = flags(STDIN)?;
+        let stdout = flags(STDOUT)?;
+        set_flags(STDIN, stdin | libc::O_NONBLOCK)?;
+        if let Err(e) = set_flags(STDOUT, stdout | libc::O_NONBLOCK) {
+            let _ = set_flags(STDIN, stdin);
+            return Err(e);
+        }
+        Ok(Stdio {
+            flags: [stdin, stdout],
+            stdin_eof: false,
+            stdin_registered: Cell::new(false),
+        })
+    }
+}
+
+fn flags(fd: RawFd) -> io::Result<libc::c_int> {
+    match unsafe { libc::fcntl(fd, libc::F_GETFL) } {
+        flags if flags < 0 => Err(io::Error::last_os_error()),
+        flags => Ok(flags),
+    }
+}
+
+fn set_flags(fd: RawFd, flags: libc::c_int) -> io::Result<()> {
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// restored in the reverse order they were set
+impl Drop for Stdio {
+    fn drop(&mut self) {
+        let _ = set_flags(STDOUT, self.flags[1]);
+        let _ = set_flags(STDIN, self.flags[0]);
+    }
+}
+
+impl Read for Stdio {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = unsafe { libc::read(STDIN, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if n == 0 && !buf.is_empty() {
+            self.stdin_eof = true;
+        }
+        Ok(n as usize)
+    }
+}
+
+impl Write for Stdio {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = unsafe { libc::write(STDOUT, buf.as_ptr() as *const libc::c_void, buf.len()) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        let len = cmp::min(bufs.len(), MAX_SLICES);
+        let n = unsafe { libc::writev(STDOUT, bufs.as_ptr() as *const libc::iovec, len as libc::c_int) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Stdio {}
+
+// stdin only ever waits on readable, and stdout on writable. Both stay
+// registered, with no events, while not wanted.
+impl Evented for Stdio {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        if !self.stdin_eof {
+            EventedFd(&STDIN).register(selector, token, interest - EventSet::writable(), opts)?;
+            self.stdin_registered.set(true);
+        }
+        EventedFd(&STDOUT).register(selector, token, interest - EventSet::readable(), opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        if self.stdin_registered.get() {
+            if self.stdin_eof {
+                EventedFd(&STDIN).deregister(selector)?;
+                self.stdin_registered.set(false);
+            } else {
+                EventedFd(&STDIN).reregister(selector, token, interest - EventSet::writable(), opts)?;
+            }
+        }
+        EventedFd(&STDOUT).reregister(selector, token, interest - EventSet::readable(), opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        if self.stdin_registered.replace(false) {
+            EventedFd(&STDIN).deregister(selector)?;
+        }
+        EventedFd(&STDOUT).deregister(selector)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::ptr;
+
+    use libc;
+
+    use super::{flags, Stdio, STDIN, STDOUT};
+
+    fn nonblocking(fd: libc::c_int) -> bool {
+        flags(fd).map(|flags| flags & libc::O_NONBLOCK != 0).unwrap_or(false)
+    }
+
+    // runs in a forked child, so only makes system calls
+    fn check_shared_terminal(slave: libc::c_int) -> libc::c_int {
+        unsafe {
+            if libc::dup2(slave, STDIN) < 0 || libc::dup2(slave, STDOUT) < 0 {
+                return 2;
+            }
+        }
+        if nonblocking(STDIN) {
+            return 3;
+        }
+        let mut stdio = match Stdio::new() {
+            Ok(stdio) => stdio,
+            Err(_) => return 4,
+        };
+        if !nonblocking(STDIN) || !nonblocking(STDOUT) || stdio.write(b"x").is_err() {
+            return 5;
+        }
+        drop(stdio);
+        if nonblocking(STDIN) || nonblocking(STDOUT) {
+            return 1;
+        }
+        0
+    }
+
+    #[test]
+    fn shared_terminals_are_left_blocking() {
+        let (mut master, mut slave) = (-1, -1);
+        unsafe {
+            assert_eq!(libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null(), ptr::null()), 0);
+            let pid = libc::fork();
+            assert!(pid >= 0);
+            if pid == 0 {
+                libc::_exit(check_shared_terminal(slave));
+            }
+            let mut status = 0;
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            libc::close(master);
+            libc::close(slave);
+            assert!(libc::WIFEXITED(status));
+            assert_eq!(libc::WEXITSTATUS(status), 0, "stdin and stdout were left non-blocking");
+        }
+    }
+}
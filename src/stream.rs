@@ -1,35 +1,122 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use mio::{Token, EventSet};
-use ::{Interest, Protocol, Transport};
+use throttle::Limiter;
+use transfer::{self, Shared};
+use transport;
+use ::{Interest, Io, Protocol, Transport};
+
+// how many times an edge-triggered stream is dispatched for a single event,
+// if the protocol keeps asking for readiness it hasn't exhausted
+const MAX_EDGE_DISPATCH: usize = 32;
 
 pub struct Stream<P: Protocol<T>, T: Transport> {
-    transport: T,
+    token: Token,
+    transport: Io<T>,
     protocol: P,
     interest: Interest,
+    // readiness seen from edge-triggered events, and not yet exhausted
+    readiness: EventSet,
+    // a hangup seen while reading was throttled, handled once it resumes
+    hangup: bool,
+    // shared with Transfers
+    shared: Arc<Shared>,
 }
 
 impl<P: Protocol<T>, T: Transport> Stream<P, T> {
 
-    pub fn new(transport: T, protocol: P, interest: Interest) -> Stream<P, T> {
+    pub fn new(token: Token, transport: T, protocol: P, interest: Interest, shared: Arc<Shared>) -> Stream<P, T> {
         Stream {
-            transport: transport,
-            protocol: protocol,
-            interest: interest,
+            token,
+            transport: Io::new(transport),
+            protocol,
+            interest,
+            readiness: EventSet::none(),
+            hangup: false,
+            shared,
+        }
+    }
+
+    /// Records edge-triggered `events`, then calls the protocol for as long
+    /// as it is interested in readiness that hasn't been exhausted, up to
+    /// `MAX_EDGE_DISPATCH` times. If it is still ready after that,
+    /// `is_edge_ready` says so, and the loop should dispatch it again once
+    /// other streams have had a turn.
+    pub fn edge_ready(&mut self, token: Token, events: EventSet) -> Option<::Error> {
+        self.readiness.insert(events);
+        for _ in 0..MAX_EDGE_DISPATCH {
+            let events = self.edge_events();
+            if events == EventSet::none() {
+                return None;
+            }
+            let err = self.ready(token, events);
+            self.readiness.remove(transport::take_blocked(&mut self.transport));
+            if err.is_some() || self.interest == Interest::Remove {
+                return err;
+            }
+        }
+        debug!("{:?} still ready after {} dispatches, yielding", token, MAX_EDGE_DISPATCH);
+        None
+    }
+
+    /// Whether the protocol wants readiness that edge-triggered events have
+    /// seen and it hasn't exhausted.
+    pub fn is_edge_ready(&self) -> bool {
+        self.interest != Interest::Remove && self.edge_events() != EventSet::none()
+    }
+
+    fn edge_events(&self) -> EventSet {
+        let mut wanted = interested(self.registered()) | EventSet::error();
+        if !self.hangup {
+            wanted.insert(EventSet::hup());
         }
+        self.readiness & wanted
     }
 
-    pub fn ready(&mut self, token: Token, events: EventSet) {
+    /// Calls the protocol for `events`. Error and hangup events remove the
+    /// stream, returning the error for the protocol's `on_error`, unless a
+    /// protocol reading at the hangup stops wanting to, having read to EOF.
+    pub fn ready(&mut self, token: Token, events: EventSet) -> Option<::Error> {
         trace!("ready {:?}, '{:?}'", token, events);
         if events.is_error() {
             error!("error event on {:?}", token);
             self.interest = Interest::Remove;
-            return;
-            //TODO: self.protocol.on_error(self.transport.error());
+            return Some(match self.transport.take_error() {
+                Ok(Some(e)) | Err(e) => e.into(),
+                Ok(None) => ::Error::Hangup,
+            });
         }
 
         if events.is_hup() {
-            error!("error event on {:?}", token);
+            if interested(self.interest).is_readable() && !self.read_throttled() {
+                // whatever is left to read comes before the hangup, however
+                // many calls the protocol takes to read it
+                let mut blocked = transport::take_blocked(&mut self.transport);
+                loop {
+                    let before = self.transport.bytes_read();
+                    trace!("on_readable {:?}, hangup ->", token);
+                    self.interest = self.protocol.on_readable(&mut self.transport);
+                    let now = transport::take_blocked(&mut self.transport);
+                    blocked.insert(now);
+                    if !interested(self.interest).is_readable() || now.is_readable()
+                        || self.transport.bytes_read() == before || self.read_throttled() {
+                        break;
+                    }
+                }
+                transport::add_blocked(&mut self.transport, blocked);
+                if !interested(self.interest).is_readable() {
+                    return None;
+                }
+            }
+            if interested(self.interest).is_readable() && self.read_throttled() {
+                trace!("{:?} hung up while throttled, waiting to read the rest", token);
+                self.hangup = true;
+                return None;
+            }
+            error!("hangup event on {:?}", token);
             self.interest = Interest::Remove;
-            return;
+            return Some(::Error::Hangup);
         }
 
         if events.is_readable() {
@@ -38,24 +125,118 @@ impl<P: Protocol<T>, T: Transport> Stream<P, T> {
         }
 
         if events.is_writable() {
-            trace!("on_writable {:?} ->", token);
-            self.interest = self.protocol.on_writable(&mut self.transport);
+            match transport::flush_sends(&mut self.transport) {
+                Ok(true) => {
+                    trace!("on_writable {:?} ->", token);
+                    self.interest = self.protocol.on_writable(&mut self.transport);
+                }
+                Ok(false) => trace!("{:?} still sending files", token),
+                Err(e) => {
+                    error!("sending file on {:?} failed: {}", token, e);
+                    self.interest = Interest::Remove;
+                    return Some(e.into());
+                }
+            }
+        }
+
+        if transport::is_sending(&self.transport) {
+            // stay writable until the queued files are sent
+            self.interest = self.interest + Interest::Write;
         }
+        None
+    }
+
+    pub fn token(&self) -> Token {
+        self.token
     }
 
     pub fn transport(&self) -> &T {
         &self.transport
     }
 
+    pub fn io(&self) -> &Io<T> {
+        &self.transport
+    }
+
     pub fn interest(&self) -> Interest {
         self.interest
     }
 
+    pub fn shared(&self) -> &Shared {
+        &self.shared
+    }
+
+    pub fn set_interest(&mut self, interest: Interest) {
+        self.interest = interest;
+    }
+
+    /// The interest to register for: the protocol's, less whatever is
+    /// throttled. Nothing is registered while a hangup waits on a throttled
+    /// read, since it would fire again straight away.
+    pub fn registered(&self) -> Interest {
+        let throttled = transport::throttled(&self.transport);
+        if self.interest == Interest::Remove || throttled == EventSet::none() {
+            return self.interest;
+        }
+        if self.hangup {
+            return Interest::Wait;
+        }
+        let mut events = interested(self.interest);
+        events.remove(throttled);
+        match (events.is_readable(), events.is_writable()) {
+            (true, true) => Interest::ReadWrite,
+            (true, false) => Interest::Read,
+            (false, true) => Interest::Write,
+            (false, false) => Interest::Wait,
+        }
+    }
+
+    /// Replaces the stream's rate limits, returning the directions the old
+    /// ones had throttled.
+    pub fn throttle(&mut self, limiter: Option<Limiter>) -> EventSet {
+        let throttled = transport::throttled(&self.transport);
+        transport::set_limiter(&mut self.transport, limiter);
+        throttled
+    }
+
+    /// The delay before the stream should be resumed, if it has just been
+    /// throttled.
+    pub fn schedule_resume(&mut self) -> Option<Duration> {
+        transport::limiter(&mut self.transport).and_then(|limiter| limiter.schedule())
+    }
+
+    /// Refills the stream's buckets, returning the directions no longer
+    /// throttled, along with `released`, that the protocol is interested in.
+    pub fn resume(&mut self, released: EventSet) -> EventSet {
+        let resumed = match transport::limiter(&mut self.transport) {
+            Some(limiter) => limiter.resume(),
+            None => EventSet::none(),
+        };
+        if !self.read_throttled() {
+            self.hangup = false;
+        }
+        (resumed | released) & interested(self.interest)
+    }
+
+    fn read_throttled(&self) -> bool {
+        transport::throttled(&self.transport).is_readable()
+    }
+
     pub fn errored(&mut self, err: ::Error) {
         self.protocol.on_error(err);
     }
 
     pub fn removed(self) {
-        self.protocol.on_remove(self.transport);
+        transfer::remove(&self.shared);
+        self.protocol.on_remove(self.transport.into_inner());
+    }
+}
+
+fn interested(interest: Interest) -> EventSet {
+    match interest {
+        Interest::Read => EventSet::readable(),
+        Interest::Write => EventSet::writable(),
+        Interest::ReadWrite => EventSet::readable() | EventSet::writable(),
+        Interest::Wait | Interest::Remove => EventSet::none(),
     }
 }
@@ -0,0 +1,332 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use mio::EventSet;
+
+use ::Clock;
+
+// a throttled direction resumes once its bucket holds this fraction of its
+// capacity, so it wakes at most this many times per interval
+const RESUME_FRACTION: u64 = 8;
+
+/// Limits on how fast a stream reads and writes.
+///
+/// Each direction is a token bucket holding up to `bytes`, refilled evenly
+/// over each `interval`, so a stream can burst up to `bytes` at once, but
+/// averages no more than `bytes` per `interval`. While a bucket is empty,
+/// reads or writes in that direction fail with `WouldBlock`, and the stream
+/// isn't registered for them, until a loop timer finds the bucket refilled
+/// and calls the protocol again.
+///
+/// Attach it to a new stream with `Context::throttle`, or to one already in
+/// the loop with `Transfer::throttle`.
+///
+/// ```no_run
+/// # extern crate mio;
+/// # extern crate tick;
+/// # fn main() {
+/// use std::time::Duration;
+/// use tick::{Context, Interest, Throttle};
+/// use tick::http::{Request, Response, Server};
+///
+/// type Tcp = mio::tcp::TcpStream;
+///
+/// let mut tick = tick::Tick::new(tick::with_context(|_, ctx: &Context<Tcp>| {
+///     // 64KB a second, each way
+///     let second = Duration::from_secs(1);
+///     ctx.throttle(Throttle::new().read(64 * 1024, second).write(64 * 1024, second));
+///     let server = Server::new(|_: Request| Response::new(200).with_body("throttled"));
+///     (server, Interest::Read)
+/// }));
+/// let listener = mio::tcp::TcpListener::bind(&"127.0.0.1:3000".parse().unwrap()).unwrap();
+/// tick.accept(listener).unwrap();
+/// tick.run().unwrap();
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Throttle {
+    read: Option<Rate>,
+    write: Option<Rate>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Rate {
+    bytes: u64,
+    interval: Duration,
+}
+
+impl Throttle {
+    /// No limits in either direction.
+    pub fn new() -> Throttle {
+        Throttle::default()
+    }
+
+    /// Limits reading to `bytes` per `interval`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` or `interval` is zero.
+    pub fn read(&mut self, bytes: u64, interval: Duration) -> &mut Throttle {
+        self.read = Some(rate(bytes, interval));
+        self
+    }
+
+    /// Limits writing to `bytes` per `interval`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` or `interval` is zero.
+    pub fn write(&mut self, bytes: u64, interval: Duration) -> &mut Throttle {
+        self.write = Some(rate(bytes, interval));
+        self
+    }
+
+    /// Whether neither direction is limited.
+    pub fn is_unlimited(&self) -> bool {
+        self.read.is_none() && self.write.is_none()
+    }
+}
+
+fn rate(bytes: u64, interval: Duration) -> Rate {
+    assert!(bytes > 0, "throttle must allow at least one byte");
+    assert!(interval > Duration::from_secs(0), "throttle interval must not be zero");
+    Rate { bytes, interval }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: Rate,
+    tokens: u64,
+    // when the tokens were last topped up
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn new(rate: Rate, now: Instant) -> Bucket {
+        Bucket {
+            rate,
+            tokens: rate.bytes,
+            refilled: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_nanos();
+        let interval = self.rate.interval.as_nanos();
+        let bytes = u128::from(self.rate.bytes);
+        let added = cmp::min(bytes * elapsed / interval, bytes) as u64;
+        if added == 0 {
+            return;
+        }
+        self.tokens = cmp::min(self.rate.bytes, self.tokens + added);
+        if self.tokens == self.rate.bytes {
+            self.refilled = now;
+        } else {
+            // only the time that earned whole tokens is used up
+            let used = u128::from(added) * interval / bytes;
+            self.refilled += Duration::from_nanos(used as u64);
+        }
+    }
+
+    // how long until the bucket holds enough to resume
+    fn wait(&self, now: Instant) -> Duration {
+        let want = cmp::max(1, self.rate.bytes / RESUME_FRACTION);
+        if self.tokens >= want {
+            return Duration::from_secs(0);
+        }
+        let missing = u128::from(want - self.tokens);
+        let interval = self.rate.interval.as_nanos();
+        let nanos = (missing * interval).div_ceil(u128::from(self.rate.bytes));
+        let at = self.refilled + Duration::from_nanos(nanos as u64);
+        at.saturating_duration_since(now)
+    }
+}
+
+/// The buckets of a throttled stream.
+#[derive(Debug)]
+pub struct Limiter {
+    clock: Clock,
+    read: Option<Bucket>,
+    write: Option<Bucket>,
+    // directions found with an empty bucket
+    throttled: EventSet,
+    // whether a timer to resume them is pending
+    scheduled: bool,
+}
+
+/// A full set of buckets for `throttle`, or `None` if it has no limits.
+#[inline]
+pub fn limiter(throttle: &Throttle, clock: Clock) -> Option<Limiter> {
+    if throttle.is_unlimited() {
+        return None;
+    }
+    let now = clock.now();
+    Some(Limiter {
+        read: throttle.read.map(|rate| Bucket::new(rate, now)),
+        write: throttle.write.map(|rate| Bucket::new(rate, now)),
+        clock,
+        throttled: EventSet::none(),
+        scheduled: false,
+    })
+}
+
+impl Limiter {
+    fn bucket(&mut self, direction: EventSet) -> Option<&mut Bucket> {
+        if direction.is_readable() {
+            self.read.as_mut()
+        } else {
+            self.write.as_mut()
+        }
+    }
+
+    /// How many of `want` bytes may be moved in `direction`, which is
+    /// marked throttled if it's none of them.
+    pub fn allow(&mut self, direction: EventSet, want: usize) -> usize {
+        let bucket = if direction.is_readable() {
+            self.read.as_mut()
+        } else {
+            self.write.as_mut()
+        };
+        let tokens = match bucket {
+            Some(bucket) => {
+                // refilled even when full, so time spent full isn't saved up
+                bucket.refill(self.clock.now());
+                bucket.tokens
+            }
+            None => return want,
+        };
+        if tokens == 0 && want > 0 {
+            trace!("throttled '{:?}'", direction);
+            self.throttled.insert(direction);
+        }
+        cmp::min(tokens, want as u64) as usize
+    }
+
+    /// Takes `n` bytes moved in `direction` from its bucket.
+    pub fn took(&mut self, direction: EventSet, n: usize) {
+        if let Some(bucket) = self.bucket(direction) {
+            bucket.tokens = bucket.tokens.saturating_sub(n as u64);
+        }
+    }
+
+    /// The directions waiting for their buckets to refill.
+    pub fn throttled(&self) -> EventSet {
+        self.throttled
+    }
+
+    /// The delay before throttled directions should be retried, unless a
+    /// timer to do so is already pending.
+    pub fn schedule(&mut self) -> Option<Duration> {
+        if self.scheduled || self.throttled == EventSet::none() {
+            return None;
+        }
+        self.scheduled = true;
+        let now = self.clock.now();
+        let throttled = self.throttled;
+        [self.read.as_ref().filter(|_| throttled.is_readable()),
+         self.write.as_ref().filter(|_| throttled.is_writable())]
+            .iter()
+            .flatten()
+            .map(|bucket| bucket.wait(now))
+            .min()
+    }
+
+    /// Refills the buckets, returning the directions no longer throttled.
+    pub fn resume(&mut self) -> EventSet {
+        self.scheduled = false;
+        let now = self.clock.now();
+        let mut resumed = EventSet::none();
+        for &direction in &[EventSet::readable(), EventSet::writable()] {
+            if self.throttled & direction != EventSet::none() {
+                let refilled = match self.bucket(direction) {
+                    Some(bucket) => {
+                        bucket.refill(now);
+                        bucket.tokens > 0
+                    }
+                    None => true,
+                };
+                if refilled {
+                    self.throttled.remove(direction);
+                    resumed.insert(direction);
+                }
+            }
+        }
+        resumed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use mio::EventSet;
+
+    use ::Clock;
+    use super::{limiter, rate, Bucket, Throttle};
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn buckets_refill_evenly_up_to_their_size() {
+        let clock = Clock::manual();
+        let mut bucket = Bucket::new(rate(100, ms(1000)), clock.now());
+        bucket.tokens = 0;
+        clock.advance(ms(255));
+        bucket.refill(clock.now());
+        assert_eq!(bucket.tokens, 25);
+        // the 5ms left over count towards the next token
+        clock.advance(ms(5));
+        bucket.refill(clock.now());
+        assert_eq!(bucket.tokens, 26);
+        clock.advance(ms(10_000));
+        bucket.refill(clock.now());
+        assert_eq!(bucket.tokens, 100);
+    }
+
+    #[test]
+    fn buckets_wait_for_a_fraction_of_their_size() {
+        let clock = Clock::manual();
+        let mut bucket = Bucket::new(rate(800, ms(800)), clock.now());
+        assert_eq!(bucket.wait(clock.now()), ms(0));
+        bucket.tokens = 0;
+        assert_eq!(bucket.wait(clock.now()), ms(100));
+        clock.advance(ms(40));
+        assert_eq!(bucket.wait(clock.now()), ms(60));
+    }
+
+    #[test]
+    fn limiters_throttle_empty_directions() {
+        let clock = Clock::manual();
+        let mut limiter = limiter(Throttle::new().write(10, ms(100)), clock.clone()).unwrap();
+        assert_eq!(limiter.allow(EventSet::readable(), 1000), 1000);
+        assert_eq!(limiter.allow(EventSet::writable(), 1000), 10);
+        limiter.took(EventSet::writable(), 10);
+        assert_eq!(limiter.throttled(), EventSet::none());
+        assert_eq!(limiter.allow(EventSet::writable(), 1000), 0);
+        assert_eq!(limiter.throttled(), EventSet::writable());
+
+        // a bucket this small resumes as soon as a byte is earned back
+        let delay = limiter.schedule().unwrap();
+        assert_eq!(delay, ms(10));
+        assert_eq!(limiter.schedule(), None);
+        clock.advance(delay);
+        assert_eq!(limiter.resume(), EventSet::writable());
+        assert_eq!(limiter.throttled(), EventSet::none());
+        assert_eq!(limiter.allow(EventSet::writable(), 1000), 1);
+    }
+
+    #[test]
+    fn unlimited_throttles_have_no_limiter() {
+        assert!(Throttle::new().is_unlimited());
+        assert!(limiter(&Throttle::new(), Clock::manual()).is_none());
+        assert!(!Throttle::new().read(1, ms(1)).is_unlimited());
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_rates_panic() {
+        Throttle::new().read(0, ms(1));
+    }
+}
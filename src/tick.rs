@@ -1,86 +1,751 @@
-//use std::time::Duration;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 
 use mio::{EventLoop, Evented, EventLoopConfig, TryAccept};
 
 use handler::LoopHandler;
-use internal::Message;
+use proxy;
+use source;
+use internal::{ListenerAction, Message};
+use timer;
 use transport::Transport;
-use ::ProtocolFactory;
+use ::{Clock, LoopObserver, ProtocolFactory, Stats};
 
 
+/// An event loop driving listeners and streams with `Protocol`s.
 pub struct Tick<T: TryAccept + Evented, F: ProtocolFactory<T::Output>> where <T as TryAccept>::Output: Transport {
     handler: LoopHandler<F, T>,
     event_loop: EventLoop<LoopHandler<F, T>>
 }
 
+/// Configuration used to construct a `Tick`.
 pub struct TickConfig {
     transports_capacity: usize,
+    max_transports: Option<usize>,
     notify_capacity: usize,
+    clock: Clock,
+    edge_triggered: bool,
 }
 
 impl TickConfig {
+    /// Creates a config with the default capacities.
     pub fn new() -> TickConfig {
         TickConfig {
-            transports_capacity: 8_192,
+            transports_capacity: 256,
+            max_transports: None,
             notify_capacity: 8_192,
+            clock: Clock::system(),
+            edge_triggered: false,
         }
     }
+
+    /// Sets how many listeners and streams there is room for up front.
+    ///
+    /// The registry doubles in size whenever it fills up, so this is only a
+    /// starting point. Defaults to 256.
+    pub fn transports_capacity(&mut self, capacity: usize) -> &mut TickConfig {
+        self.transports_capacity = capacity;
+        self
+    }
+
+    /// Sets a hard cap on the number of listeners and streams in the loop.
+    ///
+    /// Once reached, adding another fails with `Error::TooManySockets`.
+    /// Defaults to `None`, growing without bound.
+    pub fn max_transports(&mut self, max: Option<usize>) -> &mut TickConfig {
+        self.max_transports = max;
+        self
+    }
+
+    /// Sets the `Clock` used for timeouts. Defaults to the system clock.
+    pub fn clock(&mut self, clock: Clock) -> &mut TickConfig {
+        self.clock = clock;
+        self
+    }
+
+    /// Registers streams once, edge-triggered, for both reading and writing.
+    ///
+    /// By default, streams are registered level-triggered and oneshot, which
+    /// costs a `reregister` after every callback. In edge-triggered mode, the
+    /// loop instead tracks readiness itself, clearing it when a read or write
+    /// returns `WouldBlock`, and only calls a `Protocol` back for readiness it
+    /// is interested in. Protocols should read and write until `WouldBlock`;
+    /// readiness that isn't exhausted is dispatched again right away.
+    pub fn edge_triggered(&mut self, edge: bool) -> &mut TickConfig {
+        self.edge_triggered = edge;
+        self
+    }
+}
+
+impl Default for TickConfig {
+    fn default() -> TickConfig {
+        TickConfig::new()
+    }
 }
 
 impl<T: TryAccept + Evented, F: ProtocolFactory<T::Output>> Tick<T, F> where <T as TryAccept>::Output: Transport {
+    /// Creates a `Tick` with the default `TickConfig`.
     pub fn new(protocol_factory: F) -> Tick<T, F> {
         Tick::configured(protocol_factory, TickConfig::new())
     }
 
+    /// Creates a `Tick` for tests, using a manual `Clock`.
+    ///
+    /// Timeouts only fire once the clock is moved with `advance`, and `step`
+    /// can be used to handle events one at a time.
+    pub fn simulated(protocol_factory: F) -> Tick<T, F> {
+        let mut config = TickConfig::new();
+        config.clock(Clock::manual());
+        Tick::configured(protocol_factory, config)
+    }
+
+    /// Creates a `Tick` using the supplied `TickConfig`.
     pub fn configured(factory: F, config: TickConfig) -> Tick<T, F> {
         let mut loop_config = EventLoopConfig::new();
         loop_config.notify_capacity(config.notify_capacity);
         Tick {
-            handler: LoopHandler::new(factory, config.transports_capacity),
+            handler: LoopHandler::new(factory, config.transports_capacity, config.max_transports, config.clock, config.edge_triggered),
             event_loop: EventLoop::configured(loop_config).unwrap()
         }
     }
 
+    /// Gets a `Notify` handle that can talk to this loop from other threads.
     pub fn notify(&self) -> Notify {
         Notify { sender: self.event_loop.channel() }
     }
 
+    /// Adds a listener, creating a stream for every accepted transport.
+    ///
+    /// If the process runs out of file descriptors or memory, the listener
+    /// is paused for a moment, and then tries again. Other accept errors,
+    /// besides connections aborted before they were accepted, pause it and
+    /// stop the loop, which returns `Error::Accept`; `resume_accept` starts
+    /// it again.
     pub fn accept(&mut self, listener: T) -> ::Result<::Id> {
-        self.handler.listener(&mut self.event_loop, listener).map(::Id)
+        self.handler.listener(&mut self.event_loop, listener)
+    }
+
+    /// Stops accepting new streams from the listener for `id`, leaving its
+    /// existing streams alone.
+    pub fn pause_accept(&mut self, id: ::Id) -> ::Result<()> {
+        self.handler.listener_action(&mut self.event_loop, id, ListenerAction::Pause)
+    }
+
+    /// Starts accepting new streams again from a paused listener.
+    pub fn resume_accept(&mut self, id: ::Id) -> ::Result<()> {
+        self.handler.listener_action(&mut self.event_loop, id, ListenerAction::Resume)
+    }
+
+    /// Removes the listener for `id` from the loop, dropping it. Streams it
+    /// has already accepted are left alone.
+    ///
+    /// This, `pause_accept` and `resume_accept` fail with `Error::Removed`
+    /// once the listener has been removed, and `Error::NotAListener` for the
+    /// `Id` of a stream.
+    pub fn remove_listener(&mut self, id: ::Id) -> ::Result<()> {
+        self.handler.listener_action(&mut self.event_loop, id, ListenerAction::Remove)
     }
 
+    /// Adds a single stream to the loop.
     pub fn stream(&mut self, transport: T::Output) -> ::Result<::Id> {
-        self.handler.stream(&mut self.event_loop, transport).map(::Id)
+        self.handler.stream(&mut self.event_loop, transport, None)
+    }
+
+    /// Links `a` and `b`, copying bytes between them in both directions.
+    ///
+    /// Neither end needs a `Protocol`. Each direction buffers a bounded
+    /// amount, and stops reading from the sending end while its buffer is
+    /// full. On Linux, if both ends have a file descriptor, as `TcpStream`s
+    /// do, the buffer is a pipe, and bytes are moved with `splice` instead,
+    /// never copied through userspace. EOF from one end is passed on by
+    /// closing the other's writing half, once everything before it has been
+    /// written, and the proxy is removed once both directions have finished.
+    /// An error on either end drops both.
+    pub fn proxy<A, B>(&mut self, a: A, b: B) -> ::Result<::Id>
+    where A: ::Duplex + 'static, B: ::Duplex + 'static {
+        self.handler.proxy(&mut self.event_loop, Box::new(a), Box::new(b))
+    }
+
+    /// Adds a listener whose accepted streams are each proxied, as with
+    /// `proxy`, to the transport `connect` returns for them.
+    ///
+    /// If `connect` fails, the accepted stream is dropped. The returned `Id`
+    /// can be paused, resumed and removed like any other listener.
+    ///
+    /// ```no_run
+    /// # extern crate mio;
+    /// # extern crate tick;
+    /// # fn main() {
+    /// use mio::tcp::{TcpListener, TcpStream};
+    /// use tick::http::{Request, Response, Server};
+    ///
+    /// fn health(_: Request) -> Response {
+    ///     Response::new(200).with_body("ok")
+    /// }
+    ///
+    /// let mut tick = tick::Tick::new(|_| (Server::new(health), tick::Interest::Read));
+    /// // health checks are answered by the loop itself
+    /// tick.accept(TcpListener::bind(&"127.0.0.1:3000".parse().unwrap()).unwrap()).unwrap();
+    /// // and everything else is passed on to the backend
+    /// let backend = "127.0.0.1:8080".parse().unwrap();
+    /// let listener = TcpListener::bind(&"127.0.0.1:3333".parse().unwrap()).unwrap();
+    /// tick.proxy_accept(listener, move |_: &TcpStream| TcpStream::connect(&backend)).unwrap();
+    /// tick.run().unwrap();
+    /// # }
+    /// ```
+    pub fn proxy_accept<L, C, B>(&mut self, listener: L, connect: C) -> ::Result<::Id>
+    where L: TryAccept + Evented + 'static,
+          L::Output: ::Duplex + 'static,
+          C: FnMut(&L::Output) -> ::std::io::Result<B> + 'static,
+          B: ::Duplex + 'static {
+        self.handler.proxy_listener(&mut self.event_loop, proxy::listener(listener, connect))
+    }
+
+    /// Adds any `Evented`, calling `handler` on the loop thread whenever it
+    /// is ready for `interest`.
+    ///
+    /// This is how the loop can wait on things that aren't listeners or
+    /// streams, such as an eventfd or a pipe from another library. The
+    /// handler is given the `Evented` and the readiness, and returns the
+    /// next `Interest`, as a `Protocol` would. Returning `Interest::Remove`
+    /// deregisters and drops the `Evented`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interest` is `Interest::Wait` or `Interest::Remove`.
+    pub fn register<E, H>(&mut self, evented: E, interest: ::Interest, handler: H) -> ::Result<::Id>
+    where E: Evented + 'static, H: FnMut(&mut E, ::EventSet) -> ::Interest + 'static {
+        self.handler.register(&mut self.event_loop, source::new(evented, handler), interest)
+    }
+
+    /// Starts handling signals on the loop thread.
+    ///
+    /// A handler is installed that only writes to a pipe registered with the
+    /// loop, so handlers set with `signal` run on the loop thread, like any
+    /// other callback. `Signal::Interrupt` and `Signal::Terminate` are set to
+    /// shut the loop down, as with `Notify::shutdown`, unless `signal` has
+    /// already set handlers for them. Neither `signal` nor `spawn` does this
+    /// on its own.
+    ///
+    /// Signal dispositions are process wide, so only one `Tick` at a time
+    /// can handle signals. The previous dispositions are restored once the
+    /// `Tick` is dropped.
+    #[cfg(unix)]
+    pub fn handle_signals(&mut self) -> ::Result<()> {
+        self.handler.handle_signals(&mut self.event_loop)
+    }
+
+    /// Calls `handler` on the loop thread whenever `signal` is received,
+    /// replacing any previous handler, including the default ones.
+    ///
+    /// Like `handle_signals`, only one `Tick` at a time can set handlers.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # extern crate mio;
+    /// # extern crate tick;
+    /// # use tick::{Interest, Signal, Tick};
+    /// # struct Noop;
+    /// # impl tick::Protocol<mio::tcp::TcpStream> for Noop {
+    /// #     fn on_readable(&mut self, _: &mut tick::Io<mio::tcp::TcpStream>) -> Interest { Interest::Remove }
+    /// #     fn on_writable(&mut self, _: &mut tick::Io<mio::tcp::TcpStream>) -> Interest { Interest::Remove }
+    /// #     fn on_error(&mut self, _: tick::Error) {}
+    /// # }
+    /// # fn main() {
+    /// let mut tick = Tick::<mio::tcp::TcpListener, _>::new(|_| (Noop, Interest::Read));
+    /// let listener = mio::tcp::TcpListener::bind(&"127.0.0.1:3000".parse().unwrap()).unwrap();
+    /// let id = tick.accept(listener).unwrap();
+    ///
+    /// // drain: stop accepting, and let existing streams finish
+    /// let notify = tick.notify();
+    /// tick.signal(Signal::Terminate, move || {
+    ///     let _ = notify.remove_listener(id);
+    /// }).unwrap();
+    /// tick.signal(Signal::Hangup, || println!("reloading")).unwrap();
+    /// tick.run().unwrap();
+    /// # }
+    /// ```
+    #[cfg(unix)]
+    pub fn signal<H: FnMut() + 'static>(&mut self, signal: ::Signal, handler: H) -> ::Result<()> {
+        self.handler.signal(&mut self.event_loop, signal, Box::new(handler))
+    }
+
+    /// Spawns `command`, with its stdin, stdout and stderr piped into the
+    /// loop and handled by `process`.
+    ///
+    /// Exits are noticed through `Signal::Child`, so this sets a handler for
+    /// it, and, like `handle_signals`, fails if another `Tick` in the
+    /// process handles signals. No other signals are handled. Once the
+    /// child exits, its `Process` is told, and the returned `Id` is removed
+    /// from the loop.
+    #[cfg(unix)]
+    pub fn spawn<P: ::Process + 'static>(&mut self, command: &mut ::std::process::Command, process: P) -> ::Result<::Id> {
+        self.handler.spawn(&mut self.event_loop, command, Box::new(process))
+    }
+
+    /// Spawns `command` on a new pseudo-terminal of the given size, and adds
+    /// the master side as a stream.
+    ///
+    /// Like `spawn`, this handles `SIGCHLD`. Once the command exits, its
+    /// protocol is given what's left to read, and the stream is removed.
+    ///
+    /// Works with any `Tick` whose streams can be made from a `Pty`, whether
+    /// `Pty` itself, or an enum of it and other transports.
+    #[cfg(unix)]
+    pub fn spawn_pty(&mut self, command: &mut ::std::process::Command, rows: u16, cols: u16) -> ::Result<::Id>
+    where T::Output: From<::Pty> {
+        let pty = ::Pty::spawn(command, rows, cols)?;
+        let child = ::pty::child(&pty);
+        self.handler.pty(&mut self.event_loop, pty.into(), child)
+    }
+
+    /// Installs a `LoopObserver`, replacing any previous one.
+    pub fn observe<O: LoopObserver + 'static>(&mut self, observer: O) {
+        self.handler.observe(Box::new(observer));
+    }
+
+    /// Calls `callback` on the loop thread once `delay` has passed.
+    pub fn timeout<C: FnOnce() + 'static>(&mut self, delay: Duration, callback: C) {
+        self.handler.timeout(&mut self.event_loop, delay, Box::new(callback));
+    }
+
+    /// Gets a handle to the `Clock` used for timeouts.
+    pub fn clock(&self) -> Clock {
+        self.handler.clock().clone()
+    }
+
+    /// Moves the loop's manual `Clock` forward by `by`.
+    ///
+    /// Timeouts that become due fire on the next `step` or loop iteration.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the loop uses the system clock.
+    pub fn advance(&mut self, by: Duration) {
+        self.handler.clock().advance(by);
+    }
+
+    /// Handles exactly one due timeout, ready event or notify message.
+    ///
+    /// Polls without blocking if nothing was already waiting. Returns
+    /// `false` if there was nothing to handle.
+    pub fn step(&mut self) -> ::Result<bool> {
+        if !self.handler.has_pending() {
+            self.handler.set_buffering(true);
+            let polled = self.event_loop.run_once(&mut self.handler, Some(0));
+            self.handler.set_buffering(false);
+            polled?;
+        }
+        let stepped = self.handler.step(&mut self.event_loop);
+        self.failed()?;
+        Ok(stepped)
     }
 
+    /// Gets a snapshot of the loop's statistics.
+    pub fn stats(&self) -> Stats {
+        self.handler.stats()
+    }
+
+    /// Runs the loop until the stream for `id` has been removed.
+    ///
+    /// Like each of the `run_until_*complete` methods, returns
+    /// `Error::Shutdown` if the loop is shut down first.
     pub fn run_until_complete(&mut self, id: ::Id) -> ::Result<()> {
-        while self.handler.transports.contains(id.0) {
-            try!(self.event_loop.run_once(&mut self.handler, None));
+        self.run_until_deadline(None, |handler| !handler.contains(id))
+    }
+
+    /// Runs the loop until the stream for `id` has been removed, or returns
+    /// `Error::Timeout` once `deadline` passes.
+    ///
+    /// Deadlines are measured by the loop's `Clock`, so with a manual clock
+    /// they only pass once it's moved.
+    pub fn run_until_complete_by(&mut self, id: ::Id, deadline: Instant) -> ::Result<()> {
+        self.run_until_deadline(Some(deadline), |handler| !handler.contains(id))
+    }
+
+    /// Runs the loop until the streams for all of `ids` have been removed.
+    ///
+    /// If a `deadline` is given and passes first, returns `Error::Timeout`.
+    pub fn run_until_all_complete(&mut self, ids: &[::Id], deadline: Option<Instant>) -> ::Result<()> {
+        self.run_until_deadline(deadline, |handler| ids.iter().all(|&id| !handler.contains(id)))
+    }
+
+    /// Runs the loop until the stream for any of `ids` has been removed,
+    /// returning the first such `Id`.
+    ///
+    /// If a `deadline` is given and passes first, returns `Error::Timeout`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ids` is empty.
+    pub fn run_until_any_complete(&mut self, ids: &[::Id], deadline: Option<Instant>) -> ::Result<::Id> {
+        assert!(!ids.is_empty(), "run_until_any_complete needs at least one Id");
+        let mut complete = None;
+        self.run_until_deadline(deadline, |handler| {
+            complete = ids.iter().cloned().find(|&id| !handler.contains(id));
+            complete.is_some()
+        })?;
+        Ok(complete.unwrap())
+    }
+
+    fn run_until_deadline<D>(&mut self, deadline: Option<Instant>, mut done: D) -> ::Result<()>
+    where D: FnMut(&LoopHandler<F, T>) -> bool {
+        self.handler.flush(&mut self.event_loop);
+        let clock = self.handler.clock().clone();
+        while !done(&self.handler) {
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let now = clock.now();
+                    if now >= deadline {
+                        return Err(::Error::Timeout);
+                    }
+                    Some(timer::millis(deadline - now) as usize)
+                }
+                None => None,
+            };
+            self.event_loop.run_once(&mut self.handler, timeout)?;
+            self.failed()?;
+            if self.handler.take_shutdown() {
+                return Err(::Error::Shutdown);
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a single iteration of the loop, waiting up to `timeout` for
+    /// events, or forever if `None`.
+    pub fn run_once(&mut self, timeout: Option<Duration>) -> ::Result<()> {
+        self.handler.flush(&mut self.event_loop);
+        let ms = timeout.map(|t| timer::millis(t) as usize);
+        self.event_loop.run_once(&mut self.handler, ms)?;
+        self.failed()
+    }
+
+    /// Runs the loop until `duration` of real time has passed, or it is
+    /// shutdown.
+    pub fn run_for(&mut self, duration: Duration) -> ::Result<()> {
+        let deadline = Instant::now() + duration;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            self.run_once(Some(deadline - now))?;
+            if self.handler.take_shutdown() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Runs the loop until `done` returns `true`, or it is shutdown.
+    ///
+    /// `done` is checked before every iteration of the loop, each of which
+    /// blocks until there is an event, timeout or notify message to handle.
+    /// If `done` can become true some other way, such as from another
+    /// thread, send a `Notify` message to wake the loop, or use `run_for`.
+    pub fn run_until<D: FnMut(&Tick<T, F>) -> bool>(&mut self, mut done: D) -> ::Result<()> {
+        while !done(self) {
+            self.run_once(None)?;
+            if self.handler.take_shutdown() {
+                break;
+            }
         }
         Ok(())
     }
 
+    /// Runs the loop until it is shutdown, or stopped by an error, such as
+    /// `Error::Accept` from a listener.
     pub fn run(&mut self) -> ::Result<()> {
-        self.event_loop.run(&mut self.handler).map_err(From::from)
+        self.handler.flush(&mut self.event_loop);
+        let ret = self.event_loop.run(&mut self.handler);
+        self.handler.take_shutdown();
+        ret?;
+        self.failed()
+    }
+
+    // the error that stopped the loop, if any
+    fn failed(&mut self) -> ::Result<()> {
+        match self.handler.take_error() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
     }
 }
 
+/// A handle to send messages to a running `Tick`.
 #[derive(Clone)]
 pub struct Notify {
     sender: ::mio::Sender<Message>
 }
 
 impl Notify {
-    /*
-    pub fn timeout<F: FnOnce() + Send + 'static>(&self, f: F, when: Duration) {
-        let mut env = Some(f);
-        let ms = when.as_secs() * 1_000 + (when.subsec_nanos() as u64) / 1_000_000;
-        self.sender.send(Message::Timeout(Box::new(move || {
-            env.take().map(|f| f());
-        }), ms));
-    }
-    */
-    pub fn shutdown(&self) {
-        self.sender.send(Message::Shutdown).unwrap();
+    /// Calls `callback` on the loop thread once `delay` has passed.
+    ///
+    /// Like every `Notify` method, fails with `Error::NotifyFull` if the
+    /// loop's notify queue is full, or `Error::Closed` if the loop is gone.
+    pub fn timeout<C: FnOnce() + Send + 'static>(&self, delay: Duration, callback: C) -> ::Result<()> {
+        self.send(Message::Timeout(Box::new(callback), delay))
+    }
+
+    /// Asks the loop for a snapshot of its statistics.
+    ///
+    /// The `Stats` are sent on the returned receiver once the loop handles
+    /// the request, so don't block on it from the loop's own thread.
+    pub fn stats(&self) -> ::Result<mpsc::Receiver<Stats>> {
+        let (tx, rx) = mpsc::channel();
+        self.send(Message::Stats(tx))?;
+        Ok(rx)
+    }
+
+    /// Tells the loop to stop accepting from the listener for `id`.
+    pub fn pause_accept(&self, id: ::Id) -> ::Result<()> {
+        self.send(Message::Listener(id, ListenerAction::Pause))
+    }
+
+    /// Tells the loop to start accepting from a paused listener again.
+    pub fn resume_accept(&self, id: ::Id) -> ::Result<()> {
+        self.send(Message::Listener(id, ListenerAction::Resume))
+    }
+
+    /// Tells the loop to remove the listener for `id`.
+    pub fn remove_listener(&self, id: ::Id) -> ::Result<()> {
+        self.send(Message::Listener(id, ListenerAction::Remove))
+    }
+
+    /// Tells the loop to stop running.
+    pub fn shutdown(&self) -> ::Result<()> {
+        self.send(Message::Shutdown)
+    }
+
+    fn send(&self, msg: Message) -> ::Result<()> {
+        self.sender.send(msg).map_err(From::from)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{self, Read, Write};
+    use std::net;
+    use std::rc::Rc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use mio::tcp::{TcpListener, TcpStream};
+    use mio::unix::{self, PipeReader};
+
+    use ::{Clock, EventSet, Interest, Io, Protocol, Tick, TickConfig, Transfer};
+
+    // reads everything it's given, and is removed at EOF
+    struct Drain;
+
+    impl Protocol<TcpStream> for Drain {
+        fn on_readable(&mut self, transport: &mut Io<TcpStream>) -> Interest {
+            let mut buf = [0; 64];
+            loop {
+                match transport.read(&mut buf) {
+                    Ok(0) => return Interest::Remove,
+                    Ok(_) => (),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Interest::Read,
+                    Err(_) => return Interest::Remove,
+                }
+            }
+        }
+
+        fn on_writable(&mut self, _: &mut Io<TcpStream>) -> Interest {
+            Interest::Read
+        }
+
+        fn on_error(&mut self, _: ::Error) {}
+    }
+
+    fn drain(_: Transfer) -> (Drain, Interest) {
+        (Drain, Interest::Read)
+    }
+
+    type Draining = Tick<TcpListener, fn(Transfer) -> (Drain, Interest)>;
+
+    fn tick() -> Draining {
+        Tick::new(drain as fn(Transfer) -> (Drain, Interest))
+    }
+
+    // a connected pair, one end for the loop, and a blocking one for a test
+    fn pair() -> (TcpStream, net::TcpStream) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let ours = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        let (theirs, _) = listener.accept().unwrap();
+        (ours, theirs)
+    }
+
+    fn later<F: FnOnce() + Send + 'static>(f: F) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            f();
+        })
+    }
+
+    fn deadline() -> Option<Instant> {
+        Some(Instant::now() + Duration::from_secs(5))
+    }
+
+    fn is_active(tick: &Draining, id: ::Id) -> bool {
+        tick.stats().streams.iter().any(|stream| stream.id == id)
+    }
+
+    #[test]
+    fn run_until_complete_by_times_out() {
+        let mut tick = tick();
+        let (ours, _theirs) = pair();
+        let id = tick.stream(ours).unwrap();
+        let start = Instant::now();
+        match tick.run_until_complete_by(id, start + Duration::from_millis(50)) {
+            Err(::Error::Timeout) => (),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(is_active(&tick, id));
+    }
+
+    #[test]
+    fn deadlines_follow_the_loop_clock() {
+        let clock = Clock::manual();
+        let mut config = TickConfig::new();
+        config.clock(clock.clone());
+        let mut tick: Draining = Tick::configured(drain, config);
+        let (ours, mut theirs) = pair();
+        let id = tick.stream(ours).unwrap();
+        // a day of real time would never pass, but the clock can be moved
+        let deadline = clock.now() + Duration::from_secs(24 * 60 * 60);
+        let mover = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            clock.advance(Duration::from_secs(25 * 60 * 60));
+            theirs.write_all(b"wake").unwrap();
+            theirs
+        });
+        match tick.run_until_complete_by(id, deadline) {
+            Err(::Error::Timeout) => (),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        drop(mover.join().unwrap());
+    }
+
+    #[test]
+    fn run_until_all_complete_waits_for_every_stream() {
+        let mut tick = tick();
+        let (a, theirs_a) = pair();
+        let (b, theirs_b) = pair();
+        let ids = [tick.stream(a).unwrap(), tick.stream(b).unwrap()];
+        let closer = later(move || {
+            drop(theirs_a);
+            thread::sleep(Duration::from_millis(20));
+            drop(theirs_b);
+        });
+        tick.run_until_all_complete(&ids, deadline()).unwrap();
+        assert!(!is_active(&tick, ids[0]) && !is_active(&tick, ids[1]));
+        closer.join().unwrap();
+    }
+
+    #[test]
+    fn run_until_any_complete_returns_the_first() {
+        let mut tick = tick();
+        let (a, _theirs_a) = pair();
+        let (b, theirs_b) = pair();
+        let ids = [tick.stream(a).unwrap(), tick.stream(b).unwrap()];
+        let closer = later(move || drop(theirs_b));
+        assert_eq!(tick.run_until_any_complete(&ids, deadline()).unwrap(), ids[1]);
+        assert!(is_active(&tick, ids[0]));
+        closer.join().unwrap();
+    }
+
+    #[test]
+    fn shutdown_stops_run_until_complete() {
+        let mut tick = tick();
+        let (ours, _theirs) = pair();
+        let id = tick.stream(ours).unwrap();
+        let notify = tick.notify();
+        let stopper = later(move || notify.shutdown().unwrap());
+        match tick.run_until_complete(id) {
+            Err(::Error::Shutdown) => (),
+            other => panic!("expected a shutdown, got {:?}", other),
+        }
+        stopper.join().unwrap();
+
+        // the shutdown was used up, and doesn't stop the next run early
+        let start = Instant::now();
+        tick.run_for(Duration::from_millis(50)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn run_for_returns_at_its_bound() {
+        let mut tick = tick();
+        let (ours, mut theirs) = pair();
+        tick.stream(ours).unwrap();
+        let writer = later(move || theirs.write_all(b"busy").unwrap());
+        let start = Instant::now();
+        tick.run_for(Duration::from_millis(100)).unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100), "returned after {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "returned after {:?}", elapsed);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn run_until_stops_on_its_predicate() {
+        let mut tick = tick();
+        let (ours, theirs) = pair();
+        tick.stream(ours).unwrap();
+        let closer = later(move || drop(theirs));
+        tick.run_until(|tick| tick.stats().active == 0).unwrap();
+        assert_eq!(tick.stats().removed, 1);
+        closer.join().unwrap();
+    }
+
+    #[test]
+    fn run_until_stops_on_shutdown() {
+        let mut tick = tick();
+        let (ours, _theirs) = pair();
+        tick.stream(ours).unwrap();
+        let notify = tick.notify();
+        let stopper = later(move || notify.shutdown().unwrap());
+        tick.run_until(|_| false).unwrap();
+        assert_eq!(tick.stats().active, 1);
+        stopper.join().unwrap();
+    }
+
+    #[test]
+    fn registered_sources_are_dispatched_until_removed() {
+        let mut tick = tick();
+        let (reader, mut writer) = unix::pipe().unwrap();
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let s = seen.clone();
+        let id = tick.register(reader, Interest::Read, move |reader: &mut PipeReader, events: EventSet| {
+            assert!(events.is_readable());
+            let mut buf = [0; 64];
+            let n = reader.read(&mut buf).unwrap();
+            s.borrow_mut().extend_from_slice(&buf[..n]);
+            if &buf[..n] == b"bye" {
+                Interest::Remove
+            } else {
+                Interest::Read
+            }
+        }).unwrap();
+        assert!(tick.handler.contains(id));
+
+        writer.write_all(b"hello").unwrap();
+        while seen.borrow().len() < 5 {
+            tick.run_once(Some(Duration::from_millis(100))).unwrap();
+        }
+        assert_eq!(&seen.borrow()[..], b"hello");
+
+        writer.write_all(b"bye").unwrap();
+        while tick.handler.contains(id) {
+            tick.run_once(Some(Duration::from_millis(100))).unwrap();
+        }
+        assert_eq!(&seen.borrow()[..], b"hellobye");
+        // the reader was dropped along with the source
+        let err = writer.write_all(b"more").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
     }
 }
@@ -0,0 +1,78 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::time::{Duration, Instant};
+
+use ::Id;
+
+pub type Callback = Box<dyn FnOnce()>;
+
+pub enum Timer {
+    Callback(Callback),
+    // a throttled stream to resume
+    Resume(Id),
+    // a listener to resume accepting on, after running out of resources
+    Accept(Id),
+}
+
+struct Entry {
+    when: Instant,
+    seq: u64,
+    timer: Timer,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.when == other.when && self.seq == other.seq
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    // reversed, so the BinaryHeap pops the earliest entry first, and entries
+    // due at the same instant fire in the order they were added
+    fn cmp(&self, other: &Entry) -> Ordering {
+        (other.when, other.seq).cmp(&(self.when, self.seq))
+    }
+}
+
+#[derive(Default)]
+pub struct Timers {
+    heap: BinaryHeap<Entry>,
+    seq: u64,
+}
+
+impl Timers {
+    pub fn insert(&mut self, when: Instant, timer: Timer) {
+        self.seq += 1;
+        self.heap.push(Entry {
+            when,
+            seq: self.seq,
+            timer,
+        });
+    }
+
+    pub fn pop_due(&mut self, now: Instant) -> Option<Timer> {
+        match self.heap.peek() {
+            Some(entry) if entry.when <= now => (),
+            _ => return None,
+        }
+        self.heap.pop().map(|entry| entry.timer)
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+}
+
+// rounded up, so a wakeup never comes before the timer is due
+pub fn millis(duration: Duration) -> u64 {
+    let nanos = u64::from(duration.subsec_nanos());
+    duration.as_secs() * 1_000 + nanos.div_ceil(1_000_000)
+}
@@ -1,36 +1,155 @@
 use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use mio;
 
 use ::internal::Message;
+use ::{Id, Interest};
 
+const READ: usize = 0b001;
+const WRITE: usize = 0b010;
+const REMOVE: usize = 0b100;
+
+/// A handle for a stream to change its interest from outside the loop.
 #[derive(Clone)]
 pub struct Transfer {
-    token: mio::Token,
+    id: Id,
+    shared: Arc<Shared>,
     notify: mio::Sender<Message>,
+    // tells the loop to look for interest whose message didn't fit
+    missed: Arc<AtomicBool>,
+}
+
+/// State shared between a stream in the loop and its `Transfer`s.
+pub struct Shared {
+    // cleared once the stream is removed
+    alive: AtomicBool,
+    // whether a Message::Interest is already waiting in the notify queue
+    queued: AtomicBool,
+    // interest added by Transfers, not yet taken by the loop
+    pending: AtomicUsize,
 }
 
 #[inline]
-pub fn new(token: mio::Token, notify: mio::Sender<Message>) -> Transfer {
+pub fn new(id: Id, shared: Arc<Shared>, notify: mio::Sender<Message>, missed: Arc<AtomicBool>) -> Transfer {
     Transfer {
-        token: token,
-        notify: notify,
+        id,
+        shared,
+        notify,
+        missed,
+    }
+}
+
+#[inline]
+pub fn shared() -> Arc<Shared> {
+    Arc::new(Shared {
+        alive: AtomicBool::new(true),
+        queued: AtomicBool::new(false),
+        pending: AtomicUsize::new(0),
+    })
+}
+
+/// Marks the stream as removed, failing any later `Transfer::interest`.
+#[inline]
+pub fn remove(shared: &Shared) {
+    shared.alive.store(false, Ordering::Release);
+}
+
+/// Whether `Transfer`s have added interest the loop hasn't taken yet.
+#[inline]
+pub fn has_pending(shared: &Shared) -> bool {
+    shared.pending.load(Ordering::SeqCst) != 0
+}
+
+/// Takes the interest added since the last call, allowing the next
+/// `Transfer::interest` to queue a message again.
+#[inline]
+pub fn take_interest(shared: &Shared) -> Interest {
+    // cleared first, so interest added after the swap always sends a message
+    shared.queued.store(false, Ordering::SeqCst);
+    let bits = shared.pending.swap(0, Ordering::SeqCst);
+    if bits & REMOVE != 0 {
+        Interest::Remove
+    } else if bits == READ | WRITE {
+        Interest::ReadWrite
+    } else if bits == READ {
+        Interest::Read
+    } else if bits == WRITE {
+        Interest::Write
+    } else {
+        Interest::Wait
+    }
+}
+
+fn bits(interest: Interest) -> usize {
+    match interest {
+        Interest::Read => READ,
+        Interest::Write => WRITE,
+        Interest::ReadWrite => READ | WRITE,
+        Interest::Wait => 0,
+        Interest::Remove => REMOVE,
     }
 }
 
 impl Transfer {
-    #[inline]
-    pub fn interest(&self, interest: ::Interest) -> bool {
-        self.notify.send(Message::Interest(self.token, interest)).is_ok()
+    /// Adds `interest` to the stream's current interest.
+    ///
+    /// Updates are coalesced: while one message for this stream is waiting
+    /// in the loop's notify queue, further interest is merged into it rather
+    /// than queued again.
+    ///
+    /// Fails with `Error::Removed` if the stream has been removed from the
+    /// loop, `Error::NotifyFull` if the notify queue is full, or
+    /// `Error::Closed` if the loop is gone. On `NotifyFull`, the interest is
+    /// still kept, and the loop picks it up once it has worked through its
+    /// queue, so it doesn't need to be added again.
+    pub fn interest(&self, interest: Interest) -> ::Result<()> {
+        if self.is_removed() {
+            return Err(::Error::Removed);
+        }
+        self.shared.pending.fetch_or(bits(interest), Ordering::SeqCst);
+        if self.shared.queued.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.notify.send(Message::Interest(self.id)).map_err(|e| {
+            if let mio::NotifyError::Full(_) = e {
+                // left queued, for the loop to find when it looks
+                self.missed.store(true, Ordering::SeqCst);
+            } else {
+                self.shared.queued.store(false, Ordering::SeqCst);
+            }
+            e.into()
+        })
     }
 
-    //pub fn timeout(&self, )
+    /// Replaces the stream's rate limits with `throttle`, or lifts them if
+    /// it's `Throttle::new()`. The buckets start out full.
+    ///
+    /// Fails with `Error::Removed` if the stream has been removed from the
+    /// loop, `Error::NotifyFull` if the notify queue is full, in which case
+    /// the limits are unchanged, or `Error::Closed` if the loop is gone.
+    pub fn throttle(&self, throttle: &::Throttle) -> ::Result<()> {
+        if self.is_removed() {
+            return Err(::Error::Removed);
+        }
+        self.notify.send(Message::Throttle(self.id, *throttle)).map_err(|e| e.into())
+    }
+
+    /// The `Id` of the stream this `Transfer` belongs to.
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    /// Whether the stream has been removed from the loop.
+    pub fn is_removed(&self) -> bool {
+        !self.shared.alive.load(Ordering::Acquire)
+    }
 }
 
 impl fmt::Debug for Transfer {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Transfer")
-            .field("token", &self.token)
+            .field("id", &self.id)
             .finish()
     }
 }
-
@@ -1,8 +1,393 @@
+#[cfg(unix)]
+use std::collections::VecDeque;
 use std::io;
+use std::ops::{Deref, DerefMut};
+
+use mio::EventSet;
+use mio::tcp::TcpStream;
+#[cfg(unix)]
+use mio::unix::UnixStream;
 
 use ::Evented;
+use throttle::Limiter;
+
+/// Anything that can be registered with the loop, and read from and written to.
+///
+/// Implemented for mio's sockets and this crate's transports. Other types,
+/// or enums of several, need only an empty `impl`, unless they can report
+/// a socket error.
+pub trait Transport: Evented + io::Read + io::Write {
+    /// Takes the error pending on the transport, as `SO_ERROR` does for a
+    /// socket, once the loop sees an error event. Without one, the stream is
+    /// removed with `Error::Hangup`.
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        Ok(None)
+    }
+}
+
+impl Transport for TcpStream {
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        match self.take_socket_error() {
+            Ok(()) => Ok(None),
+            Err(e) => Ok(Some(e)),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut err: ::libc::c_int = 0;
+        let mut len = ::std::mem::size_of::<::libc::c_int>() as ::libc::socklen_t;
+        let ret = unsafe {
+            ::libc::getsockopt(
+                self.as_raw_fd(),
+                ::libc::SOL_SOCKET,
+                ::libc::SO_ERROR,
+                &mut err as *mut _ as *mut ::libc::c_void,
+                &mut len
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(if err == 0 { None } else { Some(io::Error::from_raw_os_error(err)) })
+    }
+}
+
+/// A `Transport` as handed to a `Protocol`.
+///
+/// Reads and writes go through to the inner transport, while the loop keeps
+/// count of the bytes moved. Everything else is reachable through `Deref`.
+#[derive(Debug)]
+pub struct Io<T> {
+    inner: T,
+    read: u64,
+    written: u64,
+    // readiness found to be gone, from reads or writes returning WouldBlock
+    blocked: EventSet,
+    // file regions queued with send_file, sent before on_writable is called
+    #[cfg(unix)]
+    sends: VecDeque<::sendfile::Region>,
+    // rate limits attached with a Throttle, boxed since most streams have none
+    limiter: Option<Box<Limiter>>,
+}
+
+/// Takes the readiness that reads or writes have found to be exhausted since
+/// the last call.
+#[inline]
+pub fn take_blocked<T>(io: &mut Io<T>) -> EventSet {
+    ::std::mem::replace(&mut io.blocked, EventSet::none())
+}
+
+/// Adds to the readiness found to be exhausted, as if reads or writes had.
+#[inline]
+pub fn add_blocked<T>(io: &mut Io<T>, events: EventSet) {
+    io.blocked.insert(events);
+}
+
+/// Replaces the rate limits on the transport.
+#[inline]
+pub fn set_limiter<T>(io: &mut Io<T>, limiter: Option<Limiter>) {
+    io.limiter = limiter.map(Box::new);
+}
+
+/// The rate limits on the transport, if it has any.
+#[inline]
+pub fn limiter<T>(io: &mut Io<T>) -> Option<&mut Limiter> {
+    io.limiter.as_deref_mut()
+}
+
+/// The directions suspended until the transport's buckets refill.
+#[inline]
+pub fn throttled<T>(io: &Io<T>) -> EventSet {
+    match io.limiter {
+        Some(ref limiter) => limiter.throttled(),
+        None => EventSet::none(),
+    }
+}
+
+/// How many of `want` bytes the transport's rate limits allow moving in
+/// `direction`, for reads and writes made directly on the transport.
+#[inline]
+pub fn allow<T>(io: &mut Io<T>, direction: EventSet, want: usize) -> usize {
+    io.allow(direction, want)
+}
+
+/// The error returned for reads and writes while the transport is throttled.
+pub fn throttled_error() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "stream is throttled")
+}
+
+/// Counts the bytes of a read made directly on the transport.
+#[inline]
+pub fn counted_read<T>(io: &mut Io<T>, result: io::Result<usize>) -> io::Result<usize> {
+    io.was_read(result)
+}
+
+/// Counts the bytes of a write made directly on the transport.
+#[inline]
+pub fn counted_write<T>(io: &mut Io<T>, result: io::Result<usize>) -> io::Result<usize> {
+    io.wrote(result)
+}
+
+/// Whether file regions queued with `send_file` are still being sent.
+#[cfg(unix)]
+#[inline]
+pub fn is_sending<T>(io: &Io<T>) -> bool {
+    !io.sends.is_empty()
+}
+
+#[cfg(not(unix))]
+#[inline]
+pub fn is_sending<T>(_io: &Io<T>) -> bool {
+    false
+}
+
+/// Sends queued file regions until they are all sent, returning `true`, or
+/// the transport can't take any more, returning `false`.
+#[cfg(unix)]
+pub fn flush_sends<T>(io: &mut Io<T>) -> io::Result<bool> {
+    while !io.sends.is_empty() {
+        let limit = io.allow(EventSet::writable(), usize::MAX);
+        if limit == 0 {
+            return Ok(false);
+        }
+        let result = io.sends[0].send(limit);
+        match io.wrote(result) {
+            Ok(_) => {
+                if io.sends[0].remaining() == 0 {
+                    io.sends.pop_front();
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => {
+                io.sends.clear();
+                return Err(e);
+            }
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+pub fn flush_sends<T>(_io: &mut Io<T>) -> io::Result<bool> {
+    Ok(true)
+}
+
+impl<T: Transport> Io<T> {
+    /// Wraps a transport, with all counters at zero.
+    pub fn new(inner: T) -> Io<T> {
+        Io {
+            inner,
+            read: 0,
+            written: 0,
+            blocked: EventSet::none(),
+            #[cfg(unix)]
+            sends: VecDeque::new(),
+            limiter: None,
+        }
+    }
+
+    /// The total number of bytes read from this transport.
+    pub fn bytes_read(&self) -> u64 {
+        self.read
+    }
+
+    /// The total number of bytes written to this transport.
+    pub fn bytes_written(&self) -> u64 {
+        self.written
+    }
+
+    /// Unwraps this `Io`, returning the inner transport.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T> Deref for Io<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T> DerefMut for Io<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: Transport> io::Read for Io<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.allow(EventSet::readable(), buf.len());
+        if len == 0 && !buf.is_empty() {
+            return Err(throttled_error());
+        }
+        let result = self.inner.read(&mut buf[..len]);
+        self.was_read(result)
+    }
+}
+
+impl<T> Io<T> {
+    fn allow(&mut self, direction: EventSet, want: usize) -> usize {
+        match self.limiter {
+            Some(ref mut limiter) => limiter.allow(direction, want),
+            None => want,
+        }
+    }
+
+    fn was_read(&mut self, result: io::Result<usize>) -> io::Result<usize> {
+        match result {
+            Ok(n) => {
+                self.read += n as u64;
+                if let Some(ref mut limiter) = self.limiter {
+                    limiter.took(EventSet::readable(), n);
+                }
+                Ok(n)
+            }
+            Err(e) => {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    self.blocked.insert(EventSet::readable());
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn wrote(&mut self, result: io::Result<usize>) -> io::Result<usize> {
+        match result {
+            Ok(n) => {
+                self.written += n as u64;
+                if let Some(ref mut limiter) = self.limiter {
+                    limiter.took(EventSet::writable(), n);
+                }
+                Ok(n)
+            }
+            Err(e) => {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    self.blocked.insert(EventSet::writable());
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // `bufs` cut short to what the rate limits allow writing, or `None` if
+    // they allow all of it
+    fn limit_slices<'a>(&mut self, bufs: &'a [io::IoSlice<'a>]) -> io::Result<Option<Vec<io::IoSlice<'a>>>> {
+        let total = bufs.iter().map(|buf| buf.len()).sum();
+        let mut left = self.allow(EventSet::writable(), total);
+        if left == total {
+            return Ok(None);
+        }
+        if left == 0 {
+            return Err(throttled_error());
+        }
+        let mut limited = Vec::new();
+        for buf in bufs {
+            let n = ::std::cmp::min(left, buf.len());
+            limited.push(io::IoSlice::new(&buf[..n]));
+            left -= n;
+            if left == 0 {
+                break;
+            }
+        }
+        Ok(Some(limited))
+    }
+}
+
+#[cfg(unix)]
+impl<T: Transport + ::std::os::unix::io::AsRawFd> Io<T> {
+    /// Writes `bufs` to the transport's file descriptor with a single
+    /// `writev`, whether or not the transport implements `write_vectored`.
+    /// Only the first 64 are written from, as with `WriteQueue`.
+    pub fn writev(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        let limited = self.limit_slices(bufs)?;
+        let bufs = limited.as_ref().map_or(bufs, |bufs| &bufs[..]);
+        // more than IOV_MAX fails with EINVAL, rather than a short write
+        let bufs = &bufs[..::std::cmp::min(bufs.len(), ::queue::MAX_SLICES)];
+        let n = unsafe {
+            ::libc::writev(
+                self.inner.as_raw_fd(),
+                bufs.as_ptr() as *const ::libc::iovec,
+                bufs.len() as ::libc::c_int
+            )
+        };
+        let result = if n < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(n as usize)
+        };
+        self.wrote(result)
+    }
+
+    /// Queues `len` bytes of `file`, starting at `offset`, to be sent with
+    /// `sendfile`, without copying them through userspace.
+    ///
+    /// The loop sends queued regions whenever the transport is writable,
+    /// keeping it registered for writing until they are all sent, and only
+    /// then calls `on_writable` again. Anything written directly to the
+    /// transport in the meantime would be interleaved with the file, so
+    /// protocols should write their headers before queueing the file.
+    ///
+    /// If sending fails, the rest of the queue is dropped, and the stream
+    /// is removed with the error.
+    pub fn send_file(&mut self, file: ::std::fs::File, offset: u64, len: u64) {
+        if len > 0 {
+            let out = self.inner.as_raw_fd();
+            self.sends.push_back(::sendfile::region(file, out, offset, len));
+        }
+    }
+
+    /// The number of bytes queued with `send_file` not yet sent.
+    pub fn sending(&self) -> u64 {
+        self.sends.iter().map(|region| region.remaining()).sum()
+    }
+}
+
+impl<T: Transport> io::Write for Io<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.allow(EventSet::writable(), buf.len());
+        if len == 0 && !buf.is_empty() {
+            return Err(throttled_error());
+        }
+        let result = self.inner.write(&buf[..len]);
+        self.wrote(result)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        let limited = self.limit_slices(bufs)?;
+        let bufs = limited.as_ref().map_or(bufs, |bufs| &bufs[..]);
+        let result = self.inner.write_vectored(bufs);
+        self.wrote(result)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::io::{IoSlice, Read};
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    use std::os::unix::net;
 
-pub trait Transport: Evented + io::Read + io::Write {}
+    use mio::unix::UnixStream;
 
-impl<T> Transport for T where T: Evented + io::Read + io::Write {}
+    use queue::MAX_SLICES;
+    use super::Io;
 
+    #[test]
+    fn writev_takes_more_slices_than_iov_max() {
+        let (a, mut b) = net::UnixStream::pair().unwrap();
+        let mut io = Io::new(unsafe { UnixStream::from_raw_fd(a.into_raw_fd()) });
+        let bufs = vec![IoSlice::new(b"x"); 2048];
+        assert_eq!(io.writev(&bufs).unwrap(), MAX_SLICES);
+        let mut buf = [0; 4096];
+        assert_eq!(b.read(&mut buf).unwrap(), MAX_SLICES);
+    }
+}